@@ -0,0 +1,628 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    consensus::{
+        App, CommitCertificate, CommitError, Evidence, ProposerSelector, ValidatorUpdate,
+        Validators,
+    },
+    crypto::{
+        contracts::{Contract, PrivateKey, PublicKey, Signable},
+        hashing::{Hash, Hashable},
+    },
+    transactions::{mempool::Mempool, state::State, Transaction},
+};
+
+use super::{
+    store::{BlockStore, Committed},
+    Block, ConsensusParams, Genesis,
+};
+
+/// How many blocks are committed between snapshots of the state.
+const SNAPSHOT_INTERVAL: u64 = 100;
+
+/// Runs the license chain: blocks are filled from a mempool, and committing
+/// them applies their transactions to the `State`. Committed blocks are stored
+/// on disk, and the ones since the last snapshot of the state are applied again
+/// when the app is reopened.
+#[derive(Clone)]
+pub struct BlockkeyApp {
+    chain_id: String,
+    genesis: Hash<Genesis>,
+    genesis_time: u128,
+    params: Arc<ConsensusParams>,
+    key: Arc<PrivateKey>,
+    validators: Arc<Validators>,
+    /// The state after every committed block.
+    state: State,
+    /// The height of the next block.
+    height: u64,
+    /// The last committed block, which the next one must follow.
+    last: Option<Committed>,
+    /// Every committed block, which are only read to be replayed or served to peers.
+    store: Arc<Mutex<BlockStore>>,
+    /// Chooses proposers at the first height.
+    first_selector: ProposerSelector,
+    /// Chooses proposers at the next height.
+    selector: ProposerSelector,
+    /// Shared with whatever receives transactions from users.
+    mempool: Arc<Mutex<Mempool>>,
+    /// The state after each block applied at this height, or None for blocks whose
+    /// transactions failed, so that validating a block again is cheap.
+    applied: Arc<Mutex<HashMap<Hash<Block>, Option<State>>>>,
+}
+
+impl BlockkeyApp {
+    /// Opens an app for the chain, storing its blocks at the given path. Blocks already
+    /// stored there since the last snapshot are applied again, and the mempool is
+    /// updated to the state they leave. Fails if the store can't be read, or its blocks
+    /// don't follow the genesis.
+    pub fn open(
+        genesis: &Genesis,
+        key: PrivateKey,
+        mempool: Arc<Mutex<Mempool>>,
+        path: impl AsRef<Path>,
+    ) -> io::Result<BlockkeyApp> {
+        let store = BlockStore::open(path)?;
+        let snapshot = store.snapshot()?;
+        let validators = genesis.validators();
+        let selector = ProposerSelector::new(&validators)
+            .expect("Genesis::check requires some stake, and bounds it");
+        let mut app = BlockkeyApp {
+            chain_id: genesis.chain_id.clone(),
+            genesis: genesis.hash(),
+            genesis_time: genesis.genesis_time,
+            params: Arc::new(genesis.consensus.clone()),
+            key: Arc::new(key),
+            first_selector: selector.clone(),
+            selector,
+            validators: Arc::new(validators),
+            state: genesis.state(),
+            height: 0,
+            last: None,
+            store: Arc::new(Mutex::new(store)),
+            mempool,
+            applied: Arc::new(Mutex::new(HashMap::new())),
+        };
+        let store = app.store.clone();
+        let mut store = store.lock().unwrap();
+        if let Some((height, state)) = snapshot {
+            app.restore(&mut store, height, state)?;
+        }
+        for height in app.height..store.len() {
+            let (block, certificate) = store.get(height)?.expect("the store has the height");
+            app.replay(block, certificate)?;
+        }
+        drop(store);
+        let height = app.height();
+        app.mempool
+            .lock()
+            .unwrap()
+            .update(app.state.clone(), height);
+        Ok(app)
+    }
+
+    /// Returns the state after every committed block.
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// Returns the hash of the last committed block, or of the genesis before the first.
+    fn previous(&self) -> Hash<Block> {
+        self.last
+            .as_ref()
+            .map_or(self.genesis.cast(), |(block, _)| block.hash())
+    }
+
+    /// Returns the time of the block that includes `last_commit`, as `App::commit`
+    /// defines it.
+    fn block_time(&self, last_commit: Option<&CommitCertificate<Block>>) -> u128 {
+        last_commit.map_or(self.genesis_time, |certificate| {
+            certificate.time(&self.validators)
+        })
+    }
+
+    /// Checks that the transactions fit in a block.
+    fn fits(&self, transactions: &[Transaction]) -> bool {
+        let bytes = transactions.iter().try_fold(0u64, |total, transaction| {
+            total.checked_add(bincode::serialized_size(transaction).ok()?)
+        });
+        transactions.len() <= self.params.max_block_transactions
+            && bytes.is_some_and(|bytes| bytes <= self.params.max_block_bytes as u64)
+    }
+
+    /// Applies the block's transactions to the committed state, or returns None if any
+    /// fail or aren't signed for this chain. The block must match its header, which
+    /// identifies it for the cache.
+    fn apply(&self, block: &Block) -> Option<State> {
+        let mut applied = self.applied.lock().unwrap();
+        applied
+            .entry(block.hash())
+            .or_insert_with(|| {
+                self.state
+                    .apply_all(&self.chain_id, self.height(), &block.transactions)
+            })
+            .clone()
+    }
+
+    /// Moves to the height of a snapshot of the state, which must be the state of the
+    /// stored block below it.
+    fn restore(&mut self, store: &mut BlockStore, height: u64, state: State) -> io::Result<()> {
+        let last = match height.checked_sub(1) {
+            Some(last) => store.get(last)?,
+            None => None,
+        };
+        let matches = last.as_ref().is_some_and(|(block, certificate)| {
+            block.header.height + 1 == height
+                && block.header.state == state.hash()
+                && certificate.block == block.hash()
+        });
+        if !matches {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "state snapshot at height {} doesn't match the stored blocks",
+                    height
+                ),
+            ));
+        }
+        self.state = state;
+        self.height = height;
+        self.last = last;
+        for _ in 0..height {
+            self.selector.advance();
+        }
+        Ok(())
+    }
+
+    /// Applies a block read back from the store. It was validated before it was
+    /// committed, so only that it follows the chain and leads to its state is checked.
+    fn replay(&mut self, block: Block, certificate: CommitCertificate<Block>) -> io::Result<()> {
+        let header = &block.header;
+        let follows = header.height == self.height()
+            && header.previous == self.previous()
+            && certificate.block == block.hash();
+        let state = self
+            .apply(&block)
+            .filter(|state| follows && state.hash() == header.state)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "stored block at height {} doesn't follow the chain",
+                        self.height()
+                    ),
+                )
+            })?;
+        self.append(block, certificate, state);
+        Ok(())
+    }
+
+    /// Moves to the next height, after the block leaves the given state.
+    fn append(&mut self, block: Block, certificate: CommitCertificate<Block>, state: State) {
+        self.state = state;
+        self.applied.lock().unwrap().clear();
+        self.height += 1;
+        self.last = Some((block, certificate));
+        self.selector.advance();
+    }
+}
+
+impl App<Block> for BlockkeyApp {
+    fn id(&self) -> Hash<PublicKey> {
+        self.key.get_public().hash()
+    }
+
+    fn chain_id(&self) -> &str {
+        &self.chain_id
+    }
+
+    fn genesis_time(&self) -> u128 {
+        self.genesis_time
+    }
+
+    fn height(&self) -> u64 {
+        self.height
+    }
+
+    /// Returns the validators of the genesis. The set is fixed at genesis, as no
+    /// transaction changes it yet, so `commit` never returns updates.
+    fn validators(&self, _height: u64) -> Validators {
+        (*self.validators).clone()
+    }
+
+    fn proposers(&self, height: u64) -> ProposerSelector {
+        // earlier heights are replayed from genesis
+        let (mut selector, from) = if height < self.height() {
+            (self.first_selector.clone(), 0)
+        } else {
+            (self.selector.clone(), self.height())
+        };
+        // the validators never change, so the selector only has to advance
+        for _ in from..height {
+            selector.advance();
+        }
+        selector
+    }
+
+    fn create_block(&self, last_commit: Option<CommitCertificate<Block>>) -> Block {
+        let batch = self.mempool.lock().unwrap().batch(
+            self.params.max_block_transactions,
+            self.params.max_block_bytes,
+        );
+        // the mempool is updated on every commit, so its transactions should all
+        // apply, but any that don't are left out rather than spoiling the block.
+        // Their signatures were checked as they entered the mempool.
+        let mut state = self.state.clone();
+        let mut transactions = Vec::new();
+        for transaction in batch {
+            if let Some(next) = state.apply_verified(self.height(), &transaction) {
+                state = next;
+                transactions.push(transaction);
+            }
+        }
+        let block = Block::new(
+            self.chain_id.clone(),
+            self.height(),
+            self.previous(),
+            self.id(),
+            self.block_time(last_commit.as_ref()),
+            state.hash(),
+            transactions,
+            last_commit,
+        );
+        self.applied
+            .lock()
+            .unwrap()
+            .insert(block.hash(), Some(state));
+        block
+    }
+
+    fn last_commit<'a>(&self, block: &'a Block) -> Option<&'a CommitCertificate<Block>> {
+        block.last_commit.as_ref()
+    }
+
+    fn validate_block(&self, block: &Block) -> bool {
+        let header = &block.header;
+        block.verify()
+            && header.chain_id == self.chain_id
+            && header.height == self.height()
+            && header.previous == self.previous()
+            && self.validators.contains_key(&header.proposer)
+            && header.time == self.block_time(block.last_commit.as_ref())
+            && self.fits(&block.transactions)
+            // checked last, as applying the transactions is the most expensive
+            && self
+                .apply(block)
+                .is_some_and(|state| state.hash() == header.state)
+    }
+
+    fn commit(
+        &mut self,
+        block: Block,
+        certificate: CommitCertificate<Block>,
+        time: u128,
+    ) -> Result<Vec<ValidatorUpdate>, CommitError> {
+        if block.header.time != time {
+            return Err(CommitError(format!(
+                "block has time {}, but consensus worked out {}",
+                block.header.time, time
+            )));
+        }
+        // blocks are validated before they are committed, which leaves their state
+        let state = self
+            .apply(&block)
+            .filter(|state| state.hash() == block.header.state)
+            .ok_or_else(|| CommitError("block doesn't lead to its state".to_string()))?;
+        {
+            let mut store = self.store.lock().unwrap();
+            store
+                .append(&block, &certificate)
+                .map_err(|e| CommitError(format!("couldn't store block: {}", e)))?;
+            if store.len().is_multiple_of(SNAPSHOT_INTERVAL) {
+                store
+                    .save_snapshot(&state)
+                    .map_err(|e| CommitError(format!("couldn't save state snapshot: {}", e)))?;
+            }
+        }
+        self.append(block, certificate, state);
+        self.mempool
+            .lock()
+            .unwrap()
+            .update(self.state.clone(), self.height());
+        Ok(Vec::new())
+    }
+
+    /// Reads the block from the store, unless it is the last one. A block that can't
+    /// be read is treated as missing, so that peers ask someone else for it.
+    fn committed(&self, height: u64) -> Option<(Block, CommitCertificate<Block>)> {
+        match &self.last {
+            Some(last) if height + 1 == self.height => Some(last.clone()),
+            _ => self.store.lock().unwrap().get(height).ok().flatten(),
+        }
+    }
+
+    /// Drops the evidence, as no transaction punishes equivocation yet.
+    fn report_evidence(&mut self, _evidence: Evidence<Block>) {}
+
+    fn sign<T: Signable>(&self, contract: T) -> Contract<T> {
+        self.key.sign(&self.chain_id, contract)
+    }
+
+    fn sign_after<T: Signable>(&self, contract: T, earliest: u128) -> Contract<T> {
+        self.key.sign_after(&self.chain_id, contract, earliest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::{
+        fs,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+    use tokio::time::{sleep, Duration};
+
+    use super::*;
+    use crate::{
+        block::{GenesisBalance, GenesisValidator},
+        testing::{lone_validator, transfer, CHAIN},
+        transactions::mempool::MempoolConfig,
+    };
+
+    /// The key of the lone validator, which is the same every time the app is opened.
+    fn validator() -> PrivateKey {
+        PrivateKey::from_rng(&mut StdRng::seed_from_u64(0))
+    }
+
+    /// An app for a lone validator that stores its blocks in `dir`, where `user` starts
+    /// with a balance of 100. The chain starts an hour from now, so block times can't
+    /// simply follow the clock.
+    fn setup(user: &PrivateKey, dir: &Path) -> (BlockkeyApp, Genesis) {
+        let key = validator();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let genesis = Genesis {
+            chain_id: CHAIN.to_string(),
+            genesis_time: now.as_millis() + 60 * 60 * 1000,
+            validators: vec![GenesisValidator {
+                id: key.get_public().hash(),
+                stake: 1,
+            }],
+            balances: vec![GenesisBalance {
+                id: user.get_public().hash(),
+                balance: 100,
+            }],
+            consensus: ConsensusParams::default(),
+        };
+        (open(&genesis, dir), genesis)
+    }
+
+    /// Opens the lone validator's app again, with an empty mempool.
+    fn open(genesis: &Genesis, dir: &Path) -> BlockkeyApp {
+        let mempool = Mempool::new(
+            MempoolConfig::default(),
+            genesis.chain_id.clone(),
+            genesis.state(),
+        );
+        let mempool = Arc::new(Mutex::new(mempool));
+        BlockkeyApp::open(genesis, validator(), mempool, dir.join("blocks")).unwrap()
+    }
+
+    fn balance(state: &State, key: &PrivateKey) -> u64 {
+        state
+            .users
+            .get(&key.get_public().hash())
+            .map_or(0, |user| user.balance)
+    }
+
+    #[test]
+    fn rejects_blocks_with_failing_transactions() {
+        let (alice, bob) = (PrivateKey::generate(), PrivateKey::generate());
+        let dir = tempfile::tempdir().unwrap();
+        let (mut app, genesis) = setup(&alice, dir.path());
+        let mempool = app.mempool.clone();
+        mempool
+            .lock()
+            .unwrap()
+            .add(transfer(&alice, &bob, 60, 0))
+            .unwrap();
+
+        let block = app.create_block(None);
+        assert_eq!(block.transactions.len(), 1);
+        assert!(app.validate_block(&block));
+        // the state left by validating is only reused for the body the header commits to
+        let mut emptied = block.clone();
+        emptied.transactions.clear();
+        assert!(!app.validate_block(&emptied));
+        assert!(app.validate_block(&block));
+
+        // overspending, so the state root can't be made to match
+        let header = &block.header;
+        let overspent = Block::new(
+            header.chain_id.clone(),
+            header.height,
+            header.previous,
+            header.proposer,
+            header.time,
+            header.state,
+            vec![block.transactions[0].clone(), transfer(&alice, &bob, 60, 1)],
+            None,
+        );
+        assert!(!app.validate_block(&overspent));
+
+        // signed by bob, claiming to be from alice, with the state root it would give
+        let mut forged = match transfer(&bob, &bob, 60, 0) {
+            Transaction::CurrencyTransfer(contract) => contract,
+            _ => unreachable!(),
+        };
+        forged.signee = alice.get_public();
+        let forged = vec![Transaction::CurrencyTransfer(forged)];
+        let state = app.state().apply_verified(0, &forged[0]).unwrap();
+        let forged = Block::new(
+            header.chain_id.clone(),
+            header.height,
+            header.previous,
+            header.proposer,
+            header.time,
+            state.hash(),
+            forged,
+            None,
+        );
+        assert!(!app.validate_block(&forged));
+
+        let mut wrong_chain = block.clone();
+        wrong_chain.header.chain_id = "other".to_string();
+        assert!(!app.validate_block(&wrong_chain));
+
+        let certificate = CommitCertificate {
+            height: 0,
+            round: 0,
+            block: block.hash(),
+            precommits: Vec::new(),
+        };
+        // inconsistencies with consensus are reported, and nothing is committed
+        let wrong_time = app.commit(block.clone(), certificate.clone(), block.header.time + 1);
+        assert!(wrong_time.is_err());
+        let time = overspent.header.time;
+        assert!(app.commit(overspent, certificate.clone(), time).is_err());
+        assert_eq!(app.height(), 0);
+        assert_eq!(balance(app.state(), &bob), 0);
+        app.commit(block.clone(), certificate, block.header.time)
+            .unwrap();
+        assert_eq!(balance(app.state(), &bob), 60);
+        assert!(mempool.lock().unwrap().is_empty());
+        // the same block can't be committed at the next height
+        assert!(!app.validate_block(&block));
+
+        // only the committed block is stored
+        let app = open(&genesis, dir.path());
+        assert_eq!(app.height(), 1);
+        assert_eq!(app.committed(0).unwrap().0, block);
+        assert_eq!(balance(app.state(), &bob), 60);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn commits_transfers() {
+        let (alice, bob) = (PrivateKey::generate(), PrivateKey::generate());
+        let dir = tempfile::tempdir().unwrap();
+        let (app, genesis) = setup(&alice, dir.path());
+        let mempool = app.mempool.clone();
+
+        let (handle, _) = lone_validator(app, genesis.config(), &dir.path().join("wal"));
+
+        sleep(Duration::from_secs(5)).await;
+        mempool
+            .lock()
+            .unwrap()
+            .add(transfer(&alice, &bob, 30, 0))
+            .unwrap();
+        sleep(Duration::from_secs(5)).await;
+        mempool
+            .lock()
+            .unwrap()
+            .add(transfer(&bob, &alice, 10, 0))
+            .unwrap();
+        sleep(Duration::from_secs(5)).await;
+
+        {
+            let pool = mempool.lock().unwrap();
+            assert!(pool.is_empty());
+            assert_eq!(balance(pool.state(), &alice), 80);
+            assert_eq!(balance(pool.state(), &bob), 20);
+        }
+        handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn restarts_from_stored_blocks() {
+        let (alice, bob) = (PrivateKey::generate(), PrivateKey::generate());
+        let dir = tempfile::tempdir().unwrap();
+        let wal = dir.path().join("wal");
+        let (app, genesis) = setup(&alice, dir.path());
+        let mempool = app.mempool.clone();
+
+        let (handle, node) = lone_validator(app, genesis.config(), &wal);
+        sleep(Duration::from_secs(5)).await;
+        mempool
+            .lock()
+            .unwrap()
+            .add(transfer(&alice, &bob, 30, 0))
+            .unwrap();
+        sleep(Duration::from_secs(5)).await;
+        handle.shutdown().await.unwrap();
+        node.await.unwrap().unwrap();
+
+        // the consensus log is at a later height than genesis, which the app catches up to
+        let app = open(&genesis, dir.path());
+        let height = app.height();
+        assert!(height > 0);
+        let mempool = app.mempool.clone();
+        assert_eq!(balance(mempool.lock().unwrap().state(), &bob), 30);
+
+        let (handle, _) = lone_validator(app, genesis.config(), &wal);
+        sleep(Duration::from_secs(5)).await;
+        assert!(handle.snapshot().await.unwrap().height > height);
+        // alice's first nonce is known to be used up
+        mempool
+            .lock()
+            .unwrap()
+            .add(transfer(&alice, &bob, 10, 1))
+            .unwrap();
+        sleep(Duration::from_secs(5)).await;
+        assert_eq!(balance(mempool.lock().unwrap().state(), &bob), 40);
+        handle.shutdown().await.unwrap();
+    }
+
+    #[test]
+    fn restarts_from_a_snapshot() {
+        let (alice, bob) = (PrivateKey::generate(), PrivateKey::generate());
+        let dir = tempfile::tempdir().unwrap();
+        let (mut app, genesis) = setup(&alice, dir.path());
+        app.mempool
+            .lock()
+            .unwrap()
+            .add(transfer(&alice, &bob, 30, 0))
+            .unwrap();
+        // committed without consensus, with the genesis time, which the app allows
+        let mut first = None;
+        for height in 0..SNAPSHOT_INTERVAL + 1 {
+            let block = app.create_block(None);
+            let certificate = CommitCertificate {
+                height,
+                round: 0,
+                block: block.hash(),
+                precommits: Vec::new(),
+            };
+            let time = block.header.time;
+            app.commit(block.clone(), certificate, time).unwrap();
+            first.get_or_insert(block);
+        }
+
+        let app = open(&genesis, dir.path());
+        assert_eq!(app.height(), SNAPSHOT_INTERVAL + 1);
+        assert_eq!(balance(app.state(), &bob), 30);
+        // older blocks are still served from the store
+        assert_eq!(app.committed(0).unwrap().0, first.unwrap());
+
+        // a snapshot that doesn't match the blocks is refused
+        let snapshot = dir.path().join("blocks.snapshot");
+        let state = (SNAPSHOT_INTERVAL, genesis.state());
+        fs::write(&snapshot, bincode::serialize(&state).unwrap()).unwrap();
+        let mempool = Mempool::new(
+            MempoolConfig::default(),
+            CHAIN.to_string(),
+            State::default(),
+        );
+        let reopened = BlockkeyApp::open(
+            &genesis,
+            validator(),
+            Arc::new(Mutex::new(mempool)),
+            dir.path().join("blocks"),
+        );
+        assert_eq!(reopened.err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+}
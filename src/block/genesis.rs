@@ -0,0 +1,381 @@
+use std::{collections::HashSet, fs, path::Path};
+
+use rand::{rngs::StdRng, SeedableRng};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tokio::time::Duration;
+
+use crate::{
+    consensus::{
+        encode, CommitCertificate, ConsensusConfig, Precommit, SyncMessage, Validators,
+        WireMessage, MAX_MESSAGE_SIZE, MAX_PARTS, MAX_TOTAL_WEIGHT, PART_SIZE,
+    },
+    crypto::{
+        contracts::{PrivateKey, UserId},
+        hashing::{Hash, Hashable},
+    },
+    transactions::state::{State, UserState},
+};
+
+use super::Block;
+
+/// Describes the start of a chain. Every node on the chain must load the same
+/// genesis, which the first block commits to by its hash.
+///
+/// In JSON, user ids are written as hexadecimal hashes of public keys, and times
+/// as milliseconds. Consensus parameters that are left out take their defaults.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Genesis {
+    pub chain_id: String,
+    /// The time of the first block, in milliseconds since the Unix epoch.
+    pub genesis_time: u128,
+    pub validators: Vec<GenesisValidator>,
+    #[serde(default)]
+    pub balances: Vec<GenesisBalance>,
+    #[serde(default)]
+    pub consensus: ConsensusParams,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GenesisValidator {
+    #[serde(with = "hex")]
+    pub id: UserId,
+    pub stake: u64,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GenesisBalance {
+    #[serde(with = "hex")]
+    pub id: UserId,
+    pub balance: u64,
+}
+
+/// The parameters that every node must agree on. Timeouts are in milliseconds
+/// (see `ConsensusConfig`).
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConsensusParams {
+    pub propose: u64,
+    pub propose_delta: u64,
+    pub prevote: u64,
+    pub prevote_delta: u64,
+    pub precommit: u64,
+    pub precommit_delta: u64,
+    pub commit_delay: u64,
+    /// The most transactions in a block.
+    pub max_block_transactions: usize,
+    /// The most encoded transaction bytes in a block. Blocks are split into parts
+    /// to be proposed, but are sent whole to lagging nodes, so along with its commit
+    /// certificate a block must fit in one message of `MAX_MESSAGE_SIZE`.
+    pub max_block_bytes: usize,
+}
+
+impl Default for ConsensusParams {
+    fn default() -> Self {
+        let config = ConsensusConfig::default();
+        let millis = |duration: Duration| duration.as_millis() as u64;
+        ConsensusParams {
+            propose: millis(config.propose),
+            propose_delta: millis(config.propose_delta),
+            prevote: millis(config.prevote),
+            prevote_delta: millis(config.prevote_delta),
+            precommit: millis(config.precommit),
+            precommit_delta: millis(config.precommit_delta),
+            commit_delay: millis(config.commit_delay),
+            max_block_transactions: 10_000,
+            max_block_bytes: 512 * 1024,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum GenesisError {
+    Io(std::io::Error),
+    /// The file isn't valid genesis JSON.
+    Malformed(serde_json::Error),
+    EmptyChainId,
+    /// No validator has any stake.
+    NoValidators,
+    DuplicateValidator(UserId),
+    DuplicateBalance(UserId),
+    /// The balances add up to more than a balance can hold.
+    SupplyOverflow,
+    /// The stakes add up to more than `MAX_TOTAL_WEIGHT`.
+    StakeOverflow,
+    /// A block with `max_block_bytes` of transactions wouldn't fit in `MAX_PARTS` parts,
+    /// or couldn't be sent to a lagging node in one message.
+    BlockTooLarge,
+}
+
+impl Genesis {
+    /// Reads and checks a genesis file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Genesis, GenesisError> {
+        let json = fs::read_to_string(path).map_err(GenesisError::Io)?;
+        Genesis::parse(&json)
+    }
+
+    pub fn parse(json: &str) -> Result<Genesis, GenesisError> {
+        let genesis: Genesis = serde_json::from_str(json).map_err(GenesisError::Malformed)?;
+        genesis.check()?;
+        Ok(genesis)
+    }
+
+    fn check(&self) -> Result<(), GenesisError> {
+        if self.chain_id.is_empty() {
+            return Err(GenesisError::EmptyChainId);
+        }
+        if self.validators.iter().all(|v| v.stake == 0) {
+            return Err(GenesisError::NoValidators);
+        }
+        let mut ids = HashSet::new();
+        if let Some(v) = self.validators.iter().find(|v| !ids.insert(v.id)) {
+            return Err(GenesisError::DuplicateValidator(v.id));
+        }
+        let mut ids = HashSet::new();
+        if let Some(b) = self.balances.iter().find(|b| !ids.insert(b.id)) {
+            return Err(GenesisError::DuplicateBalance(b.id));
+        }
+        // so that no transfer can overflow a balance
+        self.balances
+            .iter()
+            .try_fold(0u64, |total, b| total.checked_add(b.balance))
+            .ok_or(GenesisError::SupplyOverflow)?;
+        // so that votes can be counted and proposers chosen without overflowing
+        self.validators
+            .iter()
+            .try_fold(0u64, |total, v| total.checked_add(v.stake))
+            .filter(|total| *total <= MAX_TOTAL_WEIGHT)
+            .ok_or(GenesisError::StakeOverflow)?;
+        let (block, sync) = self.overhead();
+        let bytes = self.consensus.max_block_bytes as u64;
+        let fits = |overhead: u64, limit: u64| {
+            bytes
+                .checked_add(overhead)
+                .is_some_and(|total| total <= limit)
+        };
+        if !fits(block, MAX_PARTS * PART_SIZE as u64) || !fits(sync, MAX_MESSAGE_SIZE as u64) {
+            return Err(GenesisError::BlockTooLarge);
+        }
+        Ok(())
+    }
+
+    /// Returns the most bytes that an encoded block takes besides its transactions
+    /// (the header, and a `last_commit` with a precommit from every validator), and
+    /// the most that the message syncing it takes, which also carries its certificate.
+    fn overhead(&self) -> (u64, u64) {
+        // integers are encoded at a fixed width, so only the number of precommits matters,
+        // and any key will do
+        let key = PrivateKey::from_rng(&mut StdRng::seed_from_u64(0));
+        let precommit = key.sign_at(&self.chain_id, Precommit::new(0, 0, Some(Hash::empty())), 0);
+        let certificate = CommitCertificate {
+            height: 0,
+            round: 0,
+            block: Hash::empty(),
+            precommits: vec![precommit; self.validators.len()],
+        };
+        let block = Block::new(
+            self.chain_id.clone(),
+            0,
+            Hash::empty(),
+            key.get_public().hash(),
+            0,
+            Hash::empty(),
+            Vec::new(),
+            Some(certificate.clone()),
+        );
+        // encoding plain data to memory can't fail
+        let block_bytes = bincode::serialized_size(&block).unwrap();
+        let message = WireMessage::Sync(SyncMessage::Block { block, certificate });
+        // a message too large to encode leaves no room for transactions
+        let sync_bytes = encode(&message).map_or(u64::MAX, |bytes| bytes.len() as u64);
+        (block_bytes, sync_bytes)
+    }
+
+    /// Returns the state before the first block.
+    pub fn state(&self) -> State {
+        let users = self
+            .balances
+            .iter()
+            .map(|b| {
+                let user = UserState {
+                    balance: b.balance,
+                    ..UserState::default()
+                };
+                (b.id, user)
+            })
+            .collect();
+        State { users }
+    }
+
+    /// Returns the validators for the first block. Validators with no stake are left out.
+    pub fn validators(&self) -> Validators {
+        self.validators
+            .iter()
+            .filter(|v| v.stake > 0)
+            .map(|v| (v.id, v.stake))
+            .collect()
+    }
+
+    /// Returns the consensus configuration, with the timeouts from the genesis.
+    pub fn config(&self) -> ConsensusConfig {
+        let params = &self.consensus;
+        ConsensusConfig {
+            propose: Duration::from_millis(params.propose),
+            propose_delta: Duration::from_millis(params.propose_delta),
+            prevote: Duration::from_millis(params.prevote),
+            prevote_delta: Duration::from_millis(params.prevote_delta),
+            precommit: Duration::from_millis(params.precommit),
+            precommit_delta: Duration::from_millis(params.precommit_delta),
+            commit_delay: Duration::from_millis(params.commit_delay),
+            ..ConsensusConfig::default()
+        }
+    }
+}
+
+impl Hashable for Genesis {
+    /// Hashes the parsed genesis, so that the formatting of the file doesn't matter.
+    fn hash(&self) -> Hash<Self> {
+        // encoding plain data to memory can't fail
+        bincode::serialize(self).unwrap().hash().cast()
+    }
+}
+
+/// Writes user ids as they are displayed.
+mod hex {
+    use super::*;
+    use serde::de::Error;
+
+    pub fn serialize<S: Serializer>(id: &UserId, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&id.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<UserId, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        Hash::parse(&hex).ok_or_else(|| D::Error::custom("expected a 64 digit hexadecimal id"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(n: u64) -> UserId {
+        n.hash().cast()
+    }
+
+    fn json(validators: &[(UserId, u64)], balances: &[(UserId, u64)]) -> String {
+        let validators: Vec<String> = validators
+            .iter()
+            .map(|(id, stake)| format!(r#"{{"id": "{}", "stake": {}}}"#, id, stake))
+            .collect();
+        let balances: Vec<String> = balances
+            .iter()
+            .map(|(id, balance)| format!(r#"{{"id": "{}", "balance": {}}}"#, id, balance))
+            .collect();
+        format!(
+            r#"{{
+                "chain_id": "blockkey-test",
+                "genesis_time": 1600000000000,
+                "validators": [{}],
+                "balances": [{}],
+                "consensus": {{"propose": 5000, "max_block_transactions": 10}}
+            }}"#,
+            validators.join(","),
+            balances.join(",")
+        )
+    }
+
+    #[test]
+    fn loads_the_initial_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("genesis.json");
+        fs::write(&path, json(&[(id(0), 10), (id(1), 0)], &[(id(2), 500)])).unwrap();
+        let genesis = Genesis::load(&path).unwrap();
+
+        assert_eq!(genesis.chain_id, "blockkey-test");
+        assert_eq!(genesis.genesis_time, 1_600_000_000_000);
+        assert_eq!(
+            genesis.validators(),
+            vec![(id(0), 10)].into_iter().collect()
+        );
+        assert_eq!(genesis.state().users[&id(2)].balance, 500);
+
+        let config = genesis.config();
+        assert_eq!(config.propose, Duration::from_millis(5000));
+        assert_eq!(config.prevote, ConsensusConfig::default().prevote);
+        assert_eq!(genesis.consensus.max_block_transactions, 10);
+
+        // written back out, it loads as the same genesis
+        let written = serde_json::to_string(&genesis).unwrap();
+        assert_eq!(Genesis::parse(&written).unwrap().hash(), genesis.hash());
+    }
+
+    #[test]
+    fn hash_covers_every_field() {
+        let genesis = Genesis::parse(&json(&[(id(0), 10)], &[(id(2), 500)])).unwrap();
+        let changed = [
+            json(&[(id(0), 11)], &[(id(2), 500)]),
+            json(&[(id(0), 10)], &[(id(2), 501)]),
+            json(&[(id(0), 10)], &[]),
+        ];
+        for json in changed.iter() {
+            assert_ne!(Genesis::parse(json).unwrap().hash(), genesis.hash());
+        }
+    }
+
+    #[test]
+    fn blocks_fit_in_sync_messages() {
+        let with_max_bytes = |bytes: usize| {
+            let mut genesis = Genesis::parse(&json(&[(id(0), 1)], &[])).unwrap();
+            genesis.consensus.max_block_bytes = bytes;
+            genesis.check()
+        };
+        assert!(with_max_bytes(ConsensusParams::default().max_block_bytes).is_ok());
+
+        // the largest block still fits in a message along with its certificate
+        let genesis = Genesis::parse(&json(&[(id(0), 1)], &[])).unwrap();
+        let limit = MAX_MESSAGE_SIZE - genesis.overhead().1 as usize;
+        assert!(with_max_bytes(limit).is_ok());
+        assert!(matches!(
+            with_max_bytes(limit + 1),
+            Err(GenesisError::BlockTooLarge)
+        ));
+        assert!(matches!(
+            with_max_bytes(usize::MAX),
+            Err(GenesisError::BlockTooLarge)
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_genesis() {
+        let error = |json: String| Genesis::parse(&json).unwrap_err();
+        assert!(matches!(
+            error(json(&[(id(0), 0)], &[])),
+            GenesisError::NoValidators
+        ));
+        assert!(matches!(
+            error(json(&[(id(0), 1), (id(0), 2)], &[])),
+            GenesisError::DuplicateValidator(v) if v == id(0)
+        ));
+        assert!(matches!(
+            error(json(&[(id(0), 1)], &[(id(2), 1), (id(2), 1)])),
+            GenesisError::DuplicateBalance(b) if b == id(2)
+        ));
+        assert!(matches!(
+            error(json(&[(id(0), 1)], &[(id(2), u64::MAX), (id(3), 1)])),
+            GenesisError::SupplyOverflow
+        ));
+        assert!(matches!(
+            error(json(&[(id(0), MAX_TOTAL_WEIGHT), (id(1), 1)], &[])),
+            GenesisError::StakeOverflow
+        ));
+        assert!(matches!(
+            error(json(&[(id(0), u64::MAX), (id(1), 1)], &[])),
+            GenesisError::StakeOverflow
+        ));
+        let bad_id = json(&[(id(0), 1)], &[]).replace(&id(0).to_string(), "1234");
+        assert!(matches!(error(bad_id), GenesisError::Malformed(_)));
+    }
+}
@@ -0,0 +1,197 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    consensus::CommitCertificate,
+    crypto::{
+        contracts::PublicKey,
+        hashing::{Hash, Hashable, MerkleTree},
+    },
+    transactions::{state::State, Transaction},
+};
+
+mod app;
+mod genesis;
+mod store;
+
+pub use app::*;
+pub use genesis::*;
+
+/// Describes a block, and commits to everything in it. Blocks are identified by the
+/// hash of their header alone, so a header and an inclusion proof are enough to show
+/// that a transaction was committed.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Header {
+    pub chain_id: String,
+    pub height: u64,
+    /// The hash of the block at the previous height, or of the `Genesis` for the first block.
+    pub previous: Hash<Block>,
+    pub proposer: Hash<PublicKey>,
+    /// The block time, in milliseconds since the Unix epoch (see `App::commit`).
+    pub time: u128,
+    /// The hash of the block's `last_commit`.
+    pub last_commit: Hash<CommitCertificate<Block>>,
+    /// The hash of the `MerkleTree` with the block's transactions as its leaves.
+    pub transactions: Hash,
+    /// The hash of the `State` after the block's transactions are applied.
+    pub state: Hash<State>,
+}
+
+impl Hashable for Header {
+    fn hash(&self) -> Hash<Self> {
+        hash![
+            self.chain_id.as_bytes(),
+            self.height,
+            self.previous,
+            self.proposer,
+            self.time,
+            self.last_commit,
+            self.transactions,
+            self.state
+        ]
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Block {
+    pub header: Header,
+    pub transactions: Vec<Transaction>,
+    /// The certificate for the previous block, or None for the first block.
+    pub last_commit: Option<CommitCertificate<Block>>,
+}
+
+impl Hashable for Block {
+    fn hash(&self) -> Hash<Self> {
+        self.header.hash().cast()
+    }
+}
+
+impl Block {
+    /// Creates a block, filling in the header's commitments to the body.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        chain_id: String,
+        height: u64,
+        previous: Hash<Block>,
+        proposer: Hash<PublicKey>,
+        time: u128,
+        state: Hash<State>,
+        transactions: Vec<Transaction>,
+        last_commit: Option<CommitCertificate<Block>>,
+    ) -> Block {
+        Block {
+            header: Header {
+                chain_id,
+                height,
+                previous,
+                proposer,
+                time,
+                last_commit: last_commit.hash(),
+                transactions: MerkleTree::new(&transactions).hash().cast(),
+                state,
+            },
+            transactions,
+            last_commit,
+        }
+    }
+
+    /// Checks that the header commits to the transactions and last commit in the body.
+    pub fn verify(&self) -> bool {
+        self.header.last_commit == self.last_commit.hash()
+            && self.header.transactions == MerkleTree::new(&self.transactions).hash().cast()
+    }
+
+    /// Returns proof that the transaction at the given index is in the block.
+    pub fn prove(&self, index: usize) -> Option<InclusionProof> {
+        if index >= self.transactions.len() {
+            return None;
+        }
+        Some(InclusionProof {
+            index: index as u64,
+            total: self.transactions.len() as u64,
+            proof: MerkleTree::new(&self.transactions).construct_proof(index),
+        })
+    }
+}
+
+/// Proof that a transaction is in a block, given only the block's header.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct InclusionProof {
+    pub index: u64,
+    /// The number of transactions in the block.
+    pub total: u64,
+    pub proof: Vec<Hash>,
+}
+
+impl InclusionProof {
+    pub fn verify(&self, header: &Header, transaction: &Transaction) -> bool {
+        self.index < self.total
+            && MerkleTree::verify_proof(
+                self.index as usize,
+                self.total as usize,
+                transaction.clone(),
+                header.transactions.cast(),
+                &self.proof,
+            )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        crypto::contracts::PrivateKey,
+        testing::{transfer, CHAIN},
+    };
+
+    fn block(transactions: usize) -> Block {
+        let key = PrivateKey::generate();
+        let transactions = (0..transactions as u64)
+            .map(|nonce| transfer(&key, &key, 10, nonce))
+            .collect();
+        Block::new(
+            CHAIN.to_string(),
+            1,
+            Hash::empty(),
+            key.get_public().hash(),
+            1000,
+            State::default().hash(),
+            transactions,
+            None,
+        )
+    }
+
+    #[test]
+    fn hash_covers_only_the_header() {
+        let original = block(3);
+        assert!(original.verify());
+
+        let mut tampered = original.clone();
+        tampered.transactions.pop();
+        assert_eq!(tampered.hash(), original.hash());
+        assert!(!tampered.verify());
+
+        let mut tampered = original.clone();
+        tampered.header.time += 1;
+        assert_ne!(tampered.hash(), original.hash());
+    }
+
+    #[test]
+    fn proves_inclusion() {
+        for size in 1..=7 {
+            let block = block(size);
+            for (index, transaction) in block.transactions.iter().enumerate() {
+                let proof = block.prove(index).unwrap();
+                assert!(proof.verify(&block.header, transaction));
+
+                let other = &block.transactions[(index + 1) % size];
+                assert_eq!(proof.verify(&block.header, other), size == 1);
+            }
+            assert!(block.prove(size).is_none());
+        }
+
+        let block = block(4);
+        let mut proof = block.prove(1).unwrap();
+        proof.index = 2;
+        assert!(!proof.verify(&block.header, &block.transactions[1]));
+    }
+}
@@ -0,0 +1,221 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{consensus::CommitCertificate, transactions::state::State};
+
+use super::Block;
+
+/// A committed block and the certificate proving it was decided.
+pub(super) type Committed = (Block, CommitCertificate<Block>);
+
+/// The committed blocks, stored in order as one JSON entry per line, so that the
+/// chain can be rebuilt after a restart and served to nodes that are catching up.
+/// Blocks are read from disk as they are needed, rather than held in memory.
+///
+/// A snapshot of the state is kept alongside, so that reopening the store only has
+/// to apply the blocks since the snapshot was taken.
+pub(super) struct BlockStore {
+    file: File,
+    /// Where each block's entry starts in the file, by height.
+    offsets: Vec<u64>,
+    /// Where the next block's entry will start.
+    end: u64,
+    snapshot: PathBuf,
+}
+
+impl BlockStore {
+    /// Opens the store at the given path, creating it if it doesn't exist. Fails if a
+    /// stored block can't be read.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<BlockStore> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+
+        let mut reader = BufReader::new(&file);
+        let mut offsets = Vec::new();
+        let mut end = 0;
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let length = reader.read_until(b'\n', &mut line)?;
+            // an unterminated entry means that the node crashed before committing it
+            let entry = match line.strip_suffix(b"\n") {
+                Some(entry) => entry,
+                None => break,
+            };
+            // but a complete one that can't be read is corruption, which must not be
+            // hidden by discarding the blocks after it
+            parse(entry, offsets.len())?;
+            offsets.push(end);
+            end += length as u64;
+        }
+        // discard the partial entry, so that new entries start on a new line
+        file.set_len(end)?;
+        Ok(BlockStore {
+            file,
+            offsets,
+            end,
+            snapshot: path.with_extension("snapshot"),
+        })
+    }
+
+    /// Returns the number of stored blocks, which is the height of the next one.
+    pub fn len(&self) -> u64 {
+        self.offsets.len() as u64
+    }
+
+    /// Reads the block at the given height, or returns None if it isn't stored yet.
+    pub fn get(&mut self, height: u64) -> io::Result<Option<Committed>> {
+        let offset = match self.offsets.get(height as usize) {
+            Some(offset) => *offset,
+            None => return Ok(None),
+        };
+        self.file.seek(SeekFrom::Start(offset))?;
+        let mut line = Vec::new();
+        BufReader::new(&self.file).read_until(b'\n', &mut line)?;
+        parse(line.strip_suffix(b"\n").unwrap_or(&line), height as usize).map(Some)
+    }
+
+    /// Adds the block at the next height, returning once it is on disk.
+    pub fn append(
+        &mut self,
+        block: &Block,
+        certificate: &CommitCertificate<Block>,
+    ) -> io::Result<()> {
+        // encoded the same as a `Committed`
+        let mut line = serde_json::to_string(&(block, certificate))?;
+        line.push('\n');
+        // the file is opened for appending, so this writes at the end wherever the
+        // last read left the cursor
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync_data()?;
+        self.offsets.push(self.end);
+        self.end += line.len() as u64;
+        Ok(())
+    }
+
+    /// Returns the last snapshot, which is the state after the blocks below its height,
+    /// or None if none has been taken.
+    pub fn snapshot(&self) -> io::Result<Option<(u64, State)>> {
+        let bytes = match fs::read(&self.snapshot) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        bincode::deserialize(&bytes).map(Some).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("state snapshot is corrupt: {}", e),
+            )
+        })
+    }
+
+    /// Replaces the snapshot with the state after every stored block. The old one is
+    /// kept until the new one is on disk.
+    pub fn save_snapshot(&self, state: &State) -> io::Result<()> {
+        let bytes = bincode::serialize(&(self.len(), state))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let temporary = self.snapshot.with_extension("snapshot.tmp");
+        let mut file = File::create(&temporary)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(&temporary, &self.snapshot)
+    }
+}
+
+/// Reads the entry for the block at the given height.
+fn parse(entry: &[u8], height: usize) -> io::Result<Committed> {
+    serde_json::from_slice(entry).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("stored block at height {} is corrupt: {}", height, e),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hashing::{Hash, Hashable};
+
+    fn committed(height: u64) -> Committed {
+        let block = Block::new(
+            "test".to_string(),
+            height,
+            Hash::empty(),
+            Hash::empty(),
+            0,
+            Hash::empty(),
+            Vec::new(),
+            None,
+        );
+        let certificate = CommitCertificate {
+            height,
+            round: 0,
+            block: block.hash(),
+            precommits: Vec::new(),
+        };
+        (block, certificate)
+    }
+
+    fn write(path: &Path, heights: u64) {
+        let mut store = BlockStore::open(path).unwrap();
+        for height in 0..heights {
+            let (block, certificate) = committed(height);
+            store.append(&block, &certificate).unwrap();
+        }
+    }
+
+    #[test]
+    fn reads_blocks_by_height() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blocks");
+        write(&path, 2);
+
+        let mut store = BlockStore::open(&path).unwrap();
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get(1).unwrap(), Some(committed(1)));
+        // appending after a read doesn't overwrite the blocks after it
+        let (block, certificate) = committed(2);
+        store.append(&block, &certificate).unwrap();
+        assert_eq!(store.get(0).unwrap(), Some(committed(0)));
+        assert_eq!(store.get(2).unwrap(), Some(committed(2)));
+        assert_eq!(store.get(3).unwrap(), None);
+    }
+
+    #[test]
+    fn discards_only_a_torn_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blocks");
+        write(&path, 2);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"[{\"header\":{\"cha").unwrap();
+
+        let mut store = BlockStore::open(&path).unwrap();
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get(1).unwrap(), Some(committed(1)));
+        assert!(fs::read(&path).unwrap().ends_with(b"\n"));
+    }
+
+    #[test]
+    fn refuses_to_open_a_corrupt_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blocks");
+        write(&path, 3);
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        let corrupt = format!("{}\n{{not a block}}\n{}\n", lines[0], lines[2]);
+        fs::write(&path, &corrupt).unwrap();
+
+        let error = BlockStore::open(&path).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        // the blocks after the corrupt entry are left for the operator to recover
+        assert_eq!(fs::read_to_string(&path).unwrap(), corrupt);
+    }
+}
@@ -1,24 +1,80 @@
-use std::collections::HashMap;
-
 use crate::crypto::{
-    contracts::{Contract, PublicKey},
+    contracts::{Contract, PublicKey, Signable},
     hashing::{Hash, Hashable},
 };
 
+use super::{CommitCertificate, Evidence, ProposerSelector, ValidatorUpdate, Validators};
+
+/// Why an app couldn't commit a block that consensus decided. A node can't go on
+/// past a block it can't commit, so consensus stops with `Error::CommitFailed`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct CommitError(pub String);
+
 pub trait App<B: Hashable>: Clone {
     fn id(&self) -> Hash<PublicKey>;
 
-    fn validators(&self) -> HashMap<Hash<PublicKey>, u64>;
+    /// Identifies the network. Messages are signed for it, and messages signed for
+    /// any other chain are rejected.
+    fn chain_id(&self) -> &str;
+
+    /// Returns the height of the next block to be committed.
+    fn height(&self) -> u64;
+
+    /// Returns the time of the first block, in milliseconds since the Unix epoch.
+    fn genesis_time(&self) -> u128 {
+        0
+    }
 
-    fn total_votes(&self) -> u64;
+    /// Returns the validators for the given height. This must be known for the height
+    /// of the last block committed, the next one, and the UPDATE_DELAY - 1 heights after it.
+    fn validators(&self, height: u64) -> Validators;
 
-    fn proposer(&self, round: u64) -> Hash<PublicKey>;
+    /// Returns the selector that chooses the proposers at the given height, before
+    /// any round has been proposed. Every node must choose the same proposers.
+    fn proposers(&self, height: u64) -> ProposerSelector;
 
-    fn create_block(&self) -> B;
+    /// Returns the validator that proposes in the given round.
+    fn proposer(&self, height: u64, round: u64) -> Hash<PublicKey> {
+        self.proposers(height).proposer(round)
+    }
+
+    /// Creates a block for the next height. The block must include `last_commit`, the
+    /// certificate for the previous block (None for the first block), so that the
+    /// block time can be worked out from it.
+    fn create_block(&self, last_commit: Option<CommitCertificate<B>>) -> B;
+
+    /// Returns the certificate for the previous block that a block includes.
+    fn last_commit<'a>(&self, block: &'a B) -> Option<&'a CommitCertificate<B>>;
 
     fn validate_block(&self, block: &B) -> bool;
 
-    fn commit(&mut self, block: B);
+    /// Commits a decided block, along with the precommits that prove it was decided.
+    /// Returns changes to the validator set, which take effect UPDATE_DELAY heights
+    /// after the block, and must be reflected by `validators` from then on. Fails if
+    /// the app can't apply the block, which `validate_block` should have ruled out.
+    ///
+    /// `time` is the block time, in milliseconds since the Unix epoch: the stake-weighted
+    /// median of the timestamps in its `last_commit` (see `CommitCertificate::time`), or
+    /// `genesis_time` for the first block. Every block time is later than the one before.
+    fn commit(
+        &mut self,
+        block: B,
+        certificate: CommitCertificate<B>,
+        time: u128,
+    ) -> Result<Vec<ValidatorUpdate>, CommitError>;
+
+    /// Returns a previously committed block and its certificate, so that they
+    /// can be served to nodes that are catching up.
+    fn committed(&self, height: u64) -> Option<(B, CommitCertificate<B>)>;
+
+    /// Receives proof that a validator equivocated, so that it can be
+    /// included in a block and punished.
+    fn report_evidence(&mut self, evidence: Evidence<B>);
+
+    /// Signs for `chain_id`.
+    fn sign<T: Signable>(&self, contract: T) -> Contract<T>;
 
-    fn sign<T: Hashable>(&self, contract: T) -> Contract<T>;
+    /// Signs with a timestamp later than `earliest`, even if the clock is behind it.
+    /// Used for precommits, so that the next block time is later than the current one.
+    fn sign_after<T: Signable>(&self, contract: T, earliest: u128) -> Contract<T>;
 }
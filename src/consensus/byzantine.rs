@@ -0,0 +1,309 @@
+//! Validators that misbehave, for checking that honest nodes stay safe around them.
+
+use std::collections::VecDeque;
+
+use crate::crypto::hashing::{Hash, Hashable};
+
+use super::{
+    parts::propose,
+    simulation::{SimApp, SimBlock},
+    App, Broadcast, Precommit, Prevote, Proposal,
+};
+
+/// How many heights ahead a flooding validator sends votes for.
+const FLOOD_HEIGHTS: u64 = 10;
+
+/// How many rounds of each later height a flooding validator sends votes for.
+const FLOOD_ROUNDS: u64 = 3;
+
+/// How far ahead, in milliseconds, a validator with a skewed clock timestamps its precommits.
+const SKEW: u128 = 1_000_000_000;
+
+/// How many past messages a replaying validator remembers.
+const REPLAY_HISTORY: usize = 50;
+
+/// A way for a validator to misbehave. The node itself runs honest consensus,
+/// and its messages are corrupted on their way to the network.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Fault {
+    /// Sends one proposal or vote to the first half of the nodes, and a
+    /// conflicting one to the rest.
+    Equivocate,
+    /// Never sends prevotes or precommits.
+    WithholdVotes,
+    /// Proposes, and votes for, blocks that fail validation.
+    InvalidBlocks,
+    /// Sends every remembered message from an earlier round again alongside each new one.
+    Replay,
+    /// Sends votes for blocks at later heights alongside each message.
+    Flood,
+    /// Timestamps its precommits far in the future, to try to move block times.
+    SkewClock,
+}
+
+/// A message from a faulty node, for a single node or (if `to` is None) for everyone.
+pub struct Corrupted {
+    pub to: Option<usize>,
+    pub broadcast: Broadcast<SimBlock>,
+}
+
+/// Corrupts the messages of a single node.
+pub struct Byzantine {
+    fault: Fault,
+    app: SimApp,
+    nodes: usize,
+    sent: VecDeque<Broadcast<SimBlock>>,
+}
+
+impl Byzantine {
+    pub fn new(fault: Fault, app: SimApp, nodes: usize) -> Byzantine {
+        Byzantine {
+            fault,
+            app,
+            nodes,
+            sent: VecDeque::new(),
+        }
+    }
+
+    /// Returns the messages to send in place of one from the honest node.
+    pub fn corrupt(&mut self, broadcast: Broadcast<SimBlock>) -> Vec<Corrupted> {
+        let everyone = |broadcast| Corrupted {
+            to: None,
+            broadcast,
+        };
+        match self.fault {
+            Fault::Equivocate => {
+                let conflicting = self.conflicting(&broadcast);
+                (0..self.nodes)
+                    .flat_map(|to| {
+                        let messages = if to < self.nodes / 2 {
+                            vec![broadcast.clone()]
+                        } else {
+                            conflicting.clone()
+                        };
+                        messages.into_iter().map(move |broadcast| Corrupted {
+                            to: Some(to),
+                            broadcast,
+                        })
+                    })
+                    .collect()
+            }
+            Fault::WithholdVotes => match broadcast {
+                Broadcast::Proposal(_) | Broadcast::Part(_) => vec![everyone(broadcast)],
+                _ => Vec::new(),
+            },
+            Fault::InvalidBlocks => self.invalid(&broadcast).into_iter().map(everyone).collect(),
+            Fault::Replay => {
+                let (height, round) = (broadcast.height(), broadcast.round());
+                let mut messages: Vec<_> = self
+                    .sent
+                    .iter()
+                    .filter(|old| (old.height(), old.round()) < (height, round))
+                    .cloned()
+                    .map(everyone)
+                    .collect();
+                self.sent.push_back(broadcast.clone());
+                if self.sent.len() > REPLAY_HISTORY {
+                    self.sent.pop_front();
+                }
+                messages.push(everyone(broadcast));
+                messages
+            }
+            Fault::Flood => {
+                let height = broadcast.height();
+                let mut messages = vec![everyone(broadcast)];
+                for h in height + 1..=height + FLOOD_HEIGHTS {
+                    let id = Some(self.block(h).hash());
+                    for round in 0..FLOOD_ROUNDS {
+                        let prevote = self.app.sign(Prevote::new(h, round, id));
+                        let precommit = self.app.sign(Precommit::new(h, round, id));
+                        messages.push(everyone(Broadcast::Prevote(prevote)));
+                        messages.push(everyone(Broadcast::Precommit(precommit)));
+                    }
+                }
+                messages
+            }
+            Fault::SkewClock => match broadcast {
+                Broadcast::Precommit(c) => {
+                    let precommit = self.app.sign_after(c.content, c.timestamp + SKEW);
+                    vec![everyone(Broadcast::Precommit(precommit))]
+                }
+                _ => vec![everyone(broadcast)],
+            },
+        }
+    }
+
+    /// A valid block that no honest node would propose.
+    fn block(&self, height: u64) -> SimBlock {
+        let previous = height.checked_sub(1).and_then(|h| self.app.committed(h));
+        SimBlock {
+            height,
+            proposer: self.nodes + self.app.index(),
+            last_commit: previous.map(|(_, certificate)| certificate),
+        }
+    }
+
+    /// Returns the messages for the same step as the given one, but for a different block.
+    /// A proposal is followed by the parts of its block, and the honest parts are withheld.
+    fn conflicting(&self, broadcast: &Broadcast<SimBlock>) -> Vec<Broadcast<SimBlock>> {
+        let block = self.block(broadcast.height());
+        let other = |id: Option<Hash<SimBlock>>| match id {
+            Some(id) if id == block.hash() => None,
+            _ => Some(block.hash()),
+        };
+        match broadcast {
+            Broadcast::Proposal(c) => self.propose(&c.content, &block),
+            Broadcast::Prevote(c) => vec![Broadcast::Prevote(self.app.sign(Prevote {
+                id: other(c.content.id),
+                ..c.content.clone()
+            }))],
+            Broadcast::Precommit(c) => vec![Broadcast::Precommit(self.app.sign(Precommit {
+                id: other(c.content.id),
+                ..c.content.clone()
+            }))],
+            Broadcast::Part(_) => Vec::new(),
+        }
+    }
+
+    /// Returns the messages with their block, or the block they vote for, replaced by an
+    /// invalid one. The parts of the honest block are dropped.
+    fn invalid(&self, broadcast: &Broadcast<SimBlock>) -> Vec<Broadcast<SimBlock>> {
+        // blocks must be for the height they are proposed at
+        let block = SimBlock {
+            height: u64::MAX,
+            proposer: self.app.index(),
+            last_commit: None,
+        };
+        let id = Some(block.hash());
+        match broadcast {
+            Broadcast::Proposal(c) => self.propose(&c.content, &block),
+            Broadcast::Prevote(c) => vec![Broadcast::Prevote(self.app.sign(Prevote {
+                id,
+                ..c.content.clone()
+            }))],
+            Broadcast::Precommit(c) => vec![Broadcast::Precommit(self.app.sign(Precommit {
+                id,
+                ..c.content.clone()
+            }))],
+            Broadcast::Part(_) => Vec::new(),
+        }
+    }
+
+    /// Proposes the given block in place of an honest proposal.
+    fn propose(&self, honest: &Proposal<SimBlock>, block: &SimBlock) -> Vec<Broadcast<SimBlock>> {
+        let (proposal, parts) = propose(
+            &self.app,
+            honest.height,
+            honest.round,
+            block,
+            honest.valid_round,
+        );
+        std::iter::once(Broadcast::Proposal(proposal))
+            .chain(parts.into_iter().map(Broadcast::Part))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::Duration;
+
+    use super::*;
+    use crate::consensus::simulation::{Faulty, Report, Simulation};
+
+    const LIMIT: Duration = Duration::from_secs(600);
+
+    /// Runs the simulation, after checking that at most f of the voting power is faulty.
+    async fn run(sim: &Simulation, heights: u64) -> Report {
+        let total: u64 = sim.stakes.iter().sum();
+        let faulty: u64 = sim.faulty.iter().map(|f| sim.stakes[f.node]).sum();
+        assert!(faulty <= total / 3);
+        let report = sim.run(heights, LIMIT).await;
+        report.assert_safe();
+        report.assert_certified();
+        report.assert_time_increases();
+        report
+    }
+
+    /// Four validators, one of which has the given fault.
+    async fn single_fault(fault: Fault) -> Report {
+        let mut sim = Simulation::new(4, 11);
+        sim.faulty.push(Faulty { node: 0, fault });
+        let report = run(&sim, 4).await;
+        for node in 1..4 {
+            assert_eq!(report.decided(node), vec![0, 1, 2, 3]);
+        }
+        report
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn equivocation() {
+        single_fault(Fault::Equivocate).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn withheld_votes() {
+        single_fault(Fault::WithholdVotes).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn invalid_blocks() {
+        let report = single_fault(Fault::InvalidBlocks).await;
+        assert!(report.decisions.iter().all(|d| d.block.height == d.height));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replayed_messages() {
+        single_fault(Fault::Replay).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flooded_future_heights() {
+        let report = single_fault(Fault::Flood).await;
+        // the flooded blocks were never decided
+        assert!(report.decisions.iter().all(|d| d.block.proposer < 4));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn skewed_clock() {
+        let report = single_fault(Fault::SkewClock).await;
+        // block times stay within the virtual time of the run
+        let limit = LIMIT.as_millis();
+        assert!(report.decisions.iter().all(|d| d.block_time < limit));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn heavy_equivocator() {
+        let mut sim = Simulation::new(6, 12);
+        // exactly f of the voting power
+        sim.stakes = vec![2, 1, 1, 1, 1, 1];
+        sim.faulty.push(Faulty {
+            node: 0,
+            fault: Fault::Equivocate,
+        });
+        run(&sim, 3).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn mixed_faults_on_slow_network() {
+        let faults = [
+            Fault::Equivocate,
+            Fault::WithholdVotes,
+            Fault::InvalidBlocks,
+            Fault::Replay,
+            Fault::Flood,
+        ];
+        for seed in 0..4 {
+            let mut sim = Simulation::new(7, 20 + seed);
+            sim.network.max_delay = Duration::from_millis(1500);
+            sim.network.drop_rate = 0.05;
+            for (node, fault) in [5, 6].iter().zip(faults.iter().skip(seed as usize)) {
+                sim.faulty.push(Faulty {
+                    node: *node,
+                    fault: *fault,
+                });
+            }
+            run(&sim, 3).await;
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::{
+    contracts::{Contract, PublicKey},
+    hashing::{Hash, Hashable},
+};
+
+use super::Precommit;
+
+/// Proof that a block was decided: precommits for it from more than two
+/// thirds of the voting weight, all in the same round.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct CommitCertificate<B> {
+    pub height: u64,
+    pub round: u64,
+    pub block: Hash<B>,
+    pub precommits: Vec<Contract<Precommit<B>>>,
+}
+
+impl<B> CommitCertificate<B> {
+    /// Checks the certificate against the chain and the validator set for its height.
+    pub fn verify(&self, chain_id: &str, validators: &HashMap<Hash<PublicKey>, u64>) -> bool {
+        let total: u128 = validators.values().map(|weight| *weight as u128).sum();
+        let mut signees = HashSet::new();
+        let mut weight = 0u128;
+        for contract in self.precommits.iter() {
+            let precommit = &contract.content;
+            let signee = contract.signee.hash();
+            if precommit.height != self.height
+                || precommit.round != self.round
+                || precommit.id != Some(self.block)
+                || !signees.insert(signee)
+                || !contract.verify(chain_id)
+            {
+                return false;
+            }
+            weight += *validators.get(&signee).unwrap_or(&0) as u128;
+        }
+        // more than two thirds, which any two quorums overlap in more than a third of
+        weight * 3 > total * 2
+    }
+
+    /// Returns the stake-weighted median of the precommit timestamps: the earliest
+    /// timestamp that more than half of the precommitted weight is at or before. Faulty
+    /// validators hold less than half of the weight in a valid certificate, so the result
+    /// is always between the timestamps of two honest validators.
+    pub fn time(&self, validators: &HashMap<Hash<PublicKey>, u64>) -> u128 {
+        let mut timestamps: Vec<(u128, u64)> = self
+            .precommits
+            .iter()
+            .map(|contract| {
+                let weight = validators.get(&contract.signee.hash()).unwrap_or(&0);
+                (contract.timestamp, *weight)
+            })
+            .collect();
+        timestamps.sort_unstable();
+        let total: u64 = timestamps.iter().map(|(_, weight)| weight).sum();
+        let mut weight = 0;
+        for (timestamp, w) in timestamps {
+            weight += w;
+            if weight > total / 2 {
+                return timestamp;
+            }
+        }
+        0
+    }
+}
+
+impl<B> Hashable for CommitCertificate<B> {
+    fn hash(&self) -> Hash<Self> {
+        let mut precommits = Vec::new();
+        for contract in self.precommits.iter() {
+            precommits.extend_from_slice(contract.hash().get_bytes());
+        }
+        hash![self.height, self.round, self.block, precommits]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::simulation::CHAIN_ID;
+    use crate::crypto::contracts::PrivateKey;
+
+    fn setup() -> (Vec<PrivateKey>, HashMap<Hash<PublicKey>, u64>) {
+        let keys: Vec<PrivateKey> = (0..4).map(|_| PrivateKey::generate()).collect();
+        let validators = keys.iter().map(|k| (k.get_public().hash(), 1)).collect();
+        (keys, validators)
+    }
+
+    fn certificate(keys: &[PrivateKey], block: Hash<u64>) -> CommitCertificate<u64> {
+        CommitCertificate {
+            height: 2,
+            round: 1,
+            block,
+            precommits: keys
+                .iter()
+                .map(|k| k.sign(CHAIN_ID, Precommit::new(2, 1, Some(block))))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn valid_certificate() {
+        let (keys, validators) = setup();
+        assert!(certificate(&keys[..3], 7.hash().cast()).verify(CHAIN_ID, &validators));
+        assert!(certificate(&keys, 7.hash().cast()).verify(CHAIN_ID, &validators));
+        assert!(!certificate(&keys, 7.hash().cast()).verify("other", &validators));
+    }
+
+    #[test]
+    fn insufficient_weight() {
+        let (keys, validators) = setup();
+        assert!(!certificate(&keys[..2], 7.hash().cast()).verify(CHAIN_ID, &validators));
+
+        // votes from outside the validator set don't count
+        let outsider = PrivateKey::generate();
+        let mut cert = certificate(&keys[..2], 7.hash().cast());
+        cert.precommits
+            .push(outsider.sign(CHAIN_ID, Precommit::new(2, 1, Some(cert.block))));
+        assert!(!cert.verify(CHAIN_ID, &validators));
+    }
+
+    #[test]
+    fn needs_more_than_two_thirds() {
+        let keys: Vec<PrivateKey> = (0..3).map(|_| PrivateKey::generate()).collect();
+        let block: Hash<u64> = 7.hash().cast();
+        let weighted = |weights: &[u64]| -> HashMap<Hash<PublicKey>, u64> {
+            keys.iter()
+                .map(|k| k.get_public().hash())
+                .zip(weights.iter().cloned())
+                .collect()
+        };
+
+        // neither of two equal validators is a quorum alone
+        let validators = weighted(&[1, 1]);
+        assert!(!certificate(&keys[..1], block).verify(CHAIN_ID, &validators));
+        assert!(certificate(&keys[..2], block).verify(CHAIN_ID, &validators));
+
+        // 67 of 101 is less than two thirds, while 68 is more
+        let validators = weighted(&[67, 1, 33]);
+        assert!(!certificate(&keys[..1], block).verify(CHAIN_ID, &validators));
+        assert!(certificate(&keys[..2], block).verify(CHAIN_ID, &validators));
+    }
+
+    #[test]
+    fn duplicate_signee() {
+        let (keys, validators) = setup();
+        let mut cert = certificate(&keys[..2], 7.hash().cast());
+        cert.precommits.push(cert.precommits[0].clone());
+        assert!(!cert.verify(CHAIN_ID, &validators));
+    }
+
+    #[test]
+    fn weighted_median_time() {
+        let (keys, mut validators) = setup();
+        let block: Hash<u64> = 7.hash().cast();
+        let cert = |times: &[u128]| CommitCertificate {
+            height: 2,
+            round: 1,
+            block,
+            precommits: keys
+                .iter()
+                .zip(times)
+                .map(|(k, t)| k.sign_at(CHAIN_ID, Precommit::new(2, 1, Some(block)), *t))
+                .collect(),
+        };
+        assert_eq!(cert(&[10, 40, 20, 30]).time(&validators), 30);
+        assert_eq!(cert(&[10, 20, 30]).time(&validators), 20);
+
+        // a faulty validator can't move the time outside the honest timestamps
+        assert_eq!(cert(&[10, 20, 30, u128::MAX]).time(&validators), 30);
+        assert_eq!(cert(&[0, 20, 30, 40]).time(&validators), 30);
+
+        *validators.get_mut(&keys[3].get_public().hash()).unwrap() = 3;
+        assert_eq!(cert(&[10, 20, 30, 40]).time(&validators), 40);
+    }
+
+    #[test]
+    fn mismatched_precommit() {
+        let (keys, validators) = setup();
+        let mut cert = certificate(&keys, 7.hash().cast());
+        cert.precommits[0] = keys[0].sign(CHAIN_ID, Precommit::new(2, 0, Some(cert.block)));
+        assert!(!cert.verify(CHAIN_ID, &validators));
+
+        let mut cert = certificate(&keys, 7.hash().cast());
+        cert.block = 8.hash().cast();
+        assert!(!cert.verify(CHAIN_ID, &validators));
+    }
+}
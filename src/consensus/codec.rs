@@ -0,0 +1,185 @@
+use bincode::{ErrorKind, Options};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::crypto::hashing::Hashable;
+
+use super::{Broadcast, SyncMessage};
+
+/// The version of the encoding, sent as the first byte of every message.
+pub const VERSION: u8 = 1;
+
+/// The largest encoded message, including the version byte.
+pub const MAX_MESSAGE_SIZE: usize = 1 << 20;
+
+/// A message sent between nodes.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(bound(serialize = "B: Serialize", deserialize = "B: DeserializeOwned"))]
+pub enum WireMessage<B: Hashable> {
+    Consensus(Broadcast<B>),
+    Sync(SyncMessage<B>),
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    /// The message is larger than MAX_MESSAGE_SIZE.
+    TooLarge,
+    /// There was no version byte.
+    Empty,
+    /// The message was encoded with a version that this node doesn't understand.
+    UnknownVersion(u8),
+    /// The bytes aren't a valid message, or are followed by trailing bytes.
+    Malformed(bincode::Error),
+}
+
+impl From<bincode::Error> for CodecError {
+    fn from(error: bincode::Error) -> Self {
+        match *error {
+            ErrorKind::SizeLimit => CodecError::TooLarge,
+            _ => CodecError::Malformed(error),
+        }
+    }
+}
+
+/// Fixed-width little-endian integers, so that every node encodes a value
+/// to exactly the same bytes.
+pub(super) fn options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_little_endian()
+        .reject_trailing_bytes()
+}
+
+fn limited() -> impl Options {
+    options().with_limit(MAX_MESSAGE_SIZE as u64 - 1)
+}
+
+pub fn encode<B: Hashable + Serialize>(message: &WireMessage<B>) -> Result<Vec<u8>, CodecError> {
+    let mut bytes = vec![VERSION];
+    limited().serialize_into(&mut bytes, message)?;
+    Ok(bytes)
+}
+
+/// Decodes a message from an untrusted peer. Nothing is allocated beyond the bytes
+/// received, so a short message can't claim to hold a huge one.
+pub fn decode<B: Hashable + DeserializeOwned>(bytes: &[u8]) -> Result<WireMessage<B>, CodecError> {
+    if bytes.len() > MAX_MESSAGE_SIZE {
+        return Err(CodecError::TooLarge);
+    }
+    match bytes.split_first() {
+        None => Err(CodecError::Empty),
+        Some((&VERSION, rest)) => Ok(limited().deserialize(rest)?),
+        Some((&version, _)) => Err(CodecError::UnknownVersion(version)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        consensus::{
+            parts::propose,
+            simulation::{Simulation, CHAIN_ID},
+            App, BlockPart, CommitCertificate, PartsHeader, Precommit, Prevote,
+        },
+        crypto::{contracts::PrivateKey, hashing::Hash},
+    };
+
+    fn messages() -> Vec<WireMessage<Vec<u8>>> {
+        let app = &Simulation::new(1, 0).apps()[0];
+        let block = vec![1, 2, 3];
+        let (proposal, mut parts) = propose(app, 3, 1, &block, Some(0));
+        let precommit = app.sign(Precommit::new(3, 1, Some(block.hash())));
+        vec![
+            WireMessage::Consensus(Broadcast::Proposal(proposal)),
+            WireMessage::Consensus(Broadcast::Part(parts.remove(0))),
+            WireMessage::Consensus(Broadcast::Prevote(app.sign(Prevote::new(3, 1, None)))),
+            WireMessage::Consensus(Broadcast::Precommit(precommit.clone())),
+            WireMessage::Sync(SyncMessage::Request { height: 2 }),
+            WireMessage::Sync(SyncMessage::Block {
+                certificate: CommitCertificate {
+                    height: 3,
+                    round: 1,
+                    block: block.hash(),
+                    precommits: vec![precommit],
+                },
+                block,
+            }),
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        for message in messages() {
+            let bytes = encode(&message).unwrap();
+            assert_eq!(bytes[0], VERSION);
+            assert_eq!(decode::<Vec<u8>>(&bytes).unwrap(), message);
+            // re-encoding gives the same bytes
+            assert_eq!(encode(&decode::<Vec<u8>>(&bytes).unwrap()).unwrap(), bytes);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_framing() {
+        let bytes = encode(&messages().remove(1)).unwrap();
+        assert!(matches!(decode::<Vec<u8>>(&[]), Err(CodecError::Empty)));
+
+        let mut future = bytes.clone();
+        future[0] = VERSION + 1;
+        assert!(matches!(
+            decode::<Vec<u8>>(&future),
+            Err(CodecError::UnknownVersion(v)) if v == VERSION + 1
+        ));
+
+        let truncated = &bytes[..bytes.len() - 1];
+        assert!(matches!(
+            decode::<Vec<u8>>(truncated),
+            Err(CodecError::Malformed(_))
+        ));
+
+        let mut trailing = bytes;
+        trailing.push(0);
+        assert!(matches!(
+            decode::<Vec<u8>>(&trailing),
+            Err(CodecError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn enforces_size_limit() {
+        let key = PrivateKey::generate();
+        let part = |size| {
+            WireMessage::<Vec<u8>>::Consensus(Broadcast::Part(key.sign(
+                CHAIN_ID,
+                BlockPart {
+                    height: 0,
+                    round: 0,
+                    header: PartsHeader {
+                        total: 1,
+                        root: Hash::empty(),
+                    },
+                    index: 0,
+                    bytes: vec![0u8; size],
+                    proof: Vec::new(),
+                },
+            )))
+        };
+        assert!(encode(&part(MAX_MESSAGE_SIZE / 2)).is_ok());
+        assert!(matches!(
+            encode(&part(MAX_MESSAGE_SIZE)),
+            Err(CodecError::TooLarge)
+        ));
+        assert!(matches!(
+            decode::<Vec<u8>>(&vec![VERSION; MAX_MESSAGE_SIZE + 1]),
+            Err(CodecError::TooLarge)
+        ));
+
+        // a synced block whose length prefix claims far more bytes than were sent,
+        // which fails without allocating the claimed length
+        let mut bomb = vec![VERSION];
+        bomb.extend(1u32.to_le_bytes().iter());
+        bomb.extend(1u32.to_le_bytes().iter());
+        bomb.extend(u64::MAX.to_le_bytes().iter());
+        assert!(decode::<Vec<u8>>(&bomb).is_err());
+    }
+}
@@ -0,0 +1,119 @@
+use std::{convert::TryFrom, sync::Arc};
+
+use tokio::time::Duration;
+
+use super::{Clock, TokioClock};
+
+/// Timeouts used by consensus. Each timeout grows linearly with the round
+/// number, so that a round eventually lasts long enough for messages to be
+/// delivered once the network is synchronous.
+#[derive(Clone, Debug)]
+pub struct ConsensusConfig {
+    /// How long to wait for a proposal in round 0.
+    pub propose: Duration,
+    /// How much longer to wait for a proposal in each subsequent round.
+    pub propose_delta: Duration,
+    /// How long to wait for more prevotes after seeing 2f+1 of any kind.
+    pub prevote: Duration,
+    pub prevote_delta: Duration,
+    /// How long to wait for more precommits after seeing 2f+1 of any kind.
+    pub precommit: Duration,
+    pub precommit_delta: Duration,
+    /// How long to wait after committing a block before starting the next
+    /// height, so that slower validators can catch up.
+    pub commit_delay: Duration,
+    /// How long to wait for the current height to be decided, after hearing from
+    /// validators at later heights, before fetching it from peers instead.
+    /// Requests are repeated at the same interval.
+    pub sync_timeout: Duration,
+    /// Caps on the messages stored for each height.
+    pub buffer: BufferLimits,
+    /// The source of time for timeouts.
+    pub clock: Arc<dyn Clock>,
+}
+
+/// Limits on the messages a node keeps, so that peers can't fill its memory with
+/// messages that won't be looked at until later, or at all. Repeated messages count
+/// against the limits too.
+#[derive(Clone, Copy, Debug)]
+pub struct BufferLimits {
+    /// The most messages stored for any one later height. The current height isn't
+    /// capped as a whole, as every validator's messages are needed to make progress.
+    pub per_height: usize,
+    /// The most messages stored from one validator for any one height, including the
+    /// current one, where only messages for rounds other than the current one and the
+    /// next are capped. A correct validator sends two votes a round, and a proposal and
+    /// its parts in the rounds it proposes, so this should allow for many rounds.
+    pub per_validator: usize,
+    /// The most rounds of a height that one validator's proposals and block parts are
+    /// kept for. When it proposes in a later round, its lowest round is forgotten.
+    pub proposed_rounds: usize,
+    /// The most messages held for heights just past those with known validators.
+    pub overflow: usize,
+    /// How many heights past those with known validators are held in the overflow buffer.
+    pub overflow_heights: u64,
+}
+
+impl Default for BufferLimits {
+    fn default() -> Self {
+        BufferLimits {
+            per_height: 16384,
+            per_validator: 1024,
+            proposed_rounds: 4,
+            overflow: 1024,
+            overflow_heights: 2,
+        }
+    }
+}
+
+impl Default for ConsensusConfig {
+    fn default() -> Self {
+        ConsensusConfig {
+            propose: Duration::from_millis(3000),
+            propose_delta: Duration::from_millis(500),
+            prevote: Duration::from_millis(1000),
+            prevote_delta: Duration::from_millis(500),
+            precommit: Duration::from_millis(1000),
+            precommit_delta: Duration::from_millis(500),
+            commit_delay: Duration::from_millis(1000),
+            sync_timeout: Duration::from_millis(2000),
+            buffer: BufferLimits::default(),
+            clock: Arc::new(TokioClock),
+        }
+    }
+}
+
+impl ConsensusConfig {
+    pub fn propose_timeout(&self, round: u64) -> Duration {
+        grow(self.propose, self.propose_delta, round)
+    }
+
+    pub fn prevote_timeout(&self, round: u64) -> Duration {
+        grow(self.prevote, self.prevote_delta, round)
+    }
+
+    pub fn precommit_timeout(&self, round: u64) -> Duration {
+        grow(self.precommit, self.precommit_delta, round)
+    }
+}
+
+/// Returns base + round * delta, saturating rather than overflowing.
+fn grow(base: Duration, delta: Duration, round: u64) -> Duration {
+    let round = u32::try_from(round).unwrap_or(u32::MAX);
+    base.saturating_add(delta.saturating_mul(round))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeouts_grow_with_round() {
+        let config = ConsensusConfig::default();
+        assert_eq!(config.propose_timeout(0), Duration::from_millis(3000));
+        assert_eq!(config.propose_timeout(4), Duration::from_millis(5000));
+        assert_eq!(config.prevote_timeout(1), Duration::from_millis(1500));
+        assert_eq!(config.precommit_timeout(2), Duration::from_millis(2000));
+        assert!(config.precommit_timeout(u64::MAX) >= config.precommit_timeout(1 << 40));
+    }
+}
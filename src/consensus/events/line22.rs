@@ -1,16 +1,18 @@
+use serde::{de::DeserializeOwned, Serialize};
+
 use crate::{
     consensus::{App, Broadcast, Error, Prevote, Step, Tendermint},
     crypto::hashing::Hashable,
 };
 
-impl<A: App<B>, B: Hashable + Clone + Eq> Tendermint<A, B> {
+impl<A: App<B>, B: Hashable + Clone + Eq + Serialize + DeserializeOwned> Tendermint<A, B> {
     /// Receives a proposal, and votes according to whether the value is valid.
     pub async fn line22(&mut self) -> Result<bool, Error> {
         match self.line22_check() {
             None => Ok(false),
             Some(v) => {
                 // if valid(v) ^ (lockedRound_p = -1 || lockedValue_p = v)
-                let vote_id = if self.app.validate_block(v)
+                let vote_id = if self.valid_block(v)
                     && self.locked.as_ref().map(|x| &x.value == v).unwrap_or(true)
                 {
                     // id(v)
@@ -40,21 +42,21 @@ impl<A: App<B>, B: Hashable + Clone + Eq> Tendermint<A, B> {
             return None;
         }
 
-        let proposer = self.app.proposer(self.current.round);
+        let proposer = self.proposer(self.current.round);
         self.log
             .get_current()
             // Upon <proposal, ...>
             .proposals
             .iter()
             // From proposer(hp, round)
-            .filter(|contract| contract.signee.hash() == proposer)
-            .map(|contract| &contract.content)
+            .filter(|proposed| proposed.contract.signee.hash() == proposer)
             // Where <..., h_p, round_p, v, -1>
-            .find(|proposal| {
+            .find(|proposed| {
+                let proposal = &proposed.contract.content;
                 (proposal.height, proposal.round, proposal.valid_round)
                     == (self.height, self.current.round, None)
             })
             // Return v
-            .map(|contract| &contract.proposal)
+            .map(|proposed| &proposed.block)
     }
 }
@@ -1,16 +1,18 @@
+use serde::{de::DeserializeOwned, Serialize};
+
 use crate::{
     consensus::{App, Broadcast, Error, Prevote, Step, Tendermint},
     crypto::hashing::Hashable,
 };
 
-impl<A: App<B>, B: Hashable + Clone + Eq> Tendermint<A, B> {
+impl<A: App<B>, B: Hashable + Clone + Eq + Serialize + DeserializeOwned> Tendermint<A, B> {
     /// Recieves a proposal with 2f+1 prevotes and prevotes according to whether the value is valid.
     pub async fn line28(&mut self) -> Result<bool, Error> {
         match self.line28_check() {
             None => Ok(false),
             Some((v, valid_round)) => {
                 // if valid(v) & (lockedRound <= vr || lockedValue = v)
-                let vote_id = if self.app.validate_block(v)
+                let vote_id = if self.valid_block(v)
                     && self
                         .locked
                         .as_ref()
@@ -43,7 +45,7 @@ impl<A: App<B>, B: Hashable + Clone + Eq> Tendermint<A, B> {
             return None;
         }
 
-        let proposer = self.app.proposer(self.current.round);
+        let proposer = self.proposer(self.current.round);
 
         let messages = self.log.get_current();
         messages
@@ -51,42 +53,24 @@ impl<A: App<B>, B: Hashable + Clone + Eq> Tendermint<A, B> {
             .proposals
             .iter()
             // from proposer(h_p, round_p)
-            .filter(|contract| contract.signee.hash() == proposer)
-            .map(|contract| &contract.content)
-            .filter_map(|proposal| {
+            .filter(|proposed| proposed.contract.signee.hash() == proposer)
+            .filter_map(|proposed| {
+                let proposal = &proposed.contract.content;
                 // upon <..., h_p, round_p, v, vr> where (vr >= 0 ^ vr < round_p)
                 proposal.valid_round.and_then(|vr| {
                     if vr < self.current.round
                         && proposal.height == self.height
                         && proposal.round == self.current.round
                     {
-                        Some((&proposal.proposal, vr))
+                        Some((&proposed.block, vr))
                     } else {
                         None
                     }
                 })
             })
-            // AND 2f+1 <prevote, ...>
+            // AND 2f+1 <prevote, h_p, vr, id(v)>
             .find(|(proposal, valid_round)| {
-                let id = proposal.hash();
-                let total_weight = messages
-                    .prevotes
-                    .iter()
-                    .map(|contract| {
-                        (
-                            self.voting_weight(contract.signee.hash()),
-                            &contract.content,
-                        )
-                    })
-                    // <_, h_p, vr, id(v)>
-                    .filter(|(_, prevote)| {
-                        prevote.height == self.height
-                            && prevote.round == *valid_round
-                            && prevote.id == Some(id)
-                    })
-                    .map(|(weight, _)| weight)
-                    .sum::<u64>();
-                total_weight > self.two_f()
+                messages.prevotes(*valid_round, Some(proposal.hash())) > self.two_f()
             })
     }
 }
@@ -1,11 +1,11 @@
-use std::time::Duration;
+use serde::{de::DeserializeOwned, Serialize};
 
 use crate::{
     consensus::{App, Error, Step, Tendermint, Timeouts},
     crypto::hashing::Hashable,
 };
 
-impl<A: App<B>, B: Hashable + Clone + Eq> Tendermint<A, B> {
+impl<A: App<B>, B: Hashable + Clone + Eq + Serialize + DeserializeOwned> Tendermint<A, B> {
     // Schedules a timeout foir the prevote stage
     pub fn line34(&mut self) -> Result<bool, Error> {
         if self.line34_check() {
@@ -15,7 +15,7 @@ impl<A: App<B>, B: Hashable + Clone + Eq> Tendermint<A, B> {
                     height: self.height,
                     round: self.current.round,
                 },
-                Duration::from_millis(1000),
+                self.config.prevote_timeout(self.current.round),
             );
 
             // prevent function from triggering again
@@ -39,24 +39,7 @@ impl<A: App<B>, B: Hashable + Clone + Eq> Tendermint<A, B> {
             return false;
         }
 
-        let total: u64 = self
-            .log
-            .get_current()
-            // upon <Prevote, ...>
-            .prevotes
-            .iter()
-            // upon <_, h_p, round_p, *>
-            .filter_map(|contract| {
-                let content = &contract.content;
-                if content.height == self.height && content.round == self.current.round {
-                    Some(self.voting_weight(contract.signee.hash()))
-                } else {
-                    None
-                }
-            })
-            .sum();
-
-        // 2f+1
-        total > self.two_f()
+        // upon 2f+1 <prevote, h_p, round_p, *>
+        self.log.get_current().all_prevotes(self.current.round) > self.two_f()
     }
 }
@@ -1,42 +1,51 @@
+use serde::{de::DeserializeOwned, Serialize};
+
 use crate::{
-    consensus::{App, Broadcast, Error, Precommit, Record, Step, Tendermint},
+    consensus::{App, Broadcast, Error, EventKind, Precommit, Record, Step, Tendermint},
     crypto::hashing::Hashable,
 };
 
-impl<A: App<B>, B: Hashable + Clone + Eq> Tendermint<A, B> {
+impl<A: App<B>, B: Hashable + Clone + Eq + Serialize + DeserializeOwned> Tendermint<A, B> {
     pub async fn line36(&mut self) -> Result<bool, Error> {
-        match self.line36_check().cloned() {
-            Some(b) => {
+        match self.line36_check() {
+            Some((b, time)) => {
+                let b = b.clone();
                 if self.current.step.is_prevote() {
                     self.locked = Some(Record {
                         round: self.current.round,
                         value: b.clone(),
                     });
+                    self.notify(EventKind::Locked, Some(b.hash()));
 
                     let vote = Precommit::new(self.height, self.current.round, Some(b.hash()));
-
-                    self.broadcast(Broadcast::Precommit(self.app.sign(vote)))
+                    // timestamped after b, so that the block that follows it is later
+                    self.broadcast(Broadcast::Precommit(self.app.sign_after(vote, time)))
                         .await?;
 
                     self.current.step = Step::Precommit;
                 }
+                self.notify(EventKind::ValidUpdated, Some(b.hash()));
                 self.valid = Some(Record {
                     round: self.current.round,
                     value: b,
                 });
+
+                // prevent function from triggering again
+                self.current.valid_updated = true;
                 Ok(true)
             }
             None => Ok(false),
         }
     }
 
-    pub fn line36_check(&self) -> Option<&B> {
-        // while step_p >= prevote
-        if self.current.step.is_propose() {
+    /// Returns the block, and its time.
+    pub fn line36_check(&self) -> Option<(&B, u128)> {
+        // while step_p >= prevote for the first time
+        if self.current.step.is_propose() || self.current.valid_updated {
             return None;
         }
 
-        let proposer = self.app.proposer(self.current.round);
+        let proposer = self.proposer(self.current.round);
 
         let messages = self.log.get_current();
         messages
@@ -44,40 +53,16 @@ impl<A: App<B>, B: Hashable + Clone + Eq> Tendermint<A, B> {
             .proposals
             .iter()
             // from proposer(h_p, round_p)
-            .filter(|contract| contract.signee.hash() == proposer)
-            .map(|contract| &contract.content)
-            .filter_map(|proposal| {
-                // upon <..., h_p, round_p, v, *> while valid(v)
-                if proposal.height == self.height
-                    && proposal.round == self.current.round
-                    && self.app.validate_block(&proposal.proposal)
-                {
-                    Some(&proposal.proposal)
-                } else {
-                    None
-                }
-            })
-            // AND 2f+1 <prevote, ...>
-            .find(|proposal| {
-                let id = proposal.hash();
-                let total_weight = messages
-                    .prevotes
-                    .iter()
-                    .map(|contract| {
-                        (
-                            self.voting_weight(contract.signee.hash()),
-                            &contract.content,
-                        )
-                    })
-                    // <_, h_p, round_p, id(v)>
-                    .filter(|(_, prevote)| {
-                        prevote.height == self.height
-                            && prevote.round == self.current.round
-                            && prevote.id == Some(id)
-                    })
-                    .map(|(weight, _)| weight)
-                    .sum::<u64>();
-                total_weight > self.two_f()
+            .filter(|proposed| proposed.contract.signee.hash() == proposer)
+            // upon <..., h_p, round_p, v, *>
+            .filter(|proposed| {
+                let proposal = &proposed.contract.content;
+                proposal.height == self.height && proposal.round == self.current.round
             })
+            .map(|proposed| &proposed.block)
+            // AND 2f+1 <prevote, h_p, round_p, id(v)>
+            .filter(|v| messages.prevotes(self.current.round, Some(v.hash())) > self.two_f())
+            // while valid(v), which is checked last as it is the most expensive
+            .find_map(|v| self.valid_time(v).map(|time| (v, time)))
     }
 }
@@ -1,9 +1,11 @@
+use serde::{de::DeserializeOwned, Serialize};
+
 use crate::{
     consensus::{App, Broadcast, Error, Precommit, Step, Tendermint},
     crypto::hashing::Hashable,
 };
 
-impl<A: App<B>, B: Hashable + Clone + Eq> Tendermint<A, B> {
+impl<A: App<B>, B: Hashable + Clone + Eq + Serialize + DeserializeOwned> Tendermint<A, B> {
     pub async fn line44(&mut self) -> Result<bool, Error> {
         if self.line44_check() {
             // broadcast <precommit, h_p, round_p, nil>
@@ -25,27 +27,7 @@ impl<A: App<B>, B: Hashable + Clone + Eq> Tendermint<A, B> {
             return false;
         }
 
-        let total: u64 = self
-            .log
-            .get_current()
-            // upon <Prevote, ...>
-            .prevotes
-            .iter()
-            // upon <_, h_p, round_p, nil>
-            .filter_map(|contract| {
-                let content = &contract.content;
-                if content.height == self.height
-                    && content.round == self.current.round
-                    && content.id == None
-                {
-                    Some(self.voting_weight(contract.signee.hash()))
-                } else {
-                    None
-                }
-            })
-            .sum();
-
-        // 2f+1
-        total > self.two_f()
+        // upon 2f+1 <prevote, h_p, round_p, nil>
+        self.log.get_current().prevotes(self.current.round, None) > self.two_f()
     }
 }
@@ -1,14 +1,20 @@
+use serde::{de::DeserializeOwned, Serialize};
+
 use crate::{
     consensus::{App, Error, Tendermint},
     crypto::hashing::Hashable,
 };
 
-impl<A: App<B>, B: Hashable + Clone + Eq> Tendermint<A, B> {
+impl<A: App<B>, B: Hashable + Clone + Eq + Serialize + DeserializeOwned> Tendermint<A, B> {
     pub async fn line49(&mut self) -> Result<bool, Error> {
-        match self.line49_check().cloned() {
-            Some(b) => {
+        match self.line49_check() {
+            Some((r, b, time)) => {
+                let certificate = self.log.get_current().certificate(self.height, r, b.hash());
+                let b = b.clone();
+
                 // h_p <- h_p + 1
-                self.new_height(self.height + 1, Some(b)).await?;
+                self.new_height(self.height + 1, Some((b, certificate, time)))
+                    .await?;
 
                 Ok(true)
             }
@@ -16,7 +22,8 @@ impl<A: App<B>, B: Hashable + Clone + Eq> Tendermint<A, B> {
         }
     }
 
-    pub fn line49_check(&self) -> Option<&B> {
+    /// Returns the round of the decision, the block, and its time.
+    pub fn line49_check(&self) -> Option<(u64, &B, u128)> {
         // while decision_p[h_p] = nil is redundant, because if it wasn't nil then h_p would have been incremented
 
         let messages = self.log.get_current();
@@ -25,39 +32,16 @@ impl<A: App<B>, B: Hashable + Clone + Eq> Tendermint<A, B> {
             .proposals
             .iter()
             // from proposer(h_p, r)
-            .filter(|contract| contract.signee.hash() == self.app.proposer(contract.content.round))
-            .map(|contract| &contract.content)
-            .filter_map(|proposal| {
-                // upon <_, h_p, _, v, *>
-                // if valid(v)
-                if proposal.height == self.height && self.app.validate_block(&proposal.proposal) {
-                    Some((proposal.round, &proposal.proposal))
-                } else {
-                    None
-                }
-            })
-            // AND 2f+1 <precommit, ...>
-            .find(|(r, v)| {
-                let id = v.hash();
-                let total_weight = messages
-                    .precommits
-                    .iter()
-                    .map(|contract| {
-                        (
-                            self.voting_weight(contract.signee.hash()),
-                            &contract.content,
-                        )
-                    })
-                    // <_, h_p, r, id(v)>
-                    .filter(|(_, prevote)| {
-                        prevote.height == self.height
-                            && prevote.round == *r
-                            && prevote.id == Some(id)
-                    })
-                    .map(|(weight, _)| weight)
-                    .sum::<u64>();
-                total_weight > self.two_f()
+            .filter(|proposed| {
+                let contract = &proposed.contract;
+                contract.signee.hash() == self.proposer(contract.content.round)
             })
-            .map(|(_, v)| v)
+            // upon <_, h_p, _, v, *>
+            .filter(|proposed| proposed.contract.content.height == self.height)
+            .map(|proposed| (proposed.contract.content.round, &proposed.block))
+            // AND 2f+1 <precommit, h_p, r, id(v)>
+            .filter(|(r, v)| messages.precommits(*r, Some(v.hash())) > self.two_f())
+            // if valid(v), which is checked last as it is the most expensive
+            .find_map(|(r, v)| self.valid_time(v).map(|time| (r, v, time)))
     }
 }
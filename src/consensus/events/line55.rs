@@ -1,15 +1,17 @@
-use itertools::Itertools;
+use serde::{de::DeserializeOwned, Serialize};
 
 use crate::{
-    consensus::{log::Message, App, Error, Tendermint},
+    consensus::{App, Error, EventKind, Tendermint},
     crypto::hashing::Hashable,
 };
 
-impl<A: App<B>, B: Hashable + Clone + Eq> Tendermint<A, B> {
+impl<A: App<B>, B: Hashable + Clone + Eq + Serialize + DeserializeOwned> Tendermint<A, B> {
     // Advances the round if enough voters have posted messages
     pub async fn line55(&mut self) -> Result<bool, Error> {
         match self.line55_check() {
             Some(round) => {
+                self.events
+                    .emit(EventKind::RoundSkipped, self.height, round, None);
                 self.start_round(round).await?;
                 Ok(true)
             }
@@ -18,32 +20,9 @@ impl<A: App<B>, B: Hashable + Clone + Eq> Tendermint<A, B> {
     }
 
     pub fn line55_check(&self) -> Option<u64> {
-        // upon <*, h_p, round, *, *>
+        // upon f+1 <*, h_p, round, *, *> with round > round_p
         self.log
             .get_current()
-            .all()
-            .map(|m| match m {
-                Message::Proposal(c) => (c.content.round, c.signee.hash()),
-                Message::Prevote(c) => (c.content.round, c.signee.hash()),
-                Message::Precommit(c) => (c.content.round, c.signee.hash()),
-            })
-            // with round > round_p
-            .filter(|(round, _)| round > &self.current.round)
-            .group_by(|(round, _)| *round)
-            .into_iter()
-            .map(|(round, messages)| {
-                (
-                    round,
-                    messages
-                        .into_iter()
-                        .map(|(_, signee)| signee)
-                        .dedup()
-                        .map(|signee| self.voting_weight(signee))
-                        .sum::<u64>(),
-                )
-            })
-            // upon f+1
-            .find(|(_, total)| *total > self.f())
-            .map(|(round, _)| round)
+            .next_round_above(self.current.round, self.f())
     }
 }
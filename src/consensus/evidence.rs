@@ -0,0 +1,122 @@
+use crate::crypto::{
+    contracts::{Contract, PublicKey},
+    hashing::{Hash, Hashable},
+};
+
+use super::{log::Vote, Precommit, Prevote};
+
+/// Proof that a validator signed two conflicting votes in the same round.
+#[derive(Clone)]
+pub enum Evidence<B> {
+    Prevotes(Contract<Prevote<B>>, Contract<Prevote<B>>),
+    Precommits(Contract<Precommit<B>>, Contract<Precommit<B>>),
+}
+
+impl<B> Evidence<B> {
+    /// Checks the evidence without any other context: both votes must be validly
+    /// signed for the chain by the same validator, for the same height and round,
+    /// but different ids.
+    pub fn verify(&self, chain_id: &str) -> bool {
+        match self {
+            Evidence::Prevotes(first, second) => conflicting(chain_id, first, second),
+            Evidence::Precommits(first, second) => conflicting(chain_id, first, second),
+        }
+    }
+
+    /// Returns the validator that signed both votes.
+    pub fn offender(&self) -> Hash<PublicKey> {
+        match self {
+            Evidence::Prevotes(first, _) => first.signee.hash(),
+            Evidence::Precommits(first, _) => first.signee.hash(),
+        }
+    }
+
+    pub fn height(&self) -> u64 {
+        match self {
+            Evidence::Prevotes(first, _) => first.content.height,
+            Evidence::Precommits(first, _) => first.content.height,
+        }
+    }
+}
+
+fn conflicting<V: Vote>(chain_id: &str, first: &Contract<V>, second: &Contract<V>) -> bool {
+    first.signee == second.signee
+        && first.content.height() == second.content.height()
+        && first.content.round() == second.content.round()
+        && first.content.id() != second.content.id()
+        && first.verify(chain_id)
+        && second.verify(chain_id)
+}
+
+impl<B> Hashable for Evidence<B> {
+    fn hash(&self) -> Hash<Self> {
+        match self {
+            Evidence::Prevotes(first, second) => hash![0u8, first, second],
+            Evidence::Precommits(first, second) => hash![1u8, first, second],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::simulation::CHAIN_ID;
+    use crate::crypto::contracts::PrivateKey;
+
+    fn ids() -> (Option<Hash<u64>>, Option<Hash<u64>>) {
+        (Some(1u64.hash()), Some(2u64.hash()))
+    }
+
+    #[test]
+    fn conflicting_votes() {
+        let key = PrivateKey::generate();
+        let (a, b) = ids();
+
+        let evidence = Evidence::Prevotes(
+            key.sign(CHAIN_ID, Prevote::new(3, 1, a)),
+            key.sign(CHAIN_ID, Prevote::new(3, 1, b)),
+        );
+        assert!(evidence.verify(CHAIN_ID));
+        assert!(evidence.offender() == key.get_public().hash());
+
+        let evidence = Evidence::Precommits(
+            key.sign(CHAIN_ID, Precommit::new(3, 1, a)),
+            key.sign(CHAIN_ID, Precommit::new(3, 1, None)),
+        );
+        assert!(evidence.verify(CHAIN_ID));
+    }
+
+    #[test]
+    fn votes_that_do_not_conflict() {
+        let key = PrivateKey::generate();
+        let other = PrivateKey::generate();
+        let (a, b) = ids();
+
+        let same_id = Evidence::Prevotes(
+            key.sign(CHAIN_ID, Prevote::new(3, 1, a)),
+            key.sign(CHAIN_ID, Prevote::new(3, 1, a)),
+        );
+        let different_rounds = Evidence::Prevotes(
+            key.sign(CHAIN_ID, Prevote::new(3, 1, a)),
+            key.sign(CHAIN_ID, Prevote::new(3, 2, b)),
+        );
+        let different_signees = Evidence::Precommits(
+            key.sign(CHAIN_ID, Precommit::new(3, 1, a)),
+            other.sign(CHAIN_ID, Precommit::new(3, 1, b)),
+        );
+        assert!(!same_id.verify(CHAIN_ID));
+        assert!(!different_rounds.verify(CHAIN_ID));
+        assert!(!different_signees.verify(CHAIN_ID));
+    }
+
+    #[test]
+    fn forged_vote() {
+        let key = PrivateKey::generate();
+        let (a, b) = ids();
+
+        let mut forged = key.sign(CHAIN_ID, Prevote::new(3, 1, a));
+        forged.content.id = b;
+        let evidence = Evidence::Prevotes(key.sign(CHAIN_ID, Prevote::new(3, 1, a)), forged);
+        assert!(!evidence.verify(CHAIN_ID));
+    }
+}
@@ -0,0 +1,53 @@
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::mpsc::{Receiver, Sender};
+
+use crate::{
+    crypto::hashing::Hashable,
+    network::{Network, NetworkEvent},
+};
+
+use super::{decode, encode, Broadcast, SyncMessage, WireMessage};
+
+/// Carries a node's messages over a gossipsub topic, in both directions. Gossipsub doesn't
+/// deliver a node's own messages back to it, which `Tendermint` doesn't need, as it counts
+/// its own votes as it signs them.
+///
+/// Messages that can't be decoded are dropped, as are messages that arrive while the node
+/// is too busy to take them. Runs until the node stops sending.
+pub async fn gossip<B: Hashable + Clone + Serialize + DeserializeOwned>(
+    mut network: Network,
+    incoming: Sender<Broadcast<B>>,
+    mut outgoing: Receiver<Broadcast<B>>,
+    sync_incoming: Sender<SyncMessage<B>>,
+    mut sync_outgoing: Receiver<SyncMessage<B>>,
+) {
+    loop {
+        let message = tokio::select! {
+            event = network.next_event() => {
+                if let NetworkEvent::Received(message) = event {
+                    match decode(&message.data) {
+                        Ok(WireMessage::Consensus(broadcast)) => {
+                            let _ = incoming.try_send(broadcast);
+                        }
+                        Ok(WireMessage::Sync(message)) => {
+                            let _ = sync_incoming.try_send(message);
+                        }
+                        Err(_) => (),
+                    }
+                }
+                continue;
+            }
+            broadcast = outgoing.recv() => match broadcast {
+                Some(broadcast) => WireMessage::Consensus(broadcast),
+                None => return,
+            },
+            Some(message) = sync_outgoing.recv() => WireMessage::Sync(message),
+        };
+        // every message a node creates fits, as long as the app keeps its blocks small
+        // enough to sync (see `SyncMessage::Block`)
+        if let Ok(bytes) = encode(&message) {
+            // publishing fails when there are no peers yet, which retries can't fix in time
+            let _ = network.broadcast(&bytes).await;
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::{BTreeMap, HashMap};
+
+use tokio::sync::{mpsc, oneshot};
+
+use crate::crypto::{contracts::PublicKey, hashing::Hash};
+
+use super::{LogStats, Record, Step};
+
+/// The state of a node at one moment, for diagnosing rounds that don't make progress.
+#[derive(Clone, Debug)]
+pub struct Snapshot<B> {
+    pub height: u64,
+    pub round: u64,
+    pub step: Step,
+    pub locked: Option<Record<B>>,
+    pub valid: Option<Record<B>>,
+    /// The proposer for the current round.
+    pub proposer: Hash<PublicKey>,
+    /// The total voting weight at the current height.
+    pub total_weight: u64,
+    /// The votes received in each round of the current height.
+    pub tallies: BTreeMap<u64, Tally<B>>,
+    pub buffer: LogStats,
+    pub paused: bool,
+}
+
+/// The weight of the votes for each block (None is nil) in a round.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tally<B> {
+    pub prevotes: HashMap<Option<Hash<B>>, u64>,
+    pub precommits: HashMap<Option<Hash<B>>, u64>,
+}
+
+/// Returned when the node is no longer running.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Stopped;
+
+pub(super) enum Command<B> {
+    Snapshot(oneshot::Sender<Snapshot<B>>),
+    Pause(oneshot::Sender<()>),
+    Resume(oneshot::Sender<()>),
+    Shutdown(oneshot::Sender<()>),
+}
+
+/// Inspects and controls a running node. Clones control the same node.
+pub struct Handle<B> {
+    sender: mpsc::Sender<Command<B>>,
+}
+
+// derived Clone would require B: Clone
+impl<B> Clone for Handle<B> {
+    fn clone(&self) -> Self {
+        Handle {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+/// The receiving end of a `Handle`, to be passed to `Tendermint::start`.
+pub struct Controls<B> {
+    pub(super) receiver: mpsc::Receiver<Command<B>>,
+}
+
+impl<B> Handle<B> {
+    /// Creates a handle, and the controls for the node it will control.
+    pub fn new() -> (Handle<B>, Controls<B>) {
+        let (sender, receiver) = mpsc::channel(16);
+        (Handle { sender }, Controls { receiver })
+    }
+
+    /// Returns the node's current state.
+    pub async fn snapshot(&self) -> Result<Snapshot<B>, Stopped> {
+        self.request(Command::Snapshot).await
+    }
+
+    /// Stops the node from processing messages and timeouts until it is resumed. Messages
+    /// that arrive in the meantime wait in the incoming channel, and timeouts that expire
+    /// fire on resuming. Snapshots can still be taken.
+    pub async fn pause(&self) -> Result<(), Stopped> {
+        self.request(Command::Pause).await
+    }
+
+    pub async fn resume(&self) -> Result<(), Stopped> {
+        self.request(Command::Resume).await
+    }
+
+    /// Stops the node, which returns from `Tendermint::start` with `Ok(())`. Its state is
+    /// in the WAL, so it can be started again from where it left off.
+    pub async fn shutdown(&self) -> Result<(), Stopped> {
+        self.request(Command::Shutdown).await
+    }
+
+    async fn request<T>(
+        &self,
+        command: impl FnOnce(oneshot::Sender<T>) -> Command<B>,
+    ) -> Result<T, Stopped> {
+        let (reply, response) = oneshot::channel();
+        self.sender
+            .send(command(reply))
+            .await
+            .map_err(|_| Stopped)?;
+        response.await.map_err(|_| Stopped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::{sleep, Duration};
+
+    use super::*;
+    use crate::{
+        consensus::{simulation::Simulation, App, ConsensusConfig},
+        testing::lone_validator,
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn inspects_and_controls_a_node() {
+        let app = Simulation::new(1, 0).apps().remove(0);
+        let id = app.id();
+        let dir = tempfile::tempdir().unwrap();
+        let (handle, node) =
+            lone_validator(app, ConsensusConfig::default(), &dir.path().join("wal"));
+
+        sleep(Duration::from_secs(10)).await;
+        let snapshot = handle.snapshot().await.unwrap();
+        assert!(snapshot.height > 0);
+        assert_eq!(snapshot.proposer, id);
+        assert_eq!(snapshot.total_weight, 1);
+        assert!(!snapshot.paused);
+
+        handle.pause().await.unwrap();
+        let height = handle.snapshot().await.unwrap().height;
+        sleep(Duration::from_secs(10)).await;
+        let snapshot = handle.snapshot().await.unwrap();
+        assert!(snapshot.paused);
+        assert_eq!(snapshot.height, height);
+
+        handle.clone().resume().await.unwrap();
+        sleep(Duration::from_secs(10)).await;
+        assert!(handle.snapshot().await.unwrap().height > height);
+
+        handle.shutdown().await.unwrap();
+        assert!(node.await.unwrap().is_ok());
+        assert_eq!(handle.pause().await, Err(Stopped));
+    }
+}
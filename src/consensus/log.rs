@@ -1,85 +1,835 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 
-use crate::crypto::{contracts::Contract, hashing::Hashable};
+use bincode::Options;
+use serde::{de::DeserializeOwned, Serialize};
 
-use super::{Broadcast, Precommit, Prevote, Proposal};
+use crate::crypto::{
+    contracts::{Contract, PublicKey, Signable},
+    hashing::{Hash, Hashable},
+};
 
-const LIMIT: u64 = 5;
+use super::{
+    codec::options, parts::PartSet, BlockPart, Broadcast, BufferLimits, CommitCertificate,
+    Evidence, Precommit, Prevote, Proposal, Tally, UPDATE_DELAY,
+};
 
-pub enum Message<'a, B: Hashable> {
-    Proposal(&'a Contract<Proposal<B>>),
-    Prevote(&'a Contract<Prevote<B>>),
-    Precommit(&'a Contract<Precommit<B>>),
+/// A vote for a block (or for nil).
+pub trait Vote: Signable {
+    type Block;
+
+    fn height(&self) -> u64;
+
+    fn round(&self) -> u64;
+
+    fn id(&self) -> Option<Hash<Self::Block>>;
+}
+
+impl<B> Vote for Prevote<B> {
+    type Block = B;
+
+    fn height(&self) -> u64 {
+        self.height
+    }
+
+    fn round(&self) -> u64 {
+        self.round
+    }
+
+    fn id(&self) -> Option<Hash<B>> {
+        self.id
+    }
+}
+
+impl<B> Vote for Precommit<B> {
+    type Block = B;
+
+    fn height(&self) -> u64 {
+        self.height
+    }
+
+    fn round(&self) -> u64 {
+        self.round
+    }
+
+    fn id(&self) -> Option<Hash<B>> {
+        self.id
+    }
+}
+
+/// The votes of a single type for a single height and round.
+/// Stores at most one vote per validator, and keeps running tallies.
+pub struct VoteSet<V: Vote> {
+    votes: HashMap<Hash<PublicKey>, Contract<V>>,
+    /// The total weight of the votes for each block id (None is nil).
+    tallies: HashMap<Option<Hash<V::Block>>, u64>,
+    total: u64,
+    /// The signees that have already been caught voting twice.
+    equivocators: HashSet<Hash<PublicKey>>,
+}
+
+impl<V: Vote + Clone> VoteSet<V> {
+    pub fn new() -> VoteSet<V> {
+        VoteSet {
+            votes: HashMap::new(),
+            tallies: HashMap::new(),
+            total: 0,
+            equivocators: HashSet::new(),
+        }
+    }
+
+    /// Adds a vote with the given weight. If the signee has already voted, the
+    /// new vote is ignored, and both votes are returned if they are the first to
+    /// conflict, so that each equivocation is only reported once.
+    pub fn add(
+        &mut self,
+        contract: Contract<V>,
+        weight: u64,
+    ) -> Option<(Contract<V>, Contract<V>)> {
+        let signee = contract.signee.hash();
+        match self.votes.get(&signee) {
+            Some(existing) if existing.content.id() != contract.content.id() => {
+                if self.equivocators.insert(signee) {
+                    Some((existing.clone(), contract))
+                } else {
+                    None
+                }
+            }
+            Some(_) => None,
+            None => {
+                *self.tallies.entry(contract.content.id()).or_insert(0) += weight;
+                self.total += weight;
+                self.votes.insert(signee, contract);
+                None
+            }
+        }
+    }
+
+    /// Returns the total weight of votes for the given id.
+    pub fn weight(&self, id: Option<Hash<V::Block>>) -> u64 {
+        *self.tallies.get(&id).unwrap_or(&0)
+    }
+
+    /// Returns the total weight of all votes.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Returns the votes for the given id, ordered by signee.
+    pub fn votes_for(&self, id: Option<Hash<V::Block>>) -> Vec<Contract<V>> {
+        let mut votes: Vec<Contract<V>> = self
+            .votes
+            .values()
+            .filter(|contract| contract.content.id() == id)
+            .cloned()
+            .collect();
+        votes.sort_by_key(|contract| *contract.signee.hash().get_bytes());
+        votes
+    }
+}
+
+/// A proposal whose block has been reassembled from its parts.
+pub struct Proposed<B: Hashable> {
+    pub contract: Contract<Proposal<B>>,
+    pub block: B,
 }
 
 pub struct Messages<B: Hashable> {
-    pub proposals: Vec<Contract<Proposal<B>>>,
-    pub prevotes: Vec<Contract<Prevote<B>>>,
-    pub precommits: Vec<Contract<Precommit<B>>>,
+    pub proposals: Vec<Proposed<B>>,
+    /// Proposals still waiting for some of their block parts.
+    pending: Vec<Contract<Proposal<B>>>,
+    /// The block parts received in each round. Only the proposer can sign parts,
+    /// so a round only has parts for more than one block if it equivocated.
+    parts: HashMap<u64, PartSet>,
+    /// The rounds that each validator has sent proposals or block parts for.
+    proposed_rounds: HashMap<Hash<PublicKey>, BTreeSet<u64>>,
+    prevotes: HashMap<u64, VoteSet<Prevote<B>>>,
+    precommits: HashMap<u64, VoteSet<Precommit<B>>>,
+    /// The validators that have sent any message in each round, and their total weight.
+    senders: BTreeMap<u64, (HashSet<Hash<PublicKey>>, u64)>,
+    /// The number of messages added from each validator.
+    received: HashMap<Hash<PublicKey>, usize>,
+    /// The number of messages added, and their total encoded size.
+    stored: usize,
+    bytes: u64,
 }
 
-impl<B: Hashable> Messages<B> {
+impl<B: Hashable + Clone + DeserializeOwned> Messages<B> {
     pub fn new() -> Messages<B> {
         Messages {
             proposals: Vec::new(),
-            prevotes: Vec::new(),
-            precommits: Vec::new(),
+            pending: Vec::new(),
+            parts: HashMap::new(),
+            proposed_rounds: HashMap::new(),
+            prevotes: HashMap::new(),
+            precommits: HashMap::new(),
+            senders: BTreeMap::new(),
+            received: HashMap::new(),
+            stored: 0,
+            bytes: 0,
+        }
+    }
+
+    /// Returns the weight of prevotes in the given round for the given id.
+    pub fn prevotes(&self, round: u64, id: Option<Hash<B>>) -> u64 {
+        self.prevotes.get(&round).map_or(0, |v| v.weight(id))
+    }
+
+    /// Returns the weight of all prevotes in the given round.
+    pub fn all_prevotes(&self, round: u64) -> u64 {
+        self.prevotes.get(&round).map_or(0, VoteSet::total)
+    }
+
+    /// Returns the weight of precommits in the given round for the given id.
+    pub fn precommits(&self, round: u64, id: Option<Hash<B>>) -> u64 {
+        self.precommits.get(&round).map_or(0, |v| v.weight(id))
+    }
+
+    /// Returns the weight of all precommits in the given round.
+    pub fn all_precommits(&self, round: u64) -> u64 {
+        self.precommits.get(&round).map_or(0, VoteSet::total)
+    }
+
+    /// Returns the weight of the votes for each block in each round.
+    pub fn tallies(&self) -> BTreeMap<u64, Tally<B>> {
+        let rounds = self.prevotes.keys().chain(self.precommits.keys());
+        rounds
+            .map(|round| {
+                let tally = Tally {
+                    prevotes: self
+                        .prevotes
+                        .get(round)
+                        .map_or_else(HashMap::new, |v| v.tallies.clone()),
+                    precommits: self
+                        .precommits
+                        .get(round)
+                        .map_or_else(HashMap::new, |v| v.tallies.clone()),
+                };
+                (*round, tally)
+            })
+            .collect()
+    }
+
+    /// Collects the precommits for a block into a certificate.
+    pub fn certificate(&self, height: u64, round: u64, block: Hash<B>) -> CommitCertificate<B> {
+        CommitCertificate {
+            height,
+            round,
+            block,
+            precommits: self
+                .precommits
+                .get(&round)
+                .map_or_else(Vec::new, |v| v.votes_for(Some(block))),
+        }
+    }
+
+    /// Returns the first round after the given one where the weight of
+    /// validators that have sent messages is greater than the threshold.
+    pub fn next_round_above(&self, round: u64, threshold: u64) -> Option<u64> {
+        self.senders
+            .range(round + 1..)
+            .find(|(_, (_, weight))| *weight > threshold)
+            .map(|(round, _)| *round)
+    }
+
+    /// Adds a proposal, unless the signee has already proposed in the round. Returns
+    /// false if it was dropped because of the limit on rounds.
+    fn add_proposal(&mut self, contract: Contract<Proposal<B>>, limits: &BufferLimits) -> bool {
+        let round = contract.content.round;
+        let signee = contract.signee.hash();
+        if !self.keep_round(signee, round, limits.proposed_rounds) {
+            return false;
+        }
+        let proposed =
+            |c: &Contract<Proposal<B>>| c.content.round == round && c.signee == contract.signee;
+        if self.pending.iter().any(proposed) || self.proposals.iter().any(|p| proposed(&p.contract))
+        {
+            return true;
+        }
+        let header = contract.content.parts;
+        // parts that arrived first are kept if they match, and otherwise belong to an equivocation
+        let first = !self.pending.iter().any(|c| c.content.round == round)
+            && !self
+                .proposals
+                .iter()
+                .any(|p| p.contract.content.round == round);
+        if first && self.parts.get(&round).map(PartSet::header) != Some(header) {
+            self.parts.insert(round, PartSet::new(header));
+        }
+        self.pending.push(contract);
+        self.assemble(round);
+        true
+    }
+
+    /// Adds a block part. Returns false if it was dropped because of the limit on rounds.
+    fn add_part(
+        &mut self,
+        signee: Hash<PublicKey>,
+        part: BlockPart,
+        limits: &BufferLimits,
+    ) -> bool {
+        let round = part.round;
+        if !self.keep_round(signee, round, limits.proposed_rounds) {
+            return false;
+        }
+        self.parts
+            .entry(round)
+            .or_insert_with(|| PartSet::new(part.header))
+            .add(part);
+        self.assemble(round);
+        true
+    }
+
+    /// Notes that the signee sent a proposal or block part for the round, forgetting its
+    /// lowest round if it would otherwise have too many. Returns false if the round is
+    /// lower than every round kept for the signee, and there is no room for it.
+    fn keep_round(&mut self, signee: Hash<PublicKey>, round: u64, limit: usize) -> bool {
+        let rounds = self.proposed_rounds.entry(signee).or_default();
+        if rounds.contains(&round) {
+            return true;
+        }
+        let lowest = rounds.iter().next().copied();
+        if rounds.len() >= limit {
+            match lowest {
+                Some(lowest) if lowest < round => {
+                    rounds.remove(&lowest);
+                    self.forget_round(lowest);
+                }
+                _ => return false,
+            }
+        }
+        self.proposed_rounds
+            .entry(signee)
+            .or_default()
+            .insert(round);
+        true
+    }
+
+    /// Discards the proposals and block parts for a round.
+    fn forget_round(&mut self, round: u64) {
+        self.parts.remove(&round);
+        self.pending.retain(|c| c.content.round != round);
+        self.proposals.retain(|p| p.contract.content.round != round);
+    }
+
+    /// Moves the round's pending proposals into `proposals` if their block is complete.
+    /// Proposals whose parts don't decode to the proposed block are discarded.
+    fn assemble(&mut self, round: u64) {
+        let parts = match self.parts.get(&round) {
+            Some(parts) => parts,
+            None => return,
+        };
+        let block: B = match parts.assemble() {
+            Some(block) => block,
+            None => return,
+        };
+        let header = parts.header();
+        let (ready, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|c| c.content.round == round && c.content.parts == header);
+        self.pending = pending;
+        for contract in ready {
+            if contract.content.block == block.hash() {
+                self.proposals.push(Proposed {
+                    contract,
+                    block: block.clone(),
+                });
+            }
         }
     }
 
-    pub fn all(&self) -> impl Iterator<Item = Message<B>> {
-        let proposals = self.proposals.iter().map(|x| Message::Proposal(x));
-        let prevotes = self.prevotes.iter().map(|x| Message::Prevote(x));
-        let precommits = self.precommits.iter().map(|x| Message::Precommit(x));
+    /// Returns true if a message from the signee fits within the limits. The limit on
+    /// the whole height only applies to later heights.
+    fn admits(&self, signee: &Hash<PublicKey>, later: bool, limits: &BufferLimits) -> bool {
+        (!later || self.stored < limits.per_height)
+            && self.received.get(signee).copied().unwrap_or(0) < limits.per_validator
+    }
 
-        proposals.chain(prevotes).chain(precommits)
+    fn record_size(&mut self, signee: Hash<PublicKey>, bytes: u64) {
+        *self.received.entry(signee).or_insert(0) += 1;
+        self.stored += 1;
+        self.bytes += bytes;
     }
+
+    fn record_sender(&mut self, round: u64, signee: Hash<PublicKey>, weight: u64) {
+        let (signees, total) = self
+            .senders
+            .entry(round)
+            .or_insert_with(|| (HashSet::new(), 0));
+        if signees.insert(signee) {
+            *total += weight;
+        }
+    }
+}
+
+/// The number and size of the messages in a `MessageLog`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LogStats {
+    /// The number of messages stored for each height, including the current one.
+    pub messages: BTreeMap<u64, usize>,
+    /// The number of messages in the overflow buffer.
+    pub overflow: usize,
+    /// The total encoded size of the stored and overflowing messages, in bytes.
+    pub bytes: u64,
+    /// The number of messages dropped because a limit was reached.
+    pub dropped: u64,
 }
 
+/// Stores the messages for the current height and the heights after it whose
+/// validators are already known, UPDATE_DELAY heights in all. Messages further ahead
+/// can't be checked against a validator set, so they aren't stored, but those just
+/// past the window can be kept with `hold` until `release` moves them into the log.
 pub struct MessageLog<B: Hashable> {
     height: u64,
+    /// The current round, whose messages and the next round's are always stored.
+    round: u64,
+    limits: BufferLimits,
     messages: HashMap<u64, Messages<B>>,
+    /// Messages for heights just past those whose validators are known, which can't be
+    /// validated yet.
+    overflow: VecDeque<Broadcast<B>>,
+    overflow_bytes: u64,
+    dropped: u64,
+    /// Equivocations that have been detected, but not yet taken.
+    evidence: Vec<Evidence<B>>,
 }
 
-impl<B: Hashable> MessageLog<B> {
-    pub fn new() -> MessageLog<B> {
-        let mut messages = HashMap::new();
-        for i in 0..LIMIT {
-            messages.insert(i, Messages::new());
-        }
-        MessageLog {
+impl<B: Hashable + Clone + Serialize + DeserializeOwned> MessageLog<B> {
+    pub fn new(limits: BufferLimits) -> MessageLog<B> {
+        let mut log = MessageLog {
             height: 0,
-            messages,
+            round: 0,
+            limits,
+            messages: HashMap::new(),
+            overflow: VecDeque::new(),
+            overflow_bytes: 0,
+            dropped: 0,
+            evidence: Vec::new(),
+        };
+        log.set_height(0);
+        log
+    }
+
+    /// Discards messages below the given height, and makes room for messages
+    /// from the heights whose validators are already known.
+    pub fn set_height(&mut self, height: u64) {
+        self.height = height;
+        self.round = 0;
+        self.messages.retain(|h, _| *h >= height);
+        for h in height..height + UPDATE_DELAY {
+            self.messages.entry(h).or_insert_with(Messages::new);
         }
     }
 
-    pub fn increment_height(&mut self) {
-        self.messages.remove(&self.height);
-        self.height += 1;
-        self.messages.insert(self.height + LIMIT, Messages::new());
+    /// Moves to a round of the current height.
+    pub fn set_round(&mut self, round: u64) {
+        self.round = round;
     }
 
-    pub fn add(&mut self, broadcast: Broadcast<B>) {
+    /// Returns true if messages for the given height are currently being stored.
+    pub fn accepts(&self, height: u64) -> bool {
+        self.messages.contains_key(&height)
+    }
+
+    /// Adds a message from a validator with the given voting weight.
+    /// Repeated votes from the same validator are ignored, but recorded
+    /// as evidence if they conflict with the first vote.
+    /// Messages are dropped once the limits are reached, except those for the current
+    /// round and the next, which are needed to make progress however long the height
+    /// has stalled. They can't fill the log anyway, as each validator's votes are only
+    /// stored once a round, and its proposals and parts for a bounded number of rounds.
+    pub fn add(&mut self, broadcast: Broadcast<B>, weight: u64) {
+        let height = broadcast.height();
+        let signee = broadcast.signee().hash();
+        let needed = height == self.height && broadcast.round() <= self.round.saturating_add(1);
+        let m = match self.messages.get_mut(&height) {
+            Some(m) => m,
+            None => return,
+        };
+        if !needed && !m.admits(&signee, height > self.height, &self.limits) {
+            self.dropped += 1;
+            return;
+        }
+        m.record_size(signee, size(&broadcast));
         match broadcast {
             Broadcast::Proposal(contract) => {
-                if let Some(m) = self.messages.get_mut(&contract.content.height) {
-                    m.proposals.push(contract)
+                m.record_sender(contract.content.round, signee, weight);
+                if !m.add_proposal(contract, &self.limits) {
+                    self.dropped += 1;
+                }
+            }
+            Broadcast::Part(contract) => {
+                m.record_sender(contract.content.round, signee, weight);
+                if !m.add_part(signee, contract.content, &self.limits) {
+                    self.dropped += 1;
                 }
             }
             Broadcast::Prevote(contract) => {
-                if let Some(m) = self.messages.get_mut(&contract.content.height) {
-                    m.prevotes.push(contract)
+                let round = contract.content.round;
+                m.record_sender(round, signee, weight);
+                let conflict = m
+                    .prevotes
+                    .entry(round)
+                    .or_insert_with(VoteSet::new)
+                    .add(contract, weight);
+                if let Some((first, second)) = conflict {
+                    self.evidence.push(Evidence::Prevotes(first, second));
                 }
             }
             Broadcast::Precommit(contract) => {
-                if let Some(m) = self.messages.get_mut(&contract.content.height) {
-                    m.precommits.push(contract)
+                let round = contract.content.round;
+                m.record_sender(round, signee, weight);
+                let conflict = m
+                    .precommits
+                    .entry(round)
+                    .or_insert_with(VoteSet::new)
+                    .add(contract, weight);
+                if let Some((first, second)) = conflict {
+                    self.evidence.push(Evidence::Precommits(first, second));
                 }
             }
         };
     }
 
+    /// Holds a copy of a message for one of the heights just past those whose validators
+    /// are known, until `release` returns it. Returns false if the message is for another
+    /// height, or the overflow buffer is full.
+    pub fn hold(&mut self, broadcast: &Broadcast<B>) -> bool {
+        let first = self.height + UPDATE_DELAY;
+        let height = broadcast.height();
+        if height < first || height >= first + self.limits.overflow_heights {
+            return false;
+        }
+        let signee = broadcast.signee().hash();
+        let from_signee = self
+            .overflow
+            .iter()
+            .filter(|b| b.signee().hash() == signee)
+            .count();
+        if self.overflow.len() >= self.limits.overflow || from_signee >= self.limits.per_validator {
+            self.dropped += 1;
+            return false;
+        }
+        self.overflow_bytes += size(broadcast);
+        self.overflow.push_back(broadcast.clone());
+        true
+    }
+
+    /// Removes and returns the held messages that are now within the window, in the order
+    /// they arrived. Messages for heights that have passed are discarded.
+    pub fn release(&mut self) -> Vec<Broadcast<B>> {
+        let height = self.height;
+        let messages = &self.messages;
+        let (ready, held): (Vec<_>, Vec<_>) = std::mem::take(&mut self.overflow)
+            .into_iter()
+            .filter(|b| b.height() >= height)
+            .partition(|b| messages.contains_key(&b.height()));
+        self.overflow = held.into();
+        self.overflow_bytes = self.overflow.iter().map(size).sum();
+        ready
+    }
+
+    /// Returns the number and size of the messages currently stored.
+    pub fn stats(&self) -> LogStats {
+        LogStats {
+            messages: self.messages.iter().map(|(h, m)| (*h, m.stored)).collect(),
+            overflow: self.overflow.len(),
+            bytes: self.messages.values().map(|m| m.bytes).sum::<u64>() + self.overflow_bytes,
+            dropped: self.dropped,
+        }
+    }
+
     pub fn get_current(&self) -> &Messages<B> {
         self.messages.get(&self.height).unwrap()
     }
+
+    /// Removes and returns the evidence collected since the last call.
+    pub fn take_evidence(&mut self) -> Vec<Evidence<B>> {
+        std::mem::take(&mut self.evidence)
+    }
+}
+
+/// Returns the encoded size of a message, which is roughly the memory it takes up.
+fn size<B: Hashable + Serialize>(broadcast: &Broadcast<B>) -> u64 {
+    options().serialized_size(broadcast).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        consensus::{
+            parts::propose,
+            simulation::{Simulation, CHAIN_ID},
+            PART_SIZE,
+        },
+        crypto::contracts::PrivateKey,
+    };
+
+    #[test]
+    fn one_vote_per_validator() {
+        let key = PrivateKey::generate();
+        let id = Some(Hash::<u64>::empty());
+        let mut votes = VoteSet::new();
+
+        assert!(votes
+            .add(key.sign(CHAIN_ID, Prevote::new(0, 0, id)), 3)
+            .is_none());
+        // the same vote, signed again
+        assert!(votes
+            .add(key.sign(CHAIN_ID, Prevote::new(0, 0, id)), 3)
+            .is_none());
+        // a conflicting vote
+        assert!(votes
+            .add(key.sign(CHAIN_ID, Prevote::new(0, 0, None)), 3)
+            .is_some());
+
+        assert_eq!(votes.weight(id), 3);
+        assert_eq!(votes.weight(None), 0);
+        assert_eq!(votes.total(), 3);
+    }
+
+    #[test]
+    fn tallies_per_round() {
+        let keys: Vec<PrivateKey> = (0..3).map(|_| PrivateKey::generate()).collect();
+        let id = Some(Hash::<u64>::empty());
+        let mut log = MessageLog::<u64>::new(BufferLimits::default());
+
+        log.add(
+            Broadcast::Prevote(keys[0].sign(CHAIN_ID, Prevote::new(0, 0, id))),
+            1,
+        );
+        log.add(
+            Broadcast::Prevote(keys[1].sign(CHAIN_ID, Prevote::new(0, 0, None))),
+            2,
+        );
+        log.add(
+            Broadcast::Prevote(keys[2].sign(CHAIN_ID, Prevote::new(0, 1, id))),
+            4,
+        );
+        log.add(
+            Broadcast::Precommit(keys[0].sign(CHAIN_ID, Precommit::new(0, 1, id))),
+            1,
+        );
+
+        let messages = log.get_current();
+        assert_eq!(messages.prevotes(0, id), 1);
+        assert_eq!(messages.prevotes(0, None), 2);
+        assert_eq!(messages.all_prevotes(0), 3);
+        assert_eq!(messages.prevotes(1, id), 4);
+        assert_eq!(messages.all_precommits(1), 1);
+        assert_eq!(messages.precommits(2, id), 0);
+        assert_eq!(messages.tallies()[&0].prevotes[&None], 2);
+        assert!(messages.tallies()[&1].precommits.contains_key(&id));
+
+        // validators 0 and 2 sent messages in round 1
+        assert_eq!(messages.next_round_above(0, 4), Some(1));
+        assert_eq!(messages.next_round_above(0, 5), None);
+        assert_eq!(messages.next_round_above(1, 0), None);
+    }
+
+    #[test]
+    fn collects_evidence() {
+        let key = PrivateKey::generate();
+        let id = Some(Hash::<u64>::empty());
+        let mut log = MessageLog::<u64>::new(BufferLimits::default());
+
+        log.add(
+            Broadcast::Prevote(key.sign(CHAIN_ID, Prevote::new(1, 0, id))),
+            1,
+        );
+        log.add(
+            Broadcast::Prevote(key.sign(CHAIN_ID, Prevote::new(1, 0, id))),
+            1,
+        );
+        log.add(
+            Broadcast::Precommit(key.sign(CHAIN_ID, Precommit::new(1, 0, id))),
+            1,
+        );
+        assert!(log.take_evidence().is_empty());
+
+        log.add(
+            Broadcast::Precommit(key.sign(CHAIN_ID, Precommit::new(1, 0, None))),
+            1,
+        );
+        let evidence = log.take_evidence();
+        assert_eq!(evidence.len(), 1);
+        assert!(matches!(evidence[0], Evidence::Precommits(_, _)));
+        assert!(evidence[0].verify(CHAIN_ID));
+        assert!(log.take_evidence().is_empty());
+
+        // further conflicting votes in the same round add nothing
+        let other = Some(7.hash().cast());
+        log.add(
+            Broadcast::Precommit(key.sign(CHAIN_ID, Precommit::new(1, 0, other))),
+            1,
+        );
+        assert!(log.take_evidence().is_empty());
+        log.add(
+            Broadcast::Precommit(key.sign(CHAIN_ID, Precommit::new(1, 1, other))),
+            1,
+        );
+        log.add(
+            Broadcast::Precommit(key.sign(CHAIN_ID, Precommit::new(1, 1, None))),
+            1,
+        );
+        assert_eq!(log.take_evidence().len(), 1);
+    }
+
+    #[test]
+    fn waits_for_block_parts() {
+        let app = &Simulation::new(1, 0).apps()[0];
+        let block: Vec<u8> = vec![1; 2 * PART_SIZE];
+        let (proposal, parts) = propose(app, 0, 0, &block, None);
+        let mut log = MessageLog::<Vec<u8>>::new(BufferLimits::default());
+
+        // parts may arrive before the proposal
+        log.add(Broadcast::Part(parts[0].clone()), 1);
+        log.add(Broadcast::Proposal(proposal), 1);
+        assert!(log.get_current().proposals.is_empty());
+
+        for part in parts.into_iter().skip(1) {
+            log.add(Broadcast::Part(part), 1);
+        }
+        let proposals = &log.get_current().proposals;
+        assert_eq!(proposals.len(), 1);
+        assert_eq!(proposals[0].block, block);
+    }
+
+    #[test]
+    fn caps_later_heights() {
+        let keys: Vec<PrivateKey> = (0..3).map(|_| PrivateKey::generate()).collect();
+        let limits = BufferLimits {
+            per_height: 5,
+            per_validator: 3,
+            ..BufferLimits::default()
+        };
+        let mut log = MessageLog::<u64>::new(limits);
+
+        for key in keys.iter() {
+            for round in 0..4 {
+                log.add(
+                    Broadcast::Prevote(key.sign(CHAIN_ID, Prevote::new(0, round, None))),
+                    1,
+                );
+                log.add(
+                    Broadcast::Prevote(key.sign(CHAIN_ID, Prevote::new(1, round, None))),
+                    1,
+                );
+            }
+        }
+        let stats = log.stats();
+        // the current height is only capped per validator
+        assert_eq!(stats.messages[&0], 9);
+        assert_eq!(stats.messages[&1], 5);
+        assert_eq!(stats.dropped, 10);
+        assert!(stats.bytes > 0);
+        // one validator can't use up a height's allowance on its own
+        assert_eq!(log.messages[&1].received.values().max(), Some(&3));
+    }
+
+    #[test]
+    fn admits_the_current_round_of_a_stalled_height() {
+        let key = PrivateKey::generate();
+        let limits = BufferLimits {
+            per_validator: 3,
+            ..BufferLimits::default()
+        };
+        let mut log = MessageLog::<u64>::new(limits);
+        let prevote = |round| Broadcast::Prevote(key.sign(CHAIN_ID, Prevote::new(0, round, None)));
+        let precommit =
+            |round| Broadcast::Precommit(key.sign(CHAIN_ID, Precommit::new(0, round, None)));
+
+        // the validator's allowance is used up in the rounds that didn't decide
+        for round in 0..10 {
+            log.add(prevote(round), 1);
+        }
+        assert_eq!(log.stats().dropped, 7);
+
+        log.set_round(10);
+        for round in 10..13 {
+            log.add(prevote(round), 1);
+            log.add(precommit(round), 1);
+        }
+        let messages = log.get_current();
+        assert_eq!(messages.all_prevotes(10), 1);
+        assert_eq!(messages.all_precommits(10), 1);
+        assert_eq!(messages.all_precommits(11), 1);
+        // rounds further ahead are still capped
+        assert_eq!(messages.all_prevotes(12), 0);
+        assert_eq!(log.stats().dropped, 9);
+
+        // a new height starts from round 0 again, so round 11 is capped there
+        log.set_height(1);
+        for round in 2..5 {
+            log.add(
+                Broadcast::Prevote(key.sign(CHAIN_ID, Prevote::new(1, round, None))),
+                1,
+            );
+        }
+        log.add(
+            Broadcast::Prevote(key.sign(CHAIN_ID, Prevote::new(1, 11, None))),
+            1,
+        );
+        assert_eq!(log.stats().messages[&1], 3);
+    }
+
+    #[test]
+    fn forgets_the_lowest_proposed_round() {
+        let app = &Simulation::new(1, 0).apps()[0];
+        let limits = BufferLimits {
+            proposed_rounds: 2,
+            ..BufferLimits::default()
+        };
+        let mut log = MessageLog::<u64>::new(limits);
+        let mut send = |round: u64| {
+            let (proposal, parts) = propose(app, 0, round, &round, None);
+            log.add(Broadcast::Proposal(proposal), 1);
+            for part in parts {
+                log.add(Broadcast::Part(part), 1);
+            }
+        };
+
+        send(1);
+        send(2);
+        // a round below every kept round is dropped, along with its part
+        send(0);
+        // a later round replaces the lowest
+        send(3);
+        let mut rounds: Vec<u64> = log
+            .get_current()
+            .proposals
+            .iter()
+            .map(|p| p.contract.content.round)
+            .collect();
+        rounds.sort_unstable();
+        assert_eq!(rounds, vec![2, 3]);
+        assert_eq!(log.stats().dropped, 2);
+    }
+
+    #[test]
+    fn holds_messages_past_the_window() {
+        let key = PrivateKey::generate();
+        let limits = BufferLimits {
+            overflow: 2,
+            overflow_heights: 1,
+            ..BufferLimits::default()
+        };
+        let mut log = MessageLog::<u64>::new(limits);
+        let vote = |height| Broadcast::Prevote(key.sign(CHAIN_ID, Prevote::new(height, 0, None)));
+
+        assert!(!log.hold(&vote(1)));
+        assert!(!log.hold(&vote(UPDATE_DELAY + 1)));
+        assert!(log.hold(&vote(UPDATE_DELAY)));
+        assert!(log.hold(&vote(UPDATE_DELAY)));
+        assert!(!log.hold(&vote(UPDATE_DELAY)));
+        assert_eq!(log.stats().overflow, 2);
+        assert_eq!(log.stats().dropped, 1);
+
+        assert!(log.release().is_empty());
+        log.set_height(1);
+        let released = log.release();
+        assert_eq!(released.len(), 2);
+        assert!(released.iter().all(|b| log.accepts(b.height())));
+        assert_eq!(log.stats().overflow, 0);
+    }
 }
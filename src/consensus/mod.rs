@@ -2,37 +2,105 @@ use crate::crypto::{
     contracts::PublicKey,
     hashing::{Hash, Hashable},
 };
-use tokio::{
-    sync::mpsc::{Receiver, Sender},
-    time::Duration,
-};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{collections::HashMap, sync::Mutex};
+use tokio::sync::mpsc::{Receiver, Sender};
 mod app;
+#[cfg(test)]
+mod byzantine;
+mod certificate;
+mod codec;
+mod config;
 mod events;
+mod evidence;
+mod gossip;
+mod handle;
 mod log;
+mod parts;
+mod progress;
+mod proposer;
+#[cfg(test)]
+mod simulation;
+mod sync;
+mod time;
 mod timeout;
 mod types;
+mod validation;
+mod validators;
+mod wal;
 
 pub use app::*;
+pub use certificate::*;
+pub use codec::*;
+pub use config::*;
+pub use evidence::*;
+pub use gossip::gossip;
+pub use handle::{Controls, Handle, Snapshot, Stopped, Tally};
+pub use log::LogStats;
 use log::*;
+pub use parts::{BlockPart, PartsHeader, MAX_PARTS, PART_SIZE};
+pub use progress::*;
+pub use proposer::*;
+pub use sync::*;
 pub use types::*;
+pub use validation::*;
+pub use validators::*;
+pub use wal::Wal;
 
+use handle::Command;
+use time::Previous;
 use timeout::TimeoutManager;
+pub use timeout::{Clock, TokioClock};
+use wal::Checkpoint;
 
-struct Record<B> {
-    value: B,
-    round: u64,
+/// A block, and the round in which it was locked or found to be valid.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Record<B> {
+    pub value: B,
+    pub round: u64,
+}
+
+/// The channels that connect a node to its peers, to monitors, and to its `Handle`.
+pub struct Channels<B: Hashable> {
+    /// Consensus messages from peers.
+    pub incoming: Receiver<Broadcast<B>>,
+    /// The node's own consensus messages, for peers. The node counts them itself, so
+    /// they shouldn't be passed back on `incoming`.
+    pub outgoing: Sender<Broadcast<B>>,
+    /// Messages from peers that failed validation.
+    pub rejected: Sender<Rejected<B>>,
+    pub sync_incoming: Receiver<SyncMessage<B>>,
+    pub sync_outgoing: Sender<SyncMessage<B>>,
+    pub events: Events<B>,
+    pub controls: Controls<B>,
 }
 
 enum Timeouts {
     Propose { height: u64, round: u64 },
     Prevote { height: u64, round: u64 },
     Precommit { height: u64, round: u64 },
+    Commit { height: u64 },
+    Sync { height: u64 },
 }
 
+impl Timeouts {
+    fn height(&self) -> u64 {
+        match self {
+            Timeouts::Propose { height, .. }
+            | Timeouts::Prevote { height, .. }
+            | Timeouts::Precommit { height, .. }
+            | Timeouts::Commit { height }
+            | Timeouts::Sync { height } => *height,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 struct RoundState {
     round: u64,
     step: Step,
     precommit_timeout_scheduled: bool,
+    valid_updated: bool,
 }
 
 impl RoundState {
@@ -41,12 +109,14 @@ impl RoundState {
             round,
             step: Step::Propose,
             precommit_timeout_scheduled: false,
+            valid_updated: false,
         }
     }
 }
 
 pub struct Tendermint<A: App<B>, B: Hashable + Clone> {
     app: A,
+    config: ConsensusConfig,
     height: u64,
     current: RoundState,
     locked: Option<Record<B>>,
@@ -54,65 +124,157 @@ pub struct Tendermint<A: App<B>, B: Hashable + Clone> {
     log: MessageLog<B>,
     incoming: Receiver<Broadcast<B>>,
     outgoing: Sender<Broadcast<B>>,
+    rejected: Sender<Rejected<B>>,
+    sync_incoming: Receiver<SyncMessage<B>>,
+    sync_outgoing: Sender<SyncMessage<B>>,
+    /// The highest height of any validly signed message received.
+    peer_height: u64,
+    sync_scheduled: bool,
     timeouts: TimeoutManager<Timeouts>,
+    wal: Wal<B>,
+    events: Events<B>,
+    validators: ValidatorSets,
+    /// The last block committed, which blocks at this height must include the certificate for.
+    previous: Option<Previous<B>>,
+    /// The proposers for the rounds looked up so far, by height. Locked only to
+    /// look up a proposer.
+    proposers: Mutex<HashMap<u64, Rounds>>,
+    controls: Controls<B>,
+    /// False once every `Handle` has been dropped.
+    controlled: bool,
+    paused: bool,
 }
 
-impl<A: App<B>, B: Hashable + Clone + Eq> Tendermint<A, B> {
+impl<A: App<B>, B: Hashable + Clone + Eq + Serialize + DeserializeOwned> Tendermint<A, B> {
+    /// Runs consensus. Messages that fail validation are reported on `rejected`,
+    /// so that the network layer can penalise the peer that sent them.
+    /// If the node was interrupted, it resumes from the state recorded in `wal`.
+    /// Blocks that the node missed are fetched from peers over the sync channels.
+    /// Progress is reported to subscribers of `events`, and the node can be inspected
+    /// and stopped through the `Handle` that `controls` came from.
     pub async fn start(
         app: A,
-        incoming: Receiver<Broadcast<B>>,
-        outgoing: Sender<Broadcast<B>>,
+        config: ConsensusConfig,
+        wal: Wal<B>,
+        channels: Channels<B>,
     ) -> Result<(), Error> {
-        Tendermint {
+        Self::new(app, config, wal, channels)?.run().await
+    }
+
+    fn new(
+        app: A,
+        config: ConsensusConfig,
+        wal: Wal<B>,
+        channels: Channels<B>,
+    ) -> Result<Self, Error> {
+        let Channels {
+            incoming,
+            outgoing,
+            rejected,
+            sync_incoming,
+            sync_outgoing,
+            events,
+            controls,
+        } = channels;
+        let mut validators = ValidatorSets::new();
+        validators.load(app.height(), |height| app.validators(height))?;
+        let previous = Previous::load(&app, app.height());
+        Ok(Tendermint {
             current: RoundState::new(0),
             app,
             height: 0,
             locked: None,
             valid: None,
-            log: MessageLog::new(),
+            log: MessageLog::new(config.buffer),
             incoming,
             outgoing,
-            timeouts: TimeoutManager::new(),
-        }
-        .run()
-        .await
+            rejected,
+            sync_incoming,
+            sync_outgoing,
+            peer_height: 0,
+            sync_scheduled: false,
+            timeouts: TimeoutManager::new(config.clock.clone()),
+            config,
+            wal,
+            events,
+            validators,
+            previous,
+            proposers: Mutex::new(HashMap::new()),
+            controls,
+            controlled: true,
+            paused: false,
+        })
     }
 
     async fn start_round(&mut self, round: u64) -> Result<(), Error> {
         self.current = RoundState::new(round);
-        if self.app.proposer(self.current.round) == self.app.id() {
-            let proposal = match self.valid.as_ref() {
-                Some(record) => Proposal {
-                    height: self.height,
-                    round: self.current.round,
-                    proposal: record.value.clone(),
-                    valid_round: Some(record.round),
-                },
-                None => Proposal {
-                    height: self.height,
-                    round: self.current.round,
-                    proposal: self.app.create_block(),
-                    valid_round: None,
-                },
+        self.log.set_round(round);
+        self.notify(EventKind::NewRound, None);
+        if self.proposer(self.current.round) == self.app.id() {
+            let (block, valid_round) = match self.wal.proposed(round) {
+                // proposed before a restart, and possibly partly sent
+                Some((block, valid_round)) => (block.clone(), valid_round),
+                None => {
+                    let (block, valid_round) = match self.valid.as_ref() {
+                        Some(record) => (record.value.clone(), Some(record.round)),
+                        None => {
+                            let last_commit = self.previous.as_ref().map(|p| p.certificate.clone());
+                            (self.app.create_block(last_commit), None)
+                        }
+                    };
+                    self.wal
+                        .propose(round, block.clone(), valid_round)
+                        .map_err(Error::WalFailed)?;
+                    (block, valid_round)
+                }
             };
-            self.broadcast(Broadcast::Proposal(self.app.sign(proposal)))
-                .await
+            let (proposal, parts) = parts::propose(
+                &self.app,
+                self.height,
+                self.current.round,
+                &block,
+                valid_round,
+            );
+            self.broadcast(Broadcast::Proposal(proposal)).await?;
+            for part in parts {
+                self.broadcast(Broadcast::Part(part)).await?;
+            }
+            Ok(())
         } else {
             self.timeouts.add(
                 Timeouts::Propose {
                     height: self.height,
                     round: self.current.round,
                 },
-                Duration::from_millis(1000),
+                self.config.propose_timeout(self.current.round),
             );
             Ok(())
         }
     }
 
-    async fn new_height(&mut self, height: u64, block: Option<B>) -> Result<(), Error> {
-        if let Some(b) = block {
+    /// Moves on to the given height, first committing the decision for the current one,
+    /// if there is one: the block, the certificate for it, and its time.
+    async fn new_height(
+        &mut self,
+        height: u64,
+        decision: Option<(B, CommitCertificate<B>, u128)>,
+    ) -> Result<(), Error> {
+        let committed = decision.is_some();
+        if let Some((b, certificate, time)) = decision {
+            let round = certificate.round;
+            self.events
+                .emit(EventKind::Committed, self.height, round, Some(b.hash()));
+            let validators = self.validators.get(self.height).cloned();
             // decision_p[h_p] = v
-            self.app.commit(b)
+            let updates = self
+                .app
+                .commit(b, certificate.clone(), time)
+                .map_err(Error::CommitFailed)?;
+            self.validators.commit(self.height, &updates)?;
+            self.previous = validators.map(|validators| Previous {
+                certificate,
+                validators,
+            });
         }
 
         self.height = height;
@@ -120,94 +282,322 @@ impl<A: App<B>, B: Hashable + Clone + Eq> Tendermint<A, B> {
         // resets locked, valid, and empties message log.
         self.locked = None;
         self.valid = None;
-        self.log.increment_height();
+        self.log.set_height(height);
+        self.release();
+        self.proposers
+            .get_mut()
+            .unwrap()
+            .retain(|h, _| *h >= height);
+        self.timeouts.cancel(|timeout| timeout.height() < height);
+        self.sync_scheduled = false;
+        self.current = RoundState::new(0);
+        self.notify(EventKind::NewHeight, None);
+        self.wal
+            .start_height(self.checkpoint())
+            .map_err(Error::WalFailed)?;
 
-        // StartRound(0)
-        self.start_round(0).await
+        if committed && !self.config.commit_delay.is_zero() {
+            self.timeouts
+                .add(Timeouts::Commit { height }, self.config.commit_delay);
+            Ok(())
+        } else {
+            // StartRound(0)
+            self.start_round(0).await
+        }
+    }
+
+    /// Restores the state recorded before a restart, and sends the messages
+    /// signed at this height again, in case they were never delivered.
+    async fn resume(&mut self, checkpoint: Checkpoint<B>) -> Result<(), Error> {
+        self.height = checkpoint.height;
+        self.locked = checkpoint.locked;
+        self.valid = checkpoint.valid;
+        self.log.set_height(self.height);
+        self.release();
+        self.events.emit(
+            EventKind::NewHeight,
+            self.height,
+            checkpoint.current.round,
+            None,
+        );
+
+        let signed: Vec<Broadcast<B>> = self.wal.signed().cloned().collect();
+        for b in signed {
+            let weight = self.voting_weight(b.height(), b.signee().hash());
+            self.log.add(b.clone(), weight);
+            self.send(b).await?;
+        }
+
+        // timeouts are not persisted, so allow them to be scheduled again
+        let current = checkpoint.current;
+        if current.step.is_propose() {
+            self.start_round(current.round).await
+        } else {
+            self.log.set_round(current.round);
+            self.current = RoundState {
+                step: if current.step.is_prevote() {
+                    Step::prevote()
+                } else {
+                    Step::Precommit
+                },
+                precommit_timeout_scheduled: false,
+                ..current
+            };
+            Ok(())
+        }
     }
 
     pub async fn run(&mut self) -> Result<(), Error> {
-        self.new_height(0, None).await?;
+        let height = self.app.height();
+        match self.wal.checkpoint().cloned() {
+            Some(checkpoint) if checkpoint.height == height => self.resume(checkpoint).await?,
+            // starting the height again would discard what was signed at it
+            Some(checkpoint) if checkpoint.height > height => {
+                return Err(Error::WalAhead {
+                    wal: checkpoint.height,
+                    app: height,
+                })
+            }
+            // the app has committed the checkpoint's height, so nothing more is signed at it
+            _ => self.new_height(height, None).await?,
+        }
 
         loop {
+            // before waiting, as the node's own messages may have completed a quorum
+            loop {
+                let changed = [
+                    self.line22().await?,
+                    self.line28().await?,
+                    self.line34()?,
+                    self.line36().await?,
+                    self.line44().await?,
+                    self.line47()?,
+                    self.line49().await?,
+                    self.line55().await?,
+                ];
+                if !changed.iter().any(|x| *x) {
+                    break;
+                }
+            }
+            self.wal.save(self.checkpoint()).map_err(Error::WalFailed)?;
+
+            // biased, so that runs with the same inputs are reproducible
             tokio::select! {
-                function_call = self.timeouts.get_next() => {
+                biased;
+                command = self.controls.receiver.recv(), if self.controlled => {
+                    match command {
+                        Some(command) => if self.control(command) {
+                            return Ok(());
+                        },
+                        None => {
+                            // nothing could resume the node
+                            self.controlled = false;
+                            self.paused = false;
+                        }
+                    }
+                }
+                function_call = self.timeouts.get_next(), if !self.paused => {
                     match function_call {
                         Timeouts::Propose {height, round} => self.propose_timeout(height, round).await?,
                         Timeouts::Prevote {height, round} => self.prevote_timeout(height, round).await?,
                         Timeouts::Precommit {height, round} => self.precommit_timeout(height, round).await?,
+                        Timeouts::Commit {height} => self.commit_timeout(height).await?,
+                        Timeouts::Sync {height} => self.sync_timeout(height),
                     }
                 }
-                incoming = self.incoming.recv() => {
+                incoming = self.incoming.recv(), if !self.paused => {
                     match incoming {
-                        Some(b) => self.log.add(b),
+                        Some(b) => self.receive(b),
                         None => return Err(Error::IncomingClosed),
                     };
+                }
+                Some(message) = self.sync_incoming.recv(), if !self.paused => self.sync(message).await?,
+            }
+        }
+    }
 
-                    loop {
-                        let changed = [
-                            self.line22().await?,
-                            self.line28().await?,
-                            self.line34()?,
-                            self.line36().await?,
-                            self.line44().await?,
-                            self.line47()?,
-                            self.line49().await?,
-                        ];
-                        if !changed.iter().any(|x| *x) {
-                            break
-                        }
+    /// Adds a message to the log if it is valid, and otherwise reports it.
+    fn receive(&mut self, b: Broadcast<B>) {
+        match self.validate(&b) {
+            Ok(()) => {
+                self.observe(&b);
+                let weight = self.voting_weight(b.height(), b.signee().hash());
+                self.log.add(b, weight);
+                for evidence in self.log.take_evidence() {
+                    self.app.report_evidence(evidence);
+                }
+            }
+            Err(reason) => {
+                // messages from too far ahead are a sign that this node is behind,
+                // if they come from a validator in the latest set known here
+                let known = self
+                    .validators
+                    .last()
+                    .is_some_and(|last| self.voting_weight(last, b.signee().hash()) > 0);
+                if reason == Rejection::WrongHeight && known && b.verify(self.app.chain_id()) {
+                    self.observe(&b);
+                    // those just past the window are kept until their validators are known
+                    if self.log.hold(&b) {
+                        return;
                     }
                 }
+                self.reject(b, reason)
+            }
+        }
+    }
+
+    /// Receives the held messages for heights whose validators are now known.
+    fn release(&mut self) {
+        for b in self.log.release() {
+            self.receive(b);
+        }
+    }
+
+    /// Carries out a command from a `Handle`. Returns true if the node should stop.
+    fn control(&mut self, command: Command<B>) -> bool {
+        // the handle may have given up waiting for the reply
+        match command {
+            Command::Snapshot(reply) => {
+                let _ = reply.send(self.snapshot());
+            }
+            Command::Pause(reply) => {
+                self.paused = true;
+                let _ = reply.send(());
             }
+            Command::Resume(reply) => {
+                self.paused = false;
+                let _ = reply.send(());
+            }
+            Command::Shutdown(reply) => {
+                let _ = reply.send(());
+                return true;
+            }
+        }
+        false
+    }
+
+    fn snapshot(&self) -> Snapshot<B> {
+        Snapshot {
+            height: self.height,
+            round: self.current.round,
+            step: self.current.step.clone(),
+            locked: self.locked.clone(),
+            valid: self.valid.clone(),
+            proposer: self.proposer(self.current.round),
+            total_weight: self.validators.total(self.height),
+            tallies: self.log.get_current().tallies(),
+            buffer: self.log.stats(),
+            paused: self.paused,
         }
     }
 
     async fn propose_timeout(&mut self, height: u64, round: u64) -> Result<(), Error> {
         if self.height == height && self.current.round == round && self.current.step.is_propose() {
+            self.notify(EventKind::TimeoutFired(TimeoutKind::Propose), None);
             let vote = Prevote::new(height, round, None);
             self.broadcast(Broadcast::Prevote(self.app.sign(vote)))
-                .await?
+                .await?;
+            self.current.step = Step::prevote();
         }
         Ok(())
     }
 
     async fn prevote_timeout(&mut self, height: u64, round: u64) -> Result<(), Error> {
         if height == self.height && round == self.current.round && self.current.step.is_prevote() {
+            self.notify(EventKind::TimeoutFired(TimeoutKind::Prevote), None);
             let vote = Precommit::new(height, round, None);
             self.broadcast(Broadcast::Precommit(self.app.sign(vote)))
-                .await?
+                .await?;
+            self.current.step = Step::Precommit;
         }
         Ok(())
     }
 
     async fn precommit_timeout(&mut self, height: u64, round: u64) -> Result<(), Error> {
         if height == self.height && round == self.current.round {
+            self.notify(EventKind::TimeoutFired(TimeoutKind::Precommit), None);
             self.start_round(self.current.round + 1).await?
         }
         Ok(())
     }
 
-    async fn broadcast(&self, msg: Broadcast<B>) -> Result<(), Error> {
+    async fn commit_timeout(&mut self, height: u64) -> Result<(), Error> {
+        // round 0 may already be underway, if a proposal arrived during the delay
+        if height == self.height && self.current.round == 0 && self.current.step.is_propose() {
+            self.start_round(0).await?
+        }
+        Ok(())
+    }
+
+    /// Records the current state and the message in the WAL before sending it.
+    /// If a message was already signed for the same step (before a restart),
+    /// that message is sent instead, so that the node never equivocates.
+    async fn broadcast(&mut self, msg: Broadcast<B>) -> Result<(), Error> {
+        self.wal.save(self.checkpoint()).map_err(Error::WalFailed)?;
+        let msg = self.wal.sign(msg).map_err(Error::WalFailed)?;
+        match &msg {
+            Broadcast::Proposal(c) => self.notify(EventKind::Proposed, Some(c.content.block)),
+            Broadcast::Prevote(c) => self.notify(EventKind::Prevoted, c.content.id),
+            Broadcast::Precommit(c) => self.notify(EventKind::Precommitted, c.content.id),
+            Broadcast::Part(_) => (),
+        }
+        // counted straight away, rather than relying on the network to deliver it back
+        self.receive(msg.clone());
+        self.send(msg).await
+    }
+
+    async fn send(&self, msg: Broadcast<B>) -> Result<(), Error> {
         self.outgoing
             .send(msg)
             .await
             .map_err(|_| Error::OutgoingClosed)
     }
 
-    fn voting_weight(&self, id: Hash<PublicKey>) -> u64 {
-        *self.app.validators().get(&id).unwrap_or(&0)
+    /// Emits an event for the current height and round.
+    fn notify(&self, kind: EventKind, block: Option<Hash<B>>) {
+        self.events
+            .emit(kind, self.height, self.current.round, block);
+    }
+
+    fn checkpoint(&self) -> Checkpoint<B> {
+        Checkpoint {
+            height: self.height,
+            current: self.current.clone(),
+            locked: self.locked.clone(),
+            valid: self.valid.clone(),
+        }
+    }
+
+    /// Returns the voting weight of a validator at the given height,
+    /// or zero if the validator set for that height isn't known yet.
+    fn voting_weight(&self, height: u64, id: Hash<PublicKey>) -> u64 {
+        self.validators.weight(height, &id)
+    }
+
+    /// Returns the proposer for a round of the current height.
+    fn proposer(&self, round: u64) -> Hash<PublicKey> {
+        self.proposer_at(self.height, round)
+    }
+
+    /// Returns the proposer for a round of any height, working out each round only once.
+    fn proposer_at(&self, height: u64, round: u64) -> Hash<PublicKey> {
+        let mut proposers = self.proposers.lock().unwrap();
+        proposers
+            .entry(height)
+            .or_insert_with(|| Rounds::new(self.app.proposers(height)))
+            .proposer(round)
     }
 
     /// Returns the maximum voting weight of faulty processes in the network.
     /// If votes > one_f, then at least one correct process agrees.
     fn f(&self) -> u64 {
-        self.app.total_votes() / 3
+        self.validators.total(self.height) / 3
     }
 
-    /// Returns twice the maximum voting weight of adversaries in the network.
-    /// If votes > two_f, then the majority of correct processes agree.
+    /// Returns the most weight that isn't a quorum: two thirds of the total, rounded down.
+    /// If votes > two_f, then more than two thirds agree, including a majority of the
+    /// correct processes. This differs from `2 * f` when the total isn't a multiple of 3.
     fn two_f(&self) -> u64 {
-        self.f() * 2
+        (self.validators.total(self.height) as u128 * 2 / 3) as u64
     }
 }
@@ -0,0 +1,206 @@
+use bincode::Options;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::crypto::{
+    contracts::{Contract, Signable},
+    hashing::{Hash, Hashable, MerkleTree},
+};
+
+use super::{codec::options, App, Proposal};
+
+/// The size of every block part except the last.
+pub const PART_SIZE: usize = 64 * 1024;
+
+/// The most parts a block can be split into. Proposals for larger blocks are ignored.
+pub const MAX_PARTS: u64 = 256;
+
+/// Identifies the parts of a block: how many there are, and the root of the
+/// Merkle tree with the parts as its leaves.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct PartsHeader {
+    pub total: u64,
+    /// The hash of the `MerkleTree`.
+    pub root: Hash,
+}
+
+impl Hashable for PartsHeader {
+    fn hash(&self) -> Hash<Self> {
+        hash![self.total, self.root]
+    }
+}
+
+/// A piece of an encoded block, with proof that it belongs to the block in a proposal.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct BlockPart {
+    pub height: u64,
+    pub round: u64,
+    pub header: PartsHeader,
+    pub index: u64,
+    pub bytes: Vec<u8>,
+    pub proof: Vec<Hash>,
+}
+
+impl BlockPart {
+    /// Checks that the part belongs to the block described by its header.
+    pub fn verify(&self) -> bool {
+        self.header.total <= MAX_PARTS
+            && self.index < self.header.total
+            && self.bytes.len() <= PART_SIZE
+            && MerkleTree::verify_proof(
+                self.index as usize,
+                self.header.total as usize,
+                self.bytes.clone(),
+                self.header.root.cast(),
+                &self.proof,
+            )
+    }
+}
+
+impl Hashable for BlockPart {
+    fn hash(&self) -> Hash<Self> {
+        let mut proof = Vec::new();
+        for hash in self.proof.iter() {
+            proof.extend_from_slice(hash.get_bytes());
+        }
+        hash![
+            self.height,
+            self.round,
+            self.header,
+            self.index,
+            self.bytes,
+            proof
+        ]
+    }
+}
+
+impl Signable for BlockPart {
+    const DOMAIN: &'static str = "blockkey/consensus/block-part";
+}
+
+/// Encodes a block and splits it into parts, returning the proposal and parts signed by `app`.
+pub(super) fn propose<A: App<C>, B: Hashable + Serialize, C: Hashable>(
+    app: &A,
+    height: u64,
+    round: u64,
+    block: &B,
+    valid_round: Option<u64>,
+) -> (Contract<Proposal<B>>, Vec<Contract<BlockPart>>) {
+    // blocks too large to encode can't be sent, so are left for the round to time out
+    let bytes = options().serialize(block).unwrap_or_default();
+    let chunks: Vec<Vec<u8>> = bytes.chunks(PART_SIZE).map(<[u8]>::to_vec).collect();
+    let tree = MerkleTree::new(&chunks);
+    let header = PartsHeader {
+        total: chunks.len() as u64,
+        root: tree.hash().cast(),
+    };
+    let proposal = app.sign(Proposal {
+        height,
+        round,
+        block: block.hash(),
+        parts: header,
+        valid_round,
+    });
+    let parts = chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, bytes)| {
+            app.sign(BlockPart {
+                height,
+                round,
+                header,
+                index: index as u64,
+                bytes,
+                proof: tree.construct_proof(index),
+            })
+        })
+        .collect();
+    (proposal, parts)
+}
+
+/// The parts of a block received so far.
+pub struct PartSet {
+    header: PartsHeader,
+    parts: Vec<Option<Vec<u8>>>,
+    received: u64,
+}
+
+impl PartSet {
+    pub fn new(header: PartsHeader) -> PartSet {
+        PartSet {
+            header,
+            parts: vec![None; header.total.min(MAX_PARTS) as usize],
+            received: 0,
+        }
+    }
+
+    pub fn header(&self) -> PartsHeader {
+        self.header
+    }
+
+    /// Adds a verified part, if it belongs to this set.
+    pub fn add(&mut self, part: BlockPart) {
+        if part.header != self.header {
+            return;
+        }
+        if let Some(slot @ None) = self.parts.get_mut(part.index as usize) {
+            *slot = Some(part.bytes);
+            self.received += 1;
+        }
+    }
+
+    /// Reassembles the block once every part has arrived, or returns None
+    /// if parts are missing or they don't decode to a block.
+    pub fn assemble<B: DeserializeOwned>(&self) -> Option<B> {
+        if self.received != self.header.total {
+            return None;
+        }
+        let bytes: Vec<u8> = self.parts.iter().flatten().flatten().copied().collect();
+        options().deserialize(&bytes).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::simulation::Simulation;
+
+    #[test]
+    fn reassembles_verified_parts() {
+        let app = &Simulation::new(1, 0).apps()[0];
+        let block: Vec<u8> = (0..3 * PART_SIZE + 10).map(|i| i as u8).collect();
+        let (proposal, parts) = propose(app, 0, 0, &block, None);
+        assert_eq!(proposal.content.parts.total, 4);
+        assert_eq!(proposal.content.block, block.hash());
+
+        let mut set = PartSet::new(proposal.content.parts);
+        // in any order
+        for part in parts.iter().rev() {
+            assert!(part.content.verify());
+            assert_eq!(set.assemble::<Vec<u8>>(), None);
+            set.add(part.content.clone());
+        }
+        assert_eq!(set.assemble::<Vec<u8>>(), Some(block.clone()));
+        // duplicates change nothing
+        set.add(parts[0].content.clone());
+        assert_eq!(set.assemble::<Vec<u8>>(), Some(block));
+    }
+
+    #[test]
+    fn rejects_tampered_parts() {
+        let app = &Simulation::new(1, 0).apps()[0];
+        let block: Vec<u8> = vec![7; 2 * PART_SIZE];
+        let (_, parts) = propose(app, 0, 0, &block, None);
+
+        let mut part = parts[1].content.clone();
+        part.bytes[0] = 0;
+        assert!(!part.verify());
+
+        let mut part = parts[1].content.clone();
+        part.index = 0;
+        assert!(!part.verify());
+
+        let mut part = parts[1].content.clone();
+        part.header.total = MAX_PARTS + 1;
+        assert!(!part.verify());
+    }
+}
@@ -0,0 +1,69 @@
+use tokio::sync::broadcast;
+
+use crate::crypto::hashing::Hash;
+
+/// Something that consensus did, for monitoring and tests.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Event<B> {
+    pub kind: EventKind,
+    pub height: u64,
+    pub round: u64,
+    /// The block the event concerns, or None for nil votes and events without a block.
+    pub block: Option<Hash<B>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EventKind {
+    NewHeight,
+    NewRound,
+    /// This node broadcast a proposal.
+    Proposed,
+    /// This node broadcast a prevote.
+    Prevoted,
+    /// This node broadcast a precommit.
+    Precommitted,
+    Locked,
+    ValidUpdated,
+    TimeoutFired(TimeoutKind),
+    /// The node jumped to a later round, after hearing from f+1 validators in it.
+    RoundSkipped,
+    /// A block was decided and passed to `App::commit`.
+    Committed,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TimeoutKind {
+    Propose,
+    Prevote,
+    Precommit,
+}
+
+/// The stream of events from a node. Clones share the same stream.
+#[derive(Clone)]
+pub struct Events<B> {
+    sender: broadcast::Sender<Event<B>>,
+}
+
+impl<B: Clone> Events<B> {
+    /// Creates a stream that buffers up to `capacity` events for each subscriber.
+    pub fn new(capacity: usize) -> Events<B> {
+        let (sender, _) = broadcast::channel(capacity);
+        Events { sender }
+    }
+
+    /// Returns a receiver for every event emitted after this call. Emitting never waits
+    /// for subscribers, so one that falls too far behind misses the oldest events.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event<B>> {
+        self.sender.subscribe()
+    }
+
+    pub(super) fn emit(&self, kind: EventKind, height: u64, round: u64, block: Option<Hash<B>>) {
+        // fails only if there are no subscribers
+        let _ = self.sender.send(Event {
+            kind,
+            height,
+            round,
+            block,
+        });
+    }
+}
@@ -0,0 +1,288 @@
+use crate::crypto::{contracts::PublicKey, hashing::Hash};
+
+use super::{total_weight, InvalidValidators, Validators};
+
+/// Priorities are scaled down whenever the gap between the highest and lowest
+/// exceeds this multiple of the total weight, so that they stay bounded.
+const PRIORITY_WINDOW: i64 = 2;
+
+/// The largest total weight that can be used without priorities overflowing.
+pub const MAX_TOTAL_WEIGHT: u64 = i64::MAX as u64 / 8;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+struct Candidate {
+    id: Hash<PublicKey>,
+    weight: i64,
+    priority: i64,
+}
+
+/// Chooses proposers by stake-weighted round robin, as Tendermint does.
+///
+/// Every round, each validator's priority grows by its weight, and the validator
+/// with the highest priority proposes and pays the total weight back. Over time,
+/// each validator proposes in proportion to its weight. Ties go to the smallest
+/// key, so every node that replays the same sets picks the same proposers.
+///
+/// A selector describes the start of a height: the proposer for round r is
+/// `proposer(r)`. To move to the next height, call `advance` once and then
+/// `update` with the validators for that height.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ProposerSelector {
+    /// Sorted by key.
+    candidates: Vec<Candidate>,
+    total: i64,
+}
+
+impl ProposerSelector {
+    /// Creates a selector with every validator at the same priority.
+    /// Fails if the total weight is too large to keep priorities from overflowing,
+    /// or there are no validators to choose from.
+    pub fn new(validators: &Validators) -> Result<ProposerSelector, InvalidValidators> {
+        let mut selector = ProposerSelector {
+            candidates: Vec::new(),
+            total: 0,
+        };
+        selector.update(validators)?;
+        Ok(selector)
+    }
+
+    /// Moves to a new validator set. Validators that remain keep their priority,
+    /// while new ones start behind everyone else, so that leaving and rejoining
+    /// can't be used to propose sooner.
+    ///
+    /// Fails, leaving the selector unchanged, if the total weight is too large to
+    /// keep priorities from overflowing, or there are no validators to choose from.
+    pub fn update(&mut self, validators: &Validators) -> Result<(), InvalidValidators> {
+        // no more than MAX_TOTAL_WEIGHT, so every weight fits in an i64
+        let total = total_weight(validators)? as i64;
+
+        let previous = std::mem::take(&mut self.candidates);
+        let mut candidates: Vec<Candidate> = validators
+            .iter()
+            .filter(|(_, weight)| **weight > 0)
+            .map(|(id, weight)| Candidate {
+                id: *id,
+                weight: *weight as i64,
+                priority: previous
+                    .iter()
+                    .find(|c| c.id == *id)
+                    .map_or(-(total + total / 8), |c| c.priority),
+            })
+            .collect();
+        candidates.sort_by(|a, b| a.id.get_bytes().cmp(b.id.get_bytes()));
+
+        self.candidates = candidates;
+        self.total = total;
+        self.rescale();
+        self.center();
+        Ok(())
+    }
+
+    /// Advances by one round, and returns the proposer for that round.
+    pub fn advance(&mut self) -> Hash<PublicKey> {
+        self.rescale();
+        self.center();
+        for candidate in self.candidates.iter_mut() {
+            candidate.priority += candidate.weight;
+        }
+        // the first of equal priorities has the smallest key
+        let chosen = self
+            .candidates
+            .iter_mut()
+            .rev()
+            .max_by_key(|c| c.priority)
+            .expect("selectors are only made for sets with a validator");
+        chosen.priority -= self.total;
+        chosen.id
+    }
+
+    /// Returns the proposer for the given round, without advancing. This takes a
+    /// step for every round before it, so `Rounds` should be used to look up many.
+    pub fn proposer(&self, round: u64) -> Hash<PublicKey> {
+        let mut selector = self.clone();
+        for _ in 0..round {
+            selector.advance();
+        }
+        selector.advance()
+    }
+
+    /// Divides every priority by the same amount, if they have spread too far apart.
+    fn rescale(&mut self) {
+        let priorities = self.candidates.iter().map(|c| c.priority);
+        let (min, max) = match (priorities.clone().min(), priorities.max()) {
+            (Some(min), Some(max)) => (min, max),
+            _ => return,
+        };
+        let window = PRIORITY_WINDOW * self.total;
+        let spread = max - min;
+        if window > 0 && spread > window {
+            let ratio = (spread + window - 1) / window;
+            for candidate in self.candidates.iter_mut() {
+                candidate.priority /= ratio;
+            }
+        }
+    }
+
+    /// Shifts priorities so that they average zero.
+    fn center(&mut self) {
+        if self.candidates.is_empty() {
+            return;
+        }
+        let sum: i128 = self.candidates.iter().map(|c| c.priority as i128).sum();
+        let average = sum.div_euclid(self.candidates.len() as i128) as i64;
+        for candidate in self.candidates.iter_mut() {
+            candidate.priority -= average;
+        }
+    }
+}
+
+/// Remembers the proposers for the rounds of a height, so that each round is
+/// only worked out once.
+#[derive(Clone, Debug)]
+pub struct Rounds {
+    /// Advanced past every round in `proposers`.
+    selector: ProposerSelector,
+    proposers: Vec<Hash<PublicKey>>,
+}
+
+impl Rounds {
+    /// Takes the selector at the start of a height.
+    pub fn new(selector: ProposerSelector) -> Rounds {
+        Rounds {
+            selector,
+            proposers: Vec::new(),
+        }
+    }
+
+    /// Returns the proposer for the given round, advancing only past the rounds
+    /// that haven't been looked up before.
+    pub fn proposer(&mut self, round: u64) -> Hash<PublicKey> {
+        let round = round as usize;
+        while self.proposers.len() <= round {
+            self.proposers.push(self.selector.advance());
+        }
+        self.proposers[round]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::crypto::{contracts::PrivateKey, hashing::Hashable};
+
+    fn keys(n: usize) -> Vec<Hash<PublicKey>> {
+        (0..n)
+            .map(|_| PrivateKey::generate().get_public().hash())
+            .collect()
+    }
+
+    fn counts(selector: &mut ProposerSelector, rounds: u64) -> HashMap<Hash<PublicKey>, u64> {
+        let mut counts = HashMap::new();
+        for _ in 0..rounds {
+            *counts.entry(selector.advance()).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn frequency_matches_stake() {
+        let keys = keys(4);
+        let validators: Validators = keys.iter().cloned().zip(vec![1, 2, 3, 4]).collect();
+        let mut selector = ProposerSelector::new(&validators).unwrap();
+        let counts = counts(&mut selector, 1000);
+        for (key, weight) in validators.iter() {
+            assert_eq!(counts[key], weight * 100);
+        }
+    }
+
+    #[test]
+    fn equal_stakes_take_turns() {
+        let keys = keys(5);
+        let validators: Validators = keys.iter().map(|key| (*key, 7)).collect();
+        let mut selector = ProposerSelector::new(&validators).unwrap();
+        for _ in 0..10 {
+            let counts = counts(&mut selector, 5);
+            assert_eq!(counts.len(), 5);
+        }
+    }
+
+    #[test]
+    fn independent_of_insertion_order() {
+        let keys = keys(6);
+        let weighted: Vec<_> = keys.iter().cloned().zip(1..).collect();
+        let forwards: Validators = weighted.iter().cloned().collect();
+        let backwards: Validators = weighted.iter().rev().cloned().collect();
+        let mut a = ProposerSelector::new(&forwards).unwrap();
+        let mut b = ProposerSelector::new(&backwards).unwrap();
+        for _ in 0..100 {
+            assert_eq!(a.advance(), b.advance());
+        }
+    }
+
+    #[test]
+    fn proposer_does_not_advance() {
+        let validators: Validators = keys(3).into_iter().zip(vec![5, 1, 1]).collect();
+        let mut selector = ProposerSelector::new(&validators).unwrap();
+        let expected: Vec<_> = (0..10).map(|round| selector.proposer(round)).collect();
+        let mut rounds = Rounds::new(selector.clone());
+        let cached: Vec<_> = [9, 3, 0, 7].iter().map(|r| rounds.proposer(*r)).collect();
+        let actual: Vec<_> = (0..10).map(|_| selector.advance()).collect();
+        assert_eq!(expected, actual);
+        assert_eq!(cached, vec![actual[9], actual[3], actual[0], actual[7]]);
+    }
+
+    #[test]
+    fn follows_set_changes() {
+        let keys = keys(4);
+        let before: Validators = keys[..3].iter().map(|key| (*key, 1)).collect();
+        let mut selector = ProposerSelector::new(&before).unwrap();
+        counts(&mut selector, 30);
+
+        // the first validator leaves, and the last joins with twice the weight
+        let mut after = before.clone();
+        after.remove(&keys[0]);
+        after.insert(keys[3], 2);
+        selector.update(&after).unwrap();
+
+        // the new validator waits behind the others before its first turn
+        assert_ne!(selector.advance(), keys[3]);
+        let counts = counts(&mut selector, 400);
+        assert!(!counts.contains_key(&keys[0]));
+        for key in keys[1..].iter() {
+            let expected = after[key] * 100;
+            assert!(counts[key] + 1 >= expected && counts[key] <= expected + 1);
+        }
+    }
+
+    #[test]
+    fn rejects_too_much_weight() {
+        let keys = keys(2);
+        let too_large: Validators = [(keys[0], MAX_TOTAL_WEIGHT + 1)].iter().cloned().collect();
+        assert_eq!(
+            ProposerSelector::new(&too_large),
+            Err(InvalidValidators::TooMuchWeight)
+        );
+
+        let validators: Validators = [(keys[0], MAX_TOTAL_WEIGHT)].iter().cloned().collect();
+        let mut selector = ProposerSelector::new(&validators).unwrap();
+        let mut joined = validators.clone();
+        joined.insert(keys[1], 1);
+        assert_eq!(
+            selector.update(&joined),
+            Err(InvalidValidators::TooMuchWeight)
+        );
+        assert_eq!(selector, ProposerSelector::new(&validators).unwrap());
+    }
+
+    #[test]
+    fn priorities_stay_bounded() {
+        let validators: Validators = keys(3).into_iter().zip(vec![1_000_000, 1, 1]).collect();
+        let mut selector = ProposerSelector::new(&validators).unwrap();
+        counts(&mut selector, 10_000);
+        let priorities = selector.candidates.iter().map(|c| c.priority);
+        let spread = priorities.clone().max().unwrap() - priorities.min().unwrap();
+        assert!(spread <= PRIORITY_WINDOW * selector.total + selector.total);
+    }
+}
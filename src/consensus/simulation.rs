@@ -0,0 +1,917 @@
+//! A deterministic, in-process network for running many Tendermint validators
+//! under virtual tokio time (see `tokio::time::pause`).
+
+use std::{
+    collections::BTreeMap,
+    panic,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{
+        broadcast::error::TryRecvError,
+        mpsc::{self, Receiver, Sender},
+    },
+    time::{sleep, sleep_until, Duration, Instant},
+};
+
+use crate::crypto::{
+    contracts::{Contract, PrivateKey, PublicKey, Signable},
+    hashing::{Hash, Hashable},
+};
+
+use super::{
+    apply_updates,
+    byzantine::{Byzantine, Fault},
+    App, Broadcast, Channels, CommitCertificate, CommitError, ConsensusConfig, Error, Event,
+    Events, Evidence, Handle, ProposerSelector, SyncMessage, Tendermint, ValidatorUpdate,
+    Validators, Wal, UPDATE_DELAY,
+};
+
+/// The chain that simulated nodes sign for.
+pub const CHAIN_ID: &str = "simulation";
+
+/// Capacity of every simulated channel. Messages that don't fit are dropped.
+const CHANNEL_SIZE: usize = 4096;
+
+/// How long a crashed node stays down before it is restarted.
+const RESTART_DELAY: Duration = Duration::from_millis(500);
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct SimBlock {
+    pub height: u64,
+    pub proposer: usize,
+    pub last_commit: Option<CommitCertificate<SimBlock>>,
+}
+
+impl Hashable for SimBlock {
+    fn hash(&self) -> Hash<Self> {
+        let last_commit = self
+            .last_commit
+            .as_ref()
+            .map_or(Hash::empty(), |c| c.hash());
+        hash![self.height, self.proposer, last_commit]
+    }
+}
+
+/// A block committed by a node.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Decision {
+    pub node: usize,
+    pub height: u64,
+    pub block: SimBlock,
+    pub certificate: CommitCertificate<SimBlock>,
+    /// Virtual time since the start of the simulation.
+    pub time: Duration,
+    /// The BFT time of the block.
+    pub block_time: u128,
+}
+
+/// Two nodes that committed different blocks at the same height.
+pub struct SafetyViolation {
+    pub first: Decision,
+    pub second: Decision,
+}
+
+#[derive(Clone)]
+pub struct SimApp {
+    index: usize,
+    key: Arc<PrivateKey>,
+    /// The key of every node, by index.
+    keys: Arc<Vec<Hash<PublicKey>>>,
+    genesis: Arc<Validators>,
+    /// The validator updates returned by committing each height.
+    updates: Arc<BTreeMap<u64, Vec<ValidatorUpdate>>>,
+    height: u64,
+    start: Instant,
+    decisions: Arc<Mutex<Vec<Decision>>>,
+    evidence: Arc<Mutex<Vec<Evidence<SimBlock>>>>,
+}
+
+impl SimApp {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Returns the virtual milliseconds since the start of the simulation, which
+    /// timestamp messages so that runs are reproducible.
+    fn now(&self) -> u128 {
+        (Instant::now() - self.start).as_millis()
+    }
+
+    /// Returns a copy of the app as it would be after a restart, with
+    /// every decision it made before the crash already committed.
+    fn restarted(&self) -> SimApp {
+        let decided = self.decisions.lock().unwrap();
+        SimApp {
+            height: decided.iter().filter(|d| d.node == self.index).count() as u64,
+            ..self.clone()
+        }
+    }
+}
+
+impl App<SimBlock> for SimApp {
+    fn id(&self) -> Hash<PublicKey> {
+        self.keys[self.index]
+    }
+
+    fn chain_id(&self) -> &str {
+        CHAIN_ID
+    }
+
+    fn height(&self) -> u64 {
+        self.height
+    }
+
+    fn validators(&self, height: u64) -> Validators {
+        self.updates
+            .range(..height.saturating_sub(UPDATE_DELAY - 1))
+            .fold((*self.genesis).clone(), |validators, (_, updates)| {
+                apply_updates(&validators, updates).expect("simulated validators are bounded")
+            })
+    }
+
+    fn proposers(&self, height: u64) -> ProposerSelector {
+        // replayed from genesis, which is cheap for the few heights simulated
+        let mut selector = ProposerSelector::new(&self.genesis).unwrap();
+        for h in 1..=height {
+            selector.advance();
+            selector.update(&self.validators(h)).unwrap();
+        }
+        selector
+    }
+
+    fn create_block(&self, last_commit: Option<CommitCertificate<SimBlock>>) -> SimBlock {
+        SimBlock {
+            height: self.height,
+            proposer: self.index,
+            last_commit,
+        }
+    }
+
+    fn last_commit<'a>(&self, block: &'a SimBlock) -> Option<&'a CommitCertificate<SimBlock>> {
+        block.last_commit.as_ref()
+    }
+
+    fn validate_block(&self, block: &SimBlock) -> bool {
+        block.height == self.height
+    }
+
+    fn commit(
+        &mut self,
+        block: SimBlock,
+        certificate: CommitCertificate<SimBlock>,
+        block_time: u128,
+    ) -> Result<Vec<ValidatorUpdate>, CommitError> {
+        self.decisions.lock().unwrap().push(Decision {
+            node: self.index,
+            height: self.height,
+            block,
+            certificate,
+            time: Instant::now() - self.start,
+            block_time,
+        });
+        self.height += 1;
+        Ok(self
+            .updates
+            .get(&(self.height - 1))
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn committed(&self, height: u64) -> Option<(SimBlock, CommitCertificate<SimBlock>)> {
+        self.decisions
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|d| d.node == self.index && d.height == height)
+            .map(|d| (d.block.clone(), d.certificate.clone()))
+    }
+
+    fn report_evidence(&mut self, evidence: Evidence<SimBlock>) {
+        self.evidence.lock().unwrap().push(evidence);
+    }
+
+    fn sign<T: Signable>(&self, contract: T) -> Contract<T> {
+        self.key.sign_at(CHAIN_ID, contract, self.now())
+    }
+
+    fn sign_after<T: Signable>(&self, contract: T, earliest: u128) -> Contract<T> {
+        self.key
+            .sign_at(CHAIN_ID, contract, self.now().max(earliest + 1))
+    }
+}
+
+/// Cuts the network into groups for a period of virtual time. Messages
+/// between groups are held back until the partition heals.
+#[derive(Clone)]
+pub struct Partition {
+    pub from: Duration,
+    pub until: Duration,
+    /// Nodes that aren't listed form a group of their own.
+    pub groups: Vec<Vec<usize>>,
+}
+
+impl Partition {
+    fn group(&self, node: usize) -> Option<usize> {
+        self.groups.iter().position(|group| group.contains(&node))
+    }
+
+    fn separates(&self, time: Duration, a: usize, b: usize) -> bool {
+        self.from <= time && time < self.until && self.group(a) != self.group(b)
+    }
+}
+
+/// A message sent between nodes.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone)]
+enum Packet {
+    Consensus(Broadcast<SimBlock>),
+    Sync(SyncMessage<SimBlock>),
+}
+
+/// A packet sent by a node, for a single node or (if `to` is None) for everyone.
+struct Envelope {
+    from: usize,
+    to: Option<usize>,
+    packet: Packet,
+}
+
+/// Crashes a node once it has written the given number of entries to its WAL.
+#[derive(Clone)]
+pub struct Crash {
+    pub node: usize,
+    pub after_writes: usize,
+}
+
+/// Sets a node's stake when the given height is committed. Like any
+/// validator update, it takes effect UPDATE_DELAY heights later.
+#[derive(Clone)]
+pub struct StakeChange {
+    pub height: u64,
+    pub node: usize,
+    pub stake: u64,
+}
+
+/// Corrupts the consensus messages that a node sends.
+#[derive(Clone)]
+pub struct Faulty {
+    pub node: usize,
+    pub fault: Fault,
+}
+
+#[derive(Clone)]
+pub struct NetworkConfig {
+    pub min_delay: Duration,
+    pub max_delay: Duration,
+    /// Probability that a message to another node is lost.
+    pub drop_rate: f64,
+    pub partitions: Vec<Partition>,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            min_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(100),
+            drop_rate: 0.0,
+            partitions: Vec::new(),
+        }
+    }
+}
+
+pub struct Simulation {
+    pub seed: u64,
+    /// The initial voting weight of each node. Nodes with no weight only follow along.
+    pub stakes: Vec<u64>,
+    pub stake_changes: Vec<StakeChange>,
+    pub network: NetworkConfig,
+    pub config: ConsensusConfig,
+    pub crashes: Vec<Crash>,
+    pub faulty: Vec<Faulty>,
+}
+
+impl Simulation {
+    /// Creates a simulation of equally weighted validators on a reliable network.
+    pub fn new(validators: usize, seed: u64) -> Simulation {
+        Simulation {
+            seed,
+            stakes: vec![1; validators],
+            stake_changes: Vec::new(),
+            network: NetworkConfig::default(),
+            config: ConsensusConfig::default(),
+            crashes: Vec::new(),
+            faulty: Vec::new(),
+        }
+    }
+
+    /// Creates an app for each validator. The apps share a record of decisions and evidence.
+    pub fn apps(&self) -> Vec<SimApp> {
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let keys: Vec<Arc<PrivateKey>> = self
+            .stakes
+            .iter()
+            .map(|_| Arc::new(PrivateKey::from_rng(&mut rng)))
+            .collect();
+        let ids: Arc<Vec<_>> = Arc::new(keys.iter().map(|key| key.get_public().hash()).collect());
+        let genesis: Arc<Validators> = Arc::new(
+            ids.iter()
+                .zip(self.stakes.iter())
+                .filter(|(_, stake)| **stake > 0)
+                .map(|(id, stake)| (*id, *stake))
+                .collect(),
+        );
+        let mut updates = BTreeMap::new();
+        for change in self.stake_changes.iter() {
+            updates
+                .entry(change.height)
+                .or_insert_with(Vec::new)
+                .push(ValidatorUpdate {
+                    validator: ids[change.node],
+                    weight: change.stake,
+                });
+        }
+        let updates = Arc::new(updates);
+        let start = Instant::now();
+        let decisions = Arc::new(Mutex::new(Vec::new()));
+        let evidence = Arc::new(Mutex::new(Vec::new()));
+        keys.into_iter()
+            .enumerate()
+            .map(|(index, key)| SimApp {
+                index,
+                key,
+                keys: ids.clone(),
+                genesis: genesis.clone(),
+                updates: updates.clone(),
+                height: 0,
+                start,
+                decisions: decisions.clone(),
+                evidence: evidence.clone(),
+            })
+            .collect()
+    }
+
+    /// Runs until every node has decided the given number of heights,
+    /// or until the time limit has passed.
+    pub async fn run(&self, heights: u64, limit: Duration) -> Report {
+        // offset the seed, so that network randomness is independent of the keys
+        let mut rng = StdRng::seed_from_u64(self.seed.wrapping_add(1));
+        let apps = self.apps();
+
+        let start = apps[0].start;
+        let deadline = start + limit;
+        let decisions = apps[0].decisions.clone();
+        let evidence = apps[0].evidence.clone();
+        let app = apps[0].clone();
+
+        let (outgoing, mut packets) = mpsc::channel(CHANNEL_SIZE);
+        let mut inboxes: Vec<Sender<Packet>> = Vec::new();
+        let mut nodes = Vec::new();
+        let wals = tempfile::tempdir().unwrap();
+        let mut subscriptions = Vec::new();
+        for app in apps {
+            let events = Events::new(CHANNEL_SIZE);
+            subscriptions.push(events.subscribe());
+            let (inbox, incoming) = mpsc::channel(CHANNEL_SIZE);
+            let wal = wals.path().join(format!("{}.wal", app.index));
+            let crash = self.crashes.iter().find(|c| c.node == app.index);
+            let faulty = self.faulty.iter().find(|f| f.node == app.index);
+            let byzantine = faulty.map(|f| Byzantine::new(f.fault, app.clone(), self.stakes.len()));
+            inboxes.push(inbox);
+            nodes.push(tokio::spawn(run_node(
+                app,
+                self.config.clone(),
+                wal,
+                crash.map(|c| c.after_writes),
+                byzantine,
+                incoming,
+                outgoing.clone(),
+                events,
+            )));
+        }
+        drop(outgoing);
+
+        // Messages waiting to be delivered, ordered by delivery time and then by send order.
+        let mut in_flight = BTreeMap::new();
+        let mut sent: u64 = 0;
+
+        while !finished(&decisions.lock().unwrap(), self.stakes.len(), heights) {
+            let next = in_flight
+                .keys()
+                .next()
+                .map(|(time, _)| *time)
+                .unwrap_or(deadline)
+                .min(deadline);
+            tokio::select! {
+                biased;
+                Some(Envelope { from, to, packet }) = packets.recv() => {
+                    let now = Instant::now();
+                    let recipients = match to {
+                        Some(to) => to..to + 1,
+                        None => 0..inboxes.len(),
+                    };
+                    // like gossipsub, nodes don't receive their own messages
+                    for to in recipients.filter(|to| *to != from) {
+                        if let Some(time) = self.delivery_time(&mut rng, now - start, from, to) {
+                            in_flight.insert((start + time, sent), (to, Packet::clone(&packet)));
+                            sent += 1;
+                        }
+                    }
+                }
+                _ = sleep_until(next) => {
+                    if next >= deadline {
+                        break;
+                    }
+                    while let Some(&key) = in_flight.keys().next() {
+                        if key.0 > Instant::now() {
+                            break;
+                        }
+                        let (to, packet) = in_flight.remove(&key).unwrap();
+                        // a full inbox behaves like a lost message
+                        let _ = inboxes[to].try_send(packet);
+                    }
+                }
+            }
+        }
+
+        for node in nodes {
+            node.abort();
+            if let Err(e) = node.await {
+                if e.is_panic() {
+                    panic::resume_unwind(e.into_panic());
+                }
+            }
+        }
+
+        let decisions = decisions.lock().unwrap().clone();
+        let evidence = evidence.lock().unwrap().clone();
+        let decided = decisions.iter().map(|d| d.height + 1).max().unwrap_or(0);
+        let validators = (0..decided).map(|height| app.validators(height)).collect();
+        let events = subscriptions
+            .into_iter()
+            .map(|mut subscription| {
+                let mut events = Vec::new();
+                loop {
+                    match subscription.try_recv() {
+                        Ok(event) => events.push(event),
+                        // skip over events that were missed because the buffer was full
+                        Err(TryRecvError::Lagged(_)) => continue,
+                        Err(_) => break events,
+                    }
+                }
+            })
+            .collect();
+        Report {
+            validators,
+            decisions,
+            evidence,
+            events,
+        }
+    }
+
+    /// Returns the time (since the start of the simulation) at which a message should be
+    /// delivered, or None if it is lost.
+    fn delivery_time(
+        &self,
+        rng: &mut StdRng,
+        now: Duration,
+        from: usize,
+        to: usize,
+    ) -> Option<Duration> {
+        if rng.gen_bool(self.network.drop_rate) {
+            return None;
+        }
+        let min = self.network.min_delay.as_millis() as u64;
+        let max = self.network.max_delay.as_millis() as u64;
+        let delay = Duration::from_millis(rng.gen_range(min, max + 1));
+
+        // held back until every partition between the nodes has healed
+        let sent = self
+            .network
+            .partitions
+            .iter()
+            .filter(|p| p.separates(now, from, to))
+            .map(|p| p.until)
+            .max()
+            .unwrap_or(now);
+        Some(sent + delay)
+    }
+}
+
+/// Runs a node, restarting it from its WAL if it crashes. Packets sent by the
+/// node are tagged with its index. Packets that arrive while it is down are lost.
+/// A faulty node's consensus messages are corrupted before they are sent.
+#[allow(clippy::too_many_arguments)]
+async fn run_node(
+    app: SimApp,
+    config: ConsensusConfig,
+    wal: PathBuf,
+    mut crash_after: Option<usize>,
+    mut byzantine: Option<Byzantine>,
+    mut inbox: Receiver<Packet>,
+    router: Sender<Envelope>,
+    events: Events<SimBlock>,
+) -> Result<(), Error> {
+    loop {
+        let mut log = Wal::open(&wal).unwrap();
+        log.crash_after = crash_after.take();
+        let (forward, incoming) = mpsc::channel(CHANNEL_SIZE);
+        let (outgoing, mut broadcasts) = mpsc::channel(CHANNEL_SIZE);
+        let (rejected, _) = mpsc::channel(1);
+        let (forward_sync, sync_incoming) = mpsc::channel(CHANNEL_SIZE);
+        let (sync_outgoing, mut sync_messages) = mpsc::channel(CHANNEL_SIZE);
+        let (_handle, controls) = Handle::new();
+        let channels = Channels {
+            incoming,
+            outgoing,
+            rejected,
+            sync_incoming,
+            sync_outgoing,
+            events: events.clone(),
+            controls,
+        };
+        let node = Tendermint::start(app.restarted(), config.clone(), log, channels);
+        tokio::pin!(node);
+
+        let result = loop {
+            let envelopes = tokio::select! {
+                biased;
+                result = &mut node => break result,
+                Some(packet) = inbox.recv() => {
+                    // a full channel behaves like a lost message
+                    match packet {
+                        Packet::Consensus(broadcast) => { let _ = forward.try_send(broadcast); }
+                        Packet::Sync(message) => { let _ = forward_sync.try_send(message); }
+                    }
+                    continue;
+                }
+                Some(broadcast) = broadcasts.recv() => match byzantine.as_mut() {
+                    Some(byzantine) => byzantine
+                        .corrupt(broadcast)
+                        .into_iter()
+                        .map(|c| (c.to, Packet::Consensus(c.broadcast)))
+                        .collect(),
+                    None => vec![(None, Packet::Consensus(broadcast))],
+                },
+                Some(message) = sync_messages.recv() => vec![(None, Packet::Sync(message))],
+            };
+            for (to, packet) in envelopes {
+                let from = app.index;
+                let _ = router.send(Envelope { from, to, packet }).await;
+            }
+        };
+        match result {
+            Err(Error::WalFailed(_)) => {
+                sleep(RESTART_DELAY).await;
+                while inbox.try_recv().is_ok() {}
+            }
+            result => return result,
+        }
+    }
+}
+
+fn finished(decisions: &[Decision], nodes: usize, heights: u64) -> bool {
+    (0..nodes).all(|node| decisions.iter().filter(|d| d.node == node).count() as u64 >= heights)
+}
+
+pub struct Report {
+    /// The validators at each height that was decided.
+    pub validators: Vec<Validators>,
+    /// Every decision, in the order that they were made.
+    pub decisions: Vec<Decision>,
+    /// Every equivocation reported to a node, including duplicates.
+    pub evidence: Vec<Evidence<SimBlock>>,
+    /// The events emitted by each node.
+    pub events: Vec<Vec<Event<SimBlock>>>,
+}
+
+impl Report {
+    /// Returns the heights decided by a node, in the order they were decided.
+    pub fn decided(&self, node: usize) -> Vec<u64> {
+        self.decisions
+            .iter()
+            .filter(|d| d.node == node)
+            .map(|d| d.height)
+            .collect()
+    }
+
+    /// Returns the nodes that decided the given height.
+    pub fn deciders(&self, height: u64) -> Vec<usize> {
+        self.decisions
+            .iter()
+            .filter(|d| d.height == height)
+            .map(|d| d.node)
+            .collect()
+    }
+
+    /// Returns every pair of conflicting decisions.
+    pub fn violations(&self) -> Vec<SafetyViolation> {
+        let mut first: BTreeMap<u64, &Decision> = BTreeMap::new();
+        let mut violations = Vec::new();
+        for decision in self.decisions.iter() {
+            match first.get(&decision.height) {
+                Some(f) if f.block != decision.block => violations.push(SafetyViolation {
+                    first: (*f).clone(),
+                    second: decision.clone(),
+                }),
+                Some(_) => (),
+                None => {
+                    first.insert(decision.height, decision);
+                }
+            }
+        }
+        violations
+    }
+
+    /// Panics if any decision lacks a valid commit certificate.
+    pub fn assert_certified(&self) {
+        for d in self.decisions.iter() {
+            assert!(
+                d.certificate.height == d.height
+                    && d.certificate.block == d.block.hash()
+                    && self
+                        .validators
+                        .get(d.height as usize)
+                        .is_some_and(|validators| d.certificate.verify(CHAIN_ID, validators)),
+                "node {} has no valid certificate for height {}",
+                d.node,
+                d.height
+            );
+        }
+    }
+
+    /// Panics unless every node committed the same time for each height, and
+    /// the times increase with height.
+    pub fn assert_time_increases(&self) {
+        let mut times: BTreeMap<u64, u128> = BTreeMap::new();
+        for d in self.decisions.iter() {
+            let time = *times.entry(d.height).or_insert(d.block_time);
+            assert_eq!(
+                time, d.block_time,
+                "nodes disagree on the time of height {}",
+                d.height
+            );
+        }
+        let times: Vec<u128> = times.values().copied().collect();
+        assert!(
+            times.windows(2).all(|w| w[0] < w[1]),
+            "block times {:?} don't increase",
+            times
+        );
+    }
+
+    /// Panics if any two nodes committed different blocks at the same height.
+    pub fn assert_safe(&self) {
+        if let Some(v) = self.violations().first() {
+            panic!(
+                "safety violated at height {}: node {} committed {:?} but node {} committed {:?}",
+                v.first.height, v.first.node, v.first.block, v.second.node, v.second.block
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::{EventKind, TimeoutKind};
+
+    const LIMIT: Duration = Duration::from_secs(600);
+
+    #[tokio::test(start_paused = true)]
+    async fn four_validators_decide() {
+        let report = Simulation::new(4, 0).run(5, LIMIT).await;
+        report.assert_safe();
+        report.assert_certified();
+        report.assert_time_increases();
+        assert!(report.evidence.is_empty());
+        for node in 0..4 {
+            assert_eq!(report.decided(node), vec![0, 1, 2, 3, 4]);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reports_progress() {
+        let report = Simulation::new(4, 0).run(2, LIMIT).await;
+        // follow the first proposer
+        let events = &report.events[report.decisions[0].block.proposer];
+        let block = Some(report.decisions[0].block.hash());
+        let event = |kind, height, block| Event {
+            kind,
+            height,
+            round: 0,
+            block,
+        };
+        assert_eq!(
+            events[..2],
+            [
+                event(EventKind::NewHeight, 0, None),
+                event(EventKind::NewRound, 0, None)
+            ]
+        );
+
+        // everything goes to plan
+        let position =
+            |kind, height, block| events.iter().position(|e| *e == event(kind, height, block));
+        let order = [
+            position(EventKind::Proposed, 0, block),
+            position(EventKind::Prevoted, 0, block),
+            position(EventKind::Locked, 0, block),
+            position(EventKind::Precommitted, 0, block),
+            position(EventKind::Committed, 0, block),
+            position(EventKind::NewHeight, 1, None),
+        ];
+        assert!(order.iter().all(Option::is_some));
+        assert!(order.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reproducible_with_seed() {
+        let mut sim = Simulation::new(7, 42);
+        sim.network.max_delay = Duration::from_millis(1500);
+        let first = sim.run(3, LIMIT).await;
+        let second = sim.run(3, LIMIT).await;
+        assert_eq!(first.decisions, second.decisions);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_and_reordered() {
+        let mut sim = Simulation::new(10, 1);
+        // well beyond the base timeouts, which must grow until messages arrive in time
+        sim.network.max_delay = Duration::from_millis(3000);
+        let report = sim.run(3, LIMIT).await;
+        report.assert_safe();
+        assert_eq!(report.deciders(2).len(), 10);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lossy_network_is_safe() {
+        for seed in 0..5 {
+            let mut sim = Simulation::new(4, seed);
+            sim.network.drop_rate = 0.2;
+            sim.run(3, Duration::from_secs(60)).await.assert_safe();
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn isolated_minority() {
+        let mut sim = Simulation::new(4, 2);
+        sim.network.partitions.push(Partition {
+            from: Duration::from_secs(0),
+            until: Duration::from_secs(3600),
+            groups: vec![vec![3]],
+        });
+        let report = sim.run(3, Duration::from_secs(60)).await;
+        report.assert_safe();
+        assert_eq!(report.deciders(2).len(), 3);
+        assert!(report.decided(3).is_empty());
+        assert!(report.events[3]
+            .iter()
+            .any(|e| e.kind == EventKind::TimeoutFired(TimeoutKind::Propose)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn partition_heals() {
+        let mut sim = Simulation::new(4, 3);
+        let until = Duration::from_secs(30);
+        sim.network.partitions.push(Partition {
+            from: Duration::from_secs(0),
+            until,
+            groups: vec![vec![0, 1]],
+        });
+        let report = sim.run(2, LIMIT).await;
+        report.assert_safe();
+        assert!(report.decisions.iter().all(|d| d.time >= until));
+        assert_eq!(report.deciders(1).len(), 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn weighted_validators() {
+        let mut sim = Simulation::new(5, 4);
+        // the first validator alone holds more than a third of the votes
+        sim.stakes = vec![4, 1, 1, 1, 1];
+        let report = sim.run(3, LIMIT).await;
+        report.assert_safe();
+        report.assert_certified();
+        assert_eq!(report.deciders(2).len(), 5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hundred_validators() {
+        let report = Simulation::new(100, 5).run(1, LIMIT).await;
+        report.assert_safe();
+        assert_eq!(report.deciders(0).len(), 100);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn crash_and_restart() {
+        // crash at each write during the first few heights
+        for after_writes in 0..40 {
+            let mut sim = Simulation::new(4, 6);
+            sim.crashes.push(Crash {
+                node: 0,
+                after_writes,
+            });
+            let report = sim.run(4, Duration::from_secs(30)).await;
+            report.assert_safe();
+            report.assert_certified();
+            // the restarted node never signed a conflicting vote
+            assert!(report.evidence.is_empty());
+            // nor committed a height twice, and caught up with the others
+            let decided = report.decided(0);
+            assert_eq!(decided, (0..decided.len() as u64).collect::<Vec<_>>());
+            assert!(decided.len() >= 4);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lagging_node_catches_up() {
+        let mut sim = Simulation::new(4, 7);
+        let until = Duration::from_secs(30);
+        sim.network.partitions.push(Partition {
+            from: Duration::from_secs(0),
+            until,
+            groups: vec![vec![3]],
+        });
+        let report = sim.run(20, LIMIT).await;
+        report.assert_safe();
+        report.assert_certified();
+        assert_eq!(report.decided(3), (0..20).collect::<Vec<_>>());
+        // by the time the partition healed, the others were too far ahead to rejoin without syncing
+        let before = report.decisions.iter().filter(|d| d.time < until);
+        assert!(before.map(|d| d.height).max() > Some(10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn validator_set_changes() {
+        let mut sim = Simulation::new(5, 8);
+        sim.stakes = vec![1, 1, 1, 1, 0];
+        // node 4 replaces node 0, from height 1 + UPDATE_DELAY
+        sim.stake_changes = vec![
+            StakeChange {
+                height: 1,
+                node: 4,
+                stake: 1,
+            },
+            StakeChange {
+                height: 1,
+                node: 0,
+                stake: 0,
+            },
+        ];
+        let report = sim.run(10, LIMIT).await;
+        report.assert_safe();
+        report.assert_certified();
+
+        let removed = sim.apps()[0].id();
+        let changed = 1 + UPDATE_DELAY;
+        for d in report.decisions.iter().filter(|d| d.height >= changed) {
+            assert_ne!(d.block.proposer, 0);
+            assert!(d
+                .certificate
+                .precommits
+                .iter()
+                .all(|precommit| precommit.signee.hash() != removed));
+        }
+        assert!(report
+            .decisions
+            .iter()
+            .any(|d| d.height >= changed && d.block.proposer == 4));
+        // the removed validator still follows the chain
+        assert_eq!(report.decided(0), (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn detects_conflicting_decisions() {
+        let decision = |node, proposer| {
+            let block = SimBlock {
+                height: 0,
+                proposer,
+                last_commit: None,
+            };
+            Decision {
+                node,
+                height: 0,
+                certificate: CommitCertificate {
+                    height: 0,
+                    round: 0,
+                    block: block.hash(),
+                    precommits: Vec::new(),
+                },
+                block,
+                time: Duration::from_secs(0),
+                block_time: 0,
+            }
+        };
+        let report = Report {
+            validators: Vec::new(),
+            decisions: vec![decision(0, 0), decision(1, 0), decision(2, 1)],
+            evidence: Vec::new(),
+            events: Vec::new(),
+        };
+        assert_eq!(report.violations().len(), 1);
+    }
+}
@@ -0,0 +1,97 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    consensus::{App, Broadcast, CommitCertificate, Error, Tendermint, Timeouts},
+    crypto::hashing::Hashable,
+};
+
+/// A message used by lagging nodes to fetch blocks that have already been decided.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum SyncMessage<B> {
+    /// Asks peers for the block committed at the given height.
+    Request { height: u64 },
+    /// A committed block, along with the precommits proving it was decided. It is sent
+    /// whole, so apps must keep their blocks small enough for the message to fit in
+    /// `MAX_MESSAGE_SIZE`, or lagging nodes can't sync past them.
+    Block {
+        block: B,
+        certificate: CommitCertificate<B>,
+    },
+}
+
+impl<A: App<B>, B: Hashable + Clone + Eq + Serialize + DeserializeOwned> Tendermint<A, B> {
+    /// Notes a validly signed message from a later height. If the current height
+    /// isn't decided soon, the missing blocks are requested from peers.
+    pub(super) fn observe(&mut self, broadcast: &Broadcast<B>) {
+        let height = broadcast.height();
+        if height <= self.height {
+            return;
+        }
+        self.peer_height = self.peer_height.max(height);
+        if !self.sync_scheduled {
+            self.schedule_sync();
+        }
+    }
+
+    fn schedule_sync(&mut self) {
+        self.timeouts.add(
+            Timeouts::Sync {
+                height: self.height,
+            },
+            self.config.sync_timeout,
+        );
+        self.sync_scheduled = true;
+    }
+
+    /// Requests the current height again if it still hasn't been decided,
+    /// and keeps retrying until it is.
+    pub(super) fn sync_timeout(&mut self, height: u64) {
+        if height == self.height && self.peer_height > height {
+            self.request(height);
+            self.schedule_sync();
+        }
+    }
+
+    /// Serves requests for committed blocks, and commits blocks received for the current height.
+    pub(super) async fn sync(&mut self, message: SyncMessage<B>) -> Result<(), Error> {
+        match message {
+            SyncMessage::Request { height } if height < self.height => {
+                if let Some((block, certificate)) = self.app.committed(height) {
+                    self.send_sync(SyncMessage::Block { block, certificate });
+                }
+            }
+            SyncMessage::Request { .. } => (),
+            SyncMessage::Block { block, certificate } => {
+                // the certificate is checked rather than the sender, so blocks can come from anyone
+                let time = if certificate.height == self.height
+                    && certificate.block == block.hash()
+                    && self.validators.get(self.height).is_some_and(|validators| {
+                        certificate.verify(self.app.chain_id(), validators)
+                    }) {
+                    self.valid_time(&block)
+                } else {
+                    None
+                };
+                if let Some(time) = time {
+                    self.new_height(self.height + 1, Some((block, certificate, time)))
+                        .await?;
+                    // fetch the next block straight away, rather than waiting for the timeout
+                    if self.peer_height > self.height {
+                        self.request(self.height);
+                        self.schedule_sync();
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn request(&self, height: u64) {
+        self.send_sync(SyncMessage::Request { height });
+    }
+
+    /// Sync is best effort, so messages that can't be sent immediately are dropped.
+    fn send_sync(&self, message: SyncMessage<B>) {
+        let _ = self.sync_outgoing.try_send(message);
+    }
+}
@@ -0,0 +1,59 @@
+//! BFT time. Each block includes the certificate for the block before it, and its time is
+//! the stake-weighted median of the timestamps in that certificate. Correct validators
+//! timestamp their precommits for a block after its time, and hold more than half of the
+//! weight in any certificate, so block times always increase.
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::crypto::hashing::Hashable;
+
+use super::{App, CommitCertificate, Tendermint, Validators};
+
+/// The certificate for the last block committed, and the validators it was checked against.
+pub(super) struct Previous<B> {
+    pub certificate: CommitCertificate<B>,
+    pub validators: Validators,
+}
+
+impl<B: Hashable> Previous<B> {
+    /// Returns the block before the given height, if it has been committed.
+    pub fn load<A: App<B>>(app: &A, height: u64) -> Option<Previous<B>> {
+        let previous = height.checked_sub(1)?;
+        let (_, certificate) = app.committed(previous)?;
+        Some(Previous {
+            certificate,
+            validators: app.validators(previous),
+        })
+    }
+}
+
+impl<A: App<B>, B: Hashable + Clone + Eq + Serialize + DeserializeOwned> Tendermint<A, B> {
+    /// Returns the time of a block for the current height, or None if it doesn't
+    /// include a valid certificate for the previous block. The first block is at the
+    /// genesis time.
+    pub(super) fn block_time(&self, block: &B) -> Option<u128> {
+        match (self.app.last_commit(block), &self.previous) {
+            (None, None) if self.height == 0 => Some(self.app.genesis_time()),
+            (Some(last), Some(previous))
+                if last.height + 1 == self.height
+                    && last.block == previous.certificate.block
+                    && last.verify(self.app.chain_id(), &previous.validators) =>
+            {
+                Some(last.time(&previous.validators))
+            }
+            _ => None,
+        }
+    }
+
+    /// valid(v): the block is accepted by the app, and its time can be worked out.
+    pub(super) fn valid_block(&self, block: &B) -> bool {
+        self.valid_time(block).is_some()
+    }
+
+    /// Returns the time of a block for the current height if it is valid, so that a
+    /// block is only ever precommitted or committed along with its time.
+    pub(super) fn valid_time(&self, block: &B) -> Option<u128> {
+        let time = self.block_time(block)?;
+        self.app.validate_block(block).then_some(time)
+    }
+}
@@ -1,38 +1,187 @@
-use futures::future::select_all;
-use futures::FutureExt;
-use tokio::time::Instant;
+use std::{cmp::Ordering, collections::BinaryHeap, fmt, sync::Arc};
 
-use tokio::time::{sleep_until, Duration};
+use futures::{future::BoxFuture, FutureExt};
+use tokio::time::{sleep_until, Duration, Instant};
+
+/// A source of time for timeouts.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Returns a future that completes once the deadline has passed.
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()>;
+}
+
+impl fmt::Debug for dyn Clock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Clock")
+    }
+}
+
+/// The tokio timer. Under `tokio::time::pause`, the runtime advances it
+/// whenever every task is idle, which lets simulations skip through time.
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        sleep_until(deadline).boxed()
+    }
+}
 
 struct Timeout<T> {
     time: Instant,
+    /// Breaks ties between timeouts for the same time, in the order they were added.
+    seq: u64,
     value: T,
 }
 
+impl<T> PartialEq for Timeout<T> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.time, self.seq) == (other.time, other.seq)
+    }
+}
+
+impl<T> Eq for Timeout<T> {}
+
+impl<T> PartialOrd for Timeout<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Timeout<T> {
+    // reversed, so that the heap returns the earliest timeout first
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.time, other.seq).cmp(&(self.time, self.seq))
+    }
+}
+
+/// Pending timeouts, kept in a heap ordered by expiry time.
 pub struct TimeoutManager<T> {
-    timeouts: Vec<Timeout<T>>,
+    clock: Arc<dyn Clock>,
+    timeouts: BinaryHeap<Timeout<T>>,
+    seq: u64,
 }
 
 impl<T> TimeoutManager<T> {
-    pub fn new() -> TimeoutManager<T> {
+    pub fn new(clock: Arc<dyn Clock>) -> TimeoutManager<T> {
         TimeoutManager {
-            timeouts: Vec::new(),
+            clock,
+            timeouts: BinaryHeap::new(),
+            seq: 0,
         }
     }
 
     pub fn add(&mut self, value: T, delay: Duration) {
         self.timeouts.push(Timeout {
+            time: self.clock.now() + delay,
+            seq: self.seq,
             value,
-            time: Instant::now() + delay,
         });
+        self.seq += 1;
+    }
+
+    /// Removes every pending timeout that matches the predicate.
+    pub fn cancel(&mut self, mut predicate: impl FnMut(&T) -> bool) {
+        self.timeouts.retain(|timeout| !predicate(&timeout.value));
     }
 
+    /// Waits for the earliest timeout to expire, and removes it. Nothing is removed
+    /// if the future is dropped early, so this can be used in a `select!`.
     pub async fn get_next(&mut self) -> T {
-        let futures = self
-            .timeouts
-            .iter()
-            .map(|timeout| sleep_until(timeout.time).boxed());
-        let (_, idx, _) = select_all(futures).await;
-        self.timeouts.remove(idx).value
+        match self.timeouts.peek() {
+            Some(next) => self.clock.sleep_until(next.time).await,
+            // wait for the next call, which may have timeouts to wait for
+            None => futures::future::pending().await,
+        }
+        self.timeouts.pop().unwrap().value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::watch;
+
+    use super::*;
+
+    /// A clock that only moves when told to.
+    struct ManualClock {
+        sender: watch::Sender<Instant>,
+        receiver: watch::Receiver<Instant>,
+    }
+
+    impl ManualClock {
+        fn new() -> Arc<ManualClock> {
+            let now = Instant::now();
+            let (sender, receiver) = watch::channel(now);
+            Arc::new(ManualClock { sender, receiver })
+        }
+
+        fn advance(&self, duration: Duration) {
+            let now = self.now() + duration;
+            self.sender.send(now).unwrap();
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            *self.receiver.borrow()
+        }
+
+        fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+            let mut receiver = self.receiver.clone();
+            async move {
+                while *receiver.borrow() < deadline {
+                    receiver.changed().await.unwrap();
+                }
+            }
+            .boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn expires_in_order() {
+        let clock = ManualClock::new();
+        let mut timeouts = TimeoutManager::new(clock.clone());
+        timeouts.add("c", Duration::from_millis(300));
+        timeouts.add("a", Duration::from_millis(100));
+        timeouts.add("b", Duration::from_millis(100));
+
+        assert_eq!(timeouts.get_next().now_or_never(), None);
+        assert_eq!(timeouts.timeouts.len(), 3);
+
+        clock.advance(Duration::from_millis(200));
+        assert_eq!(timeouts.get_next().now_or_never(), Some("a"));
+        assert_eq!(timeouts.get_next().now_or_never(), Some("b"));
+        assert_eq!(timeouts.get_next().now_or_never(), None);
+
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(timeouts.get_next().await, "c");
+        assert_eq!(timeouts.timeouts.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn cancels_matching_timeouts() {
+        let clock = ManualClock::new();
+        let mut timeouts = TimeoutManager::new(clock.clone());
+        for height in 0..4u64 {
+            timeouts.add(height, Duration::from_millis(100));
+        }
+        timeouts.cancel(|height| *height < 2);
+        assert_eq!(timeouts.timeouts.len(), 2);
+
+        clock.advance(Duration::from_millis(100));
+        assert_eq!(timeouts.get_next().await, 2);
+        assert_eq!(timeouts.get_next().await, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_forever_when_empty() {
+        let mut timeouts = TimeoutManager::<()>::new(Arc::new(TokioClock));
+        let waited = tokio::time::timeout(Duration::from_secs(3600), timeouts.get_next()).await;
+        assert!(waited.is_err());
     }
 }
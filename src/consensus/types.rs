@@ -1,8 +1,13 @@
+use serde::{Deserialize, Serialize};
+
 use crate::crypto::{
-    contracts::Contract,
+    contracts::{Contract, PublicKey, Signable},
     hashing::{Hash, Hashable},
 };
 
+use super::{BlockPart, CommitError, InvalidValidators, PartsHeader};
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum Step {
     Propose,
     Prevote { timeout_scheduled: bool },
@@ -29,13 +34,17 @@ impl Step {
     }
 }
 
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub struct Proposal<T: Hashable> {
     pub height: u64,
     pub round: u64,
-    pub proposal: T,
+    /// The proposed block, which is sent separately as parts.
+    pub block: Hash<T>,
+    pub parts: PartsHeader,
     pub valid_round: Option<u64>,
 }
 
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub struct Prevote<T> {
     pub height: u64,
     pub round: u64,
@@ -47,6 +56,7 @@ impl<T> Prevote<T> {
         Prevote { height, round, id }
     }
 }
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub struct Precommit<T> {
     pub height: u64,
     pub round: u64,
@@ -60,10 +70,53 @@ impl<T> Precommit<T> {
 }
 
 #[allow(clippy::large_enum_variant)]
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum Broadcast<B: Hashable> {
     Proposal(Contract<Proposal<B>>),
     Prevote(Contract<Prevote<B>>),
     Precommit(Contract<Precommit<B>>),
+    /// A part of the block in a proposal, signed by the proposer.
+    Part(Contract<BlockPart>),
+}
+
+impl<B: Hashable> Broadcast<B> {
+    /// Returns the public key of the validator that signed the message.
+    pub fn signee(&self) -> &PublicKey {
+        match self {
+            Broadcast::Proposal(c) => &c.signee,
+            Broadcast::Prevote(c) => &c.signee,
+            Broadcast::Precommit(c) => &c.signee,
+            Broadcast::Part(c) => &c.signee,
+        }
+    }
+
+    pub fn height(&self) -> u64 {
+        match self {
+            Broadcast::Proposal(c) => c.content.height,
+            Broadcast::Prevote(c) => c.content.height,
+            Broadcast::Precommit(c) => c.content.height,
+            Broadcast::Part(c) => c.content.height,
+        }
+    }
+
+    pub fn round(&self) -> u64 {
+        match self {
+            Broadcast::Proposal(c) => c.content.round,
+            Broadcast::Prevote(c) => c.content.round,
+            Broadcast::Precommit(c) => c.content.round,
+            Broadcast::Part(c) => c.content.round,
+        }
+    }
+
+    /// Checks the signature on the message.
+    pub fn verify(&self, chain_id: &str) -> bool {
+        match self {
+            Broadcast::Proposal(c) => c.verify(chain_id),
+            Broadcast::Prevote(c) => c.verify(chain_id),
+            Broadcast::Precommit(c) => c.verify(chain_id),
+            Broadcast::Part(c) => c.verify(chain_id),
+        }
+    }
 }
 
 impl Hashable for Step {
@@ -78,7 +131,13 @@ impl Hashable for Step {
 
 impl<T: Hashable> Hashable for Proposal<T> {
     fn hash(&self) -> Hash<Self> {
-        hash![self.height, self.round, self.proposal, self.valid_round]
+        hash![
+            self.height,
+            self.round,
+            self.block,
+            self.parts,
+            self.valid_round
+        ]
     }
 }
 impl<T> Hashable for Prevote<T> {
@@ -92,8 +151,45 @@ impl<T> Hashable for Precommit<T> {
     }
 }
 
+impl<T: Hashable> Signable for Proposal<T> {
+    const DOMAIN: &'static str = "blockkey/consensus/proposal";
+}
+
+// prevotes and precommits hash the same, so only their domains tell them apart
+impl<T> Signable for Prevote<T> {
+    const DOMAIN: &'static str = "blockkey/consensus/prevote";
+}
+
+impl<T> Signable for Precommit<T> {
+    const DOMAIN: &'static str = "blockkey/consensus/precommit";
+}
+
+#[derive(Debug)]
 pub enum Error {
     NotImplemented,
     OutgoingClosed,
     IncomingClosed,
+    /// The write-ahead log could not be written, so consensus can't safely continue.
+    WalFailed(std::io::Error),
+    /// The write-ahead log is at a later height than the app, which must have lost blocks
+    /// it committed. Starting from the app's height could sign conflicting messages.
+    WalAhead {
+        wal: u64,
+        app: u64,
+    },
+    /// The app gave validators whose total weight is more than `MAX_TOTAL_WEIGHT`.
+    TooMuchWeight,
+    /// The app gave a validator set with no weight, or removed every validator.
+    NoValidators,
+    /// The app couldn't commit a decided block.
+    CommitFailed(CommitError),
+}
+
+impl From<InvalidValidators> for Error {
+    fn from(error: InvalidValidators) -> Self {
+        match error {
+            InvalidValidators::TooMuchWeight => Error::TooMuchWeight,
+            InvalidValidators::NoValidators => Error::NoValidators,
+        }
+    }
 }
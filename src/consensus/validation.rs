@@ -0,0 +1,289 @@
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    consensus::{App, Broadcast, Tendermint},
+    crypto::hashing::Hashable,
+};
+
+/// How many rounds past the current one messages are accepted for. Validators move on
+/// by timing out, or skip to a round that more than f of the weight has sent messages
+/// for, which some correct validator must already have reached. So the rounds of
+/// correct validators only drift apart one timeout at a time, and never this far.
+pub const MAX_ROUNDS_AHEAD: u64 = 1000;
+
+/// The reason a message was refused before reaching the message log.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Rejection {
+    /// The signature does not match the signee and content.
+    InvalidSignature,
+    /// The signee has no voting weight.
+    NotValidator,
+    /// The message is for a past height, or too far in the future to be stored.
+    WrongHeight,
+    /// The message is for a round more than `MAX_ROUNDS_AHEAD` past the current one.
+    WrongRound,
+    /// The proposal or block part was not signed by the proposer for its round.
+    NotProposer,
+    /// The proposal's valid round is not before its round.
+    MalformedProposal,
+    /// The block part's proof doesn't match the header it claims to belong to.
+    MalformedPart,
+}
+
+/// A message that failed validation, along with the reason.
+pub struct Rejected<B: Hashable> {
+    pub message: Broadcast<B>,
+    pub reason: Rejection,
+}
+
+impl<A: App<B>, B: Hashable + Clone + Eq + Serialize + DeserializeOwned> Tendermint<A, B> {
+    /// Checks that a message is signed by a validator and fits in the message log.
+    pub fn validate(&self, broadcast: &Broadcast<B>) -> Result<(), Rejection> {
+        let height = broadcast.height();
+        // the log only makes room for heights whose validators are known
+        if !self.log.accepts(height) {
+            return Err(Rejection::WrongHeight);
+        }
+        let signee = broadcast.signee().hash();
+        if self.voting_weight(height, signee) == 0 {
+            return Err(Rejection::NotValidator);
+        }
+
+        if broadcast.round() > self.current.round.saturating_add(MAX_ROUNDS_AHEAD) {
+            return Err(Rejection::WrongRound);
+        }
+        // checked before the proposer, so that only validators can make us look one up
+        if !broadcast.verify(self.app.chain_id()) {
+            return Err(Rejection::InvalidSignature);
+        }
+
+        match broadcast {
+            Broadcast::Proposal(contract) => {
+                let proposal = &contract.content;
+                if self.proposer_at(proposal.height, proposal.round) != signee {
+                    return Err(Rejection::NotProposer);
+                }
+                if matches!(proposal.valid_round, Some(vr) if vr >= proposal.round) {
+                    return Err(Rejection::MalformedProposal);
+                }
+            }
+            Broadcast::Part(contract) => {
+                let part = &contract.content;
+                if self.proposer_at(part.height, part.round) != signee {
+                    return Err(Rejection::NotProposer);
+                }
+                if !part.verify() {
+                    return Err(Rejection::MalformedPart);
+                }
+            }
+            Broadcast::Prevote(_) | Broadcast::Precommit(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Reports a message that failed validation. If the report can't be
+    /// delivered immediately, it is dropped rather than stalling consensus.
+    pub fn reject(&self, message: Broadcast<B>, reason: Rejection) {
+        let _ = self.rejected.try_send(Rejected { message, reason });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        consensus::{
+            parts::propose,
+            simulation::{SimApp, SimBlock, Simulation, CHAIN_ID},
+            Channels, ConsensusConfig, Events, Handle, PartsHeader, Precommit, Prevote, Proposal,
+            Wal,
+        },
+        crypto::contracts::PrivateKey,
+    };
+
+    fn node(app: SimApp) -> Tendermint<SimApp, SimBlock> {
+        let (_, incoming) = mpsc::channel(1);
+        let (outgoing, _) = mpsc::channel(1);
+        let (rejected, _) = mpsc::channel(1);
+        let (_, sync_incoming) = mpsc::channel(1);
+        let (sync_outgoing, _) = mpsc::channel(1);
+        // validation never writes to the WAL, so the directory needn't outlive it
+        let wal = Wal::open(tempfile::tempdir().unwrap().path().join("wal")).unwrap();
+        let channels = Channels {
+            incoming,
+            outgoing,
+            rejected,
+            sync_incoming,
+            sync_outgoing,
+            events: Events::new(1),
+            controls: Handle::new().1,
+        };
+        Tendermint::new(app, ConsensusConfig::default(), wal, channels).unwrap()
+    }
+
+    /// Returns the index of the proposer for round 0 of height 0.
+    fn first_proposer(apps: &[SimApp]) -> usize {
+        let proposer = apps[0].proposer(0, 0);
+        apps.iter().position(|app| app.id() == proposer).unwrap()
+    }
+
+    fn proposal(height: u64, round: u64, valid_round: Option<u64>) -> Proposal<SimBlock> {
+        let block = SimBlock {
+            height,
+            proposer: 0,
+            last_commit: None,
+        };
+        Proposal {
+            height,
+            round,
+            block: block.hash(),
+            parts: PartsHeader {
+                total: 1,
+                root: block.hash().cast(),
+            },
+            valid_round,
+        }
+    }
+
+    #[test]
+    fn accepts_valid_messages() {
+        let apps = Simulation::new(4, 0).apps();
+        let tendermint = node(apps[1].clone());
+
+        let messages = [
+            Broadcast::Proposal(apps[first_proposer(&apps)].sign(proposal(0, 0, None))),
+            Broadcast::Prevote(apps[2].sign(Prevote::new(0, 0, None))),
+            Broadcast::Precommit(apps[3].sign(Precommit::new(1, 7, None))),
+        ];
+        for message in messages.iter() {
+            assert_eq!(tendermint.validate(message), Ok(()));
+        }
+    }
+
+    #[test]
+    fn rejects_forged_signature() {
+        let apps = Simulation::new(4, 0).apps();
+        let tendermint = node(apps[0].clone());
+
+        let mut vote = apps[1].sign(Prevote::new(0, 0, None));
+        vote.signee = apps[2].sign(Prevote::<SimBlock>::new(0, 0, None)).signee;
+        assert_eq!(
+            tendermint.validate(&Broadcast::Prevote(vote)),
+            Err(Rejection::InvalidSignature)
+        );
+
+        let mut vote = apps[1].sign(Precommit::new(0, 0, None));
+        vote.content.round = 1;
+        assert_eq!(
+            tendermint.validate(&Broadcast::Precommit(vote)),
+            Err(Rejection::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn rejects_non_validator() {
+        let apps = Simulation::new(4, 0).apps();
+        let tendermint = node(apps[0].clone());
+
+        let vote = PrivateKey::generate().sign(CHAIN_ID, Prevote::new(0, 0, None));
+        assert_eq!(
+            tendermint.validate(&Broadcast::Prevote(vote)),
+            Err(Rejection::NotValidator)
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_height() {
+        let apps = Simulation::new(4, 0).apps();
+        let mut tendermint = node(apps[0].clone());
+        tendermint.height = 3;
+        tendermint.log.set_height(3);
+
+        let old = apps[1].sign(Prevote::new(2, 0, None));
+        let distant = apps[1].sign(Prevote::new(100, 0, None));
+        assert_eq!(
+            tendermint.validate(&Broadcast::Prevote(old)),
+            Err(Rejection::WrongHeight)
+        );
+        assert_eq!(
+            tendermint.validate(&Broadcast::Prevote(distant)),
+            Err(Rejection::WrongHeight)
+        );
+    }
+
+    #[test]
+    fn rejects_distant_rounds() {
+        let apps = Simulation::new(4, 0).apps();
+        let tendermint = node(apps[1].clone());
+
+        // the proposer is never looked up, or this would take forever
+        let proposer = first_proposer(&apps);
+        let distant = apps[proposer].sign(proposal(0, u64::MAX, None));
+        assert_eq!(
+            tendermint.validate(&Broadcast::Proposal(distant)),
+            Err(Rejection::WrongRound)
+        );
+        let vote = apps[2].sign(Prevote::new(0, MAX_ROUNDS_AHEAD + 1, None));
+        assert_eq!(
+            tendermint.validate(&Broadcast::Prevote(vote)),
+            Err(Rejection::WrongRound)
+        );
+
+        let furthest = apps[proposer].sign(proposal(0, MAX_ROUNDS_AHEAD, None));
+        assert_ne!(
+            tendermint.validate(&Broadcast::Proposal(furthest)),
+            Err(Rejection::WrongRound)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_proposals() {
+        let apps = Simulation::new(4, 0).apps();
+        let tendermint = node(apps[1].clone());
+
+        let proposer = first_proposer(&apps);
+        let wrong_proposer = apps[(proposer + 1) % 4].sign(proposal(0, 0, None));
+        let future_round = apps[proposer].sign(proposal(0, 0, Some(0)));
+        assert_eq!(
+            tendermint.validate(&Broadcast::Proposal(wrong_proposer)),
+            Err(Rejection::NotProposer)
+        );
+        assert_eq!(
+            tendermint.validate(&Broadcast::Proposal(future_round)),
+            Err(Rejection::MalformedProposal)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_parts() {
+        let apps = Simulation::new(4, 0).apps();
+        let tendermint = node(apps[1].clone());
+
+        let proposer = first_proposer(&apps);
+        let block = SimBlock {
+            height: 0,
+            proposer,
+            last_commit: None,
+        };
+        let (_, parts) = propose(&apps[proposer], 0, 0, &block, None);
+        let (_, wrong_proposer) = propose(&apps[(proposer + 1) % 4], 0, 0, &block, None);
+        let mut tampered = parts[0].content.clone();
+        tampered.bytes.push(0);
+        let tampered = apps[proposer].sign(tampered);
+
+        assert_eq!(
+            tendermint.validate(&Broadcast::Part(parts[0].clone())),
+            Ok(())
+        );
+        assert_eq!(
+            tendermint.validate(&Broadcast::Part(wrong_proposer[0].clone())),
+            Err(Rejection::NotProposer)
+        );
+        assert_eq!(
+            tendermint.validate(&Broadcast::Part(tampered)),
+            Err(Rejection::MalformedPart)
+        );
+    }
+}
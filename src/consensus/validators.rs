@@ -0,0 +1,243 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::{contracts::PublicKey, hashing::Hash};
+
+use super::MAX_TOTAL_WEIGHT;
+
+/// Updates returned by committing the block at height h take effect at height
+/// h + UPDATE_DELAY, so the validators for the next height are always known.
+pub const UPDATE_DELAY: u64 = 2;
+
+/// A change to a validator's voting weight. A weight of zero removes the validator.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct ValidatorUpdate {
+    pub validator: Hash<PublicKey>,
+    pub weight: u64,
+}
+
+/// The voting weight of each validator at a single height.
+pub type Validators = HashMap<Hash<PublicKey>, u64>;
+
+/// Why a validator set can't be used.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InvalidValidators {
+    /// The total weight is more than `MAX_TOTAL_WEIGHT`, so that votes couldn't be
+    /// counted or proposers chosen without overflowing.
+    TooMuchWeight,
+    /// No validator has any weight, so no one could propose or vote.
+    NoValidators,
+}
+
+/// Returns the total weight of the validators, if it isn't too large or zero.
+pub fn total_weight(validators: &Validators) -> Result<u64, InvalidValidators> {
+    let total = validators
+        .values()
+        .try_fold(0u64, |total, weight| total.checked_add(*weight))
+        .filter(|total| *total <= MAX_TOTAL_WEIGHT)
+        .ok_or(InvalidValidators::TooMuchWeight)?;
+    if total == 0 {
+        return Err(InvalidValidators::NoValidators);
+    }
+    Ok(total)
+}
+
+/// Returns the validators after applying the given updates, or an error if their
+/// total weight would be too large, or they would all be removed.
+pub fn apply_updates(
+    validators: &Validators,
+    updates: &[ValidatorUpdate],
+) -> Result<Validators, InvalidValidators> {
+    let mut validators = validators.clone();
+    for update in updates {
+        if update.weight == 0 {
+            validators.remove(&update.validator);
+        } else {
+            validators.insert(update.validator, update.weight);
+        }
+    }
+    total_weight(&validators)?;
+    Ok(validators)
+}
+
+/// The validator sets for the current height and the heights that are already
+/// determined after it.
+#[derive(Default)]
+pub struct ValidatorSets {
+    sets: BTreeMap<u64, (Validators, u64)>,
+}
+
+impl ValidatorSets {
+    pub fn new() -> ValidatorSets {
+        ValidatorSets {
+            sets: BTreeMap::new(),
+        }
+    }
+
+    /// Replaces the known sets with those for the given height and the
+    /// UPDATE_DELAY - 1 heights after it. Fails if any of them is too large or empty.
+    pub fn load(
+        &mut self,
+        height: u64,
+        get: impl Fn(u64) -> Validators,
+    ) -> Result<(), InvalidValidators> {
+        self.sets.clear();
+        for h in height..height + UPDATE_DELAY {
+            self.insert(h, get(h))?;
+        }
+        Ok(())
+    }
+
+    /// Applies the updates from committing the given height, and forgets that height.
+    /// Fails, leaving the sets unchanged, if the updated set would be too large or empty.
+    pub fn commit(
+        &mut self,
+        height: u64,
+        updates: &[ValidatorUpdate],
+    ) -> Result<(), InvalidValidators> {
+        let next = self
+            .sets
+            .values()
+            .next_back()
+            .map(|(validators, _)| apply_updates(validators, updates))
+            .transpose()?;
+        if let Some(next) = next {
+            self.insert(height + UPDATE_DELAY, next)?;
+        }
+        self.sets = self.sets.split_off(&(height + 1));
+        Ok(())
+    }
+
+    /// Returns the validators for the given height, if they are known.
+    pub fn get(&self, height: u64) -> Option<&Validators> {
+        self.sets.get(&height).map(|(validators, _)| validators)
+    }
+
+    /// Returns the voting weight of a validator at the given height, or zero if unknown.
+    pub fn weight(&self, height: u64, id: &Hash<PublicKey>) -> u64 {
+        self.get(height)
+            .and_then(|validators| validators.get(id))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Returns the total voting weight at the given height, or zero if unknown.
+    pub fn total(&self, height: u64) -> u64 {
+        self.sets.get(&height).map_or(0, |(_, total)| *total)
+    }
+
+    /// Returns the latest height with a known validator set.
+    pub fn last(&self) -> Option<u64> {
+        self.sets.keys().next_back().copied()
+    }
+
+    fn insert(&mut self, height: u64, validators: Validators) -> Result<(), InvalidValidators> {
+        let total = total_weight(&validators)?;
+        self.sets.insert(height, (validators, total));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{contracts::PrivateKey, hashing::Hashable};
+
+    #[test]
+    fn updates_take_effect_later() {
+        let keys: Vec<Hash<PublicKey>> = (0..3)
+            .map(|_| PrivateKey::generate().get_public().hash())
+            .collect();
+        let genesis: Validators = keys[..2].iter().map(|k| (*k, 1)).collect();
+
+        let mut sets = ValidatorSets::new();
+        sets.load(0, |_| genesis.clone()).unwrap();
+        assert_eq!(sets.total(0), 2);
+        assert_eq!(sets.total(1), 2);
+        assert!(sets.get(2).is_none());
+
+        let updates = [
+            ValidatorUpdate {
+                validator: keys[0],
+                weight: 0,
+            },
+            ValidatorUpdate {
+                validator: keys[2],
+                weight: 5,
+            },
+        ];
+        sets.commit(0, &updates).unwrap();
+        assert!(sets.get(0).is_none());
+        assert_eq!(sets.weight(1, &keys[0]), 1);
+        assert_eq!(sets.weight(2, &keys[0]), 0);
+        assert_eq!(sets.weight(2, &keys[2]), 5);
+        assert_eq!(sets.total(2), 6);
+
+        // no updates, so the set carries over
+        sets.commit(1, &[]).unwrap();
+        assert_eq!(sets.get(3), sets.get(2));
+        assert_eq!(sets.last(), Some(3));
+    }
+
+    #[test]
+    fn rejects_sets_with_too_much_weight() {
+        let keys: Vec<Hash<PublicKey>> = (0..2)
+            .map(|_| PrivateKey::generate().get_public().hash())
+            .collect();
+        let mut sets = ValidatorSets::new();
+        let too_large = |_| [(keys[0], MAX_TOTAL_WEIGHT + 1)].iter().cloned().collect();
+        assert_eq!(
+            sets.load(0, too_large),
+            Err(InvalidValidators::TooMuchWeight)
+        );
+
+        let genesis: Validators = [(keys[0], MAX_TOTAL_WEIGHT)].iter().cloned().collect();
+        sets.load(0, |_| genesis.clone()).unwrap();
+        let update = ValidatorUpdate {
+            validator: keys[1],
+            weight: 1,
+        };
+        assert_eq!(
+            sets.commit(0, &[update]),
+            Err(InvalidValidators::TooMuchWeight)
+        );
+        assert_eq!(sets.last(), Some(1));
+        assert!(sets.get(0).is_some());
+
+        // replacing the weight rather than adding to it is fine
+        let update = ValidatorUpdate {
+            validator: keys[0],
+            weight: MAX_TOTAL_WEIGHT - 1,
+        };
+        sets.commit(0, &[update]).unwrap();
+        assert_eq!(sets.total(2), MAX_TOTAL_WEIGHT - 1);
+    }
+
+    #[test]
+    fn rejects_empty_sets() {
+        let key = PrivateKey::generate().get_public().hash();
+        let mut sets = ValidatorSets::new();
+        assert_eq!(
+            sets.load(0, |_| Validators::new()),
+            Err(InvalidValidators::NoValidators)
+        );
+        let unweighted = |_| [(key, 0)].iter().cloned().collect();
+        assert_eq!(
+            sets.load(0, unweighted),
+            Err(InvalidValidators::NoValidators)
+        );
+
+        let genesis: Validators = [(key, 1)].iter().cloned().collect();
+        sets.load(0, |_| genesis.clone()).unwrap();
+        let removed = ValidatorUpdate {
+            validator: key,
+            weight: 0,
+        };
+        assert_eq!(
+            sets.commit(0, &[removed]),
+            Err(InvalidValidators::NoValidators)
+        );
+        assert_eq!(sets.last(), Some(1));
+    }
+}
@@ -0,0 +1,407 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::crypto::hashing::Hashable;
+
+use super::{Broadcast, Record, RoundState};
+
+/// The state needed to resume consensus after a restart.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub(super) struct Checkpoint<B> {
+    pub height: u64,
+    pub current: RoundState,
+    pub locked: Option<Record<B>>,
+    pub valid: Option<Record<B>>,
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Serialize, Deserialize)]
+#[serde(bound(serialize = "B: Serialize", deserialize = "B: DeserializeOwned"))]
+enum Entry<B: Hashable> {
+    State(Checkpoint<B>),
+    /// A message signed by this node, written before it is broadcast.
+    Signed(Broadcast<B>),
+    /// A block this node proposed, written before the proposal is signed.
+    Proposed {
+        round: u64,
+        block: B,
+        valid_round: Option<u64>,
+    },
+}
+
+/// Identifies the step a message was signed for: (height, round, message type, part index).
+type Slot = (u64, u64, u8, u64);
+
+fn slot<B: Hashable>(broadcast: &Broadcast<B>) -> Slot {
+    match broadcast {
+        Broadcast::Proposal(c) => (c.content.height, c.content.round, 0, 0),
+        Broadcast::Prevote(c) => (c.content.height, c.content.round, 1, 0),
+        Broadcast::Precommit(c) => (c.content.height, c.content.round, 2, 0),
+        Broadcast::Part(c) => (c.content.height, c.content.round, 3, c.content.index),
+    }
+}
+
+/// A write-ahead log of consensus state and signed messages, stored as
+/// one JSON entry per line. Only the current height is kept.
+pub struct Wal<B: Hashable> {
+    path: PathBuf,
+    file: File,
+    checkpoint: Option<Checkpoint<B>>,
+    signed: HashMap<Slot, Broadcast<B>>,
+    /// Messages in the order they were signed.
+    order: Vec<Slot>,
+    /// The blocks proposed by this node and their valid rounds, by round.
+    proposed: HashMap<u64, (B, Option<u64>)>,
+    /// Simulates a crash by failing after the given number of further writes.
+    #[cfg(test)]
+    pub(super) crash_after: Option<usize>,
+}
+
+impl<B: Hashable + Clone + PartialEq + Serialize + DeserializeOwned> Wal<B> {
+    /// Opens the log at the given path, creating it if it doesn't exist.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Wal<B>> {
+        let path = path.as_ref().to_path_buf();
+        let mut wal = Wal {
+            file: OpenOptions::new().create(true).append(true).open(&path)?,
+            path,
+            checkpoint: None,
+            signed: HashMap::new(),
+            order: Vec::new(),
+            proposed: HashMap::new(),
+            #[cfg(test)]
+            crash_after: None,
+        };
+
+        let contents = fs::read(&wal.path)?;
+        let mut length = 0;
+        for line in contents.split_inclusive(|byte| *byte == b'\n') {
+            // an unterminated entry means that the node crashed while writing it
+            let entry = match line.strip_suffix(b"\n") {
+                Some(entry) => entry,
+                None => break,
+            };
+            // but a complete one that can't be read is corruption. Discarding it and the
+            // entries after it would forget messages this node signed, and let it sign
+            // conflicting ones.
+            let entry = serde_json::from_slice(entry).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("corrupt entry in the write-ahead log: {}", e),
+                )
+            })?;
+            match entry {
+                Entry::State(checkpoint) => wal.checkpoint = Some(checkpoint),
+                Entry::Signed(broadcast) => wal.remember(broadcast),
+                Entry::Proposed {
+                    round,
+                    block,
+                    valid_round,
+                } => {
+                    wal.proposed.insert(round, (block, valid_round));
+                }
+            }
+            length += line.len();
+        }
+        // discard the partial entry, so that new entries start on a new line
+        wal.file.set_len(length as u64)?;
+        Ok(wal)
+    }
+
+    /// Returns the most recently written checkpoint.
+    pub(super) fn checkpoint(&self) -> Option<&Checkpoint<B>> {
+        self.checkpoint.as_ref()
+    }
+
+    /// Returns the messages signed at the current height, in the order they were signed.
+    pub(super) fn signed(&self) -> impl Iterator<Item = &Broadcast<B>> {
+        self.order.iter().map(move |slot| &self.signed[slot])
+    }
+
+    /// Returns the block proposed in the given round of the current height, if any,
+    /// and its valid round.
+    pub(super) fn proposed(&self, round: u64) -> Option<(&B, Option<u64>)> {
+        self.proposed
+            .get(&round)
+            .map(|(block, valid_round)| (block, *valid_round))
+    }
+
+    /// Records a block before it is proposed. The proposal and its parts are signed one
+    /// at a time, so after a restart the same block must be proposed again, for the parts
+    /// signed since to match those signed before.
+    pub(super) fn propose(
+        &mut self,
+        round: u64,
+        block: B,
+        valid_round: Option<u64>,
+    ) -> io::Result<()> {
+        if self.proposed.contains_key(&round) {
+            return Ok(());
+        }
+        self.write(&Entry::Proposed {
+            round,
+            block: block.clone(),
+            valid_round,
+        })?;
+        self.proposed.insert(round, (block, valid_round));
+        Ok(())
+    }
+
+    /// Writes a checkpoint, unless it is identical to the last one.
+    pub(super) fn save(&mut self, checkpoint: Checkpoint<B>) -> io::Result<()> {
+        if self.checkpoint.as_ref() != Some(&checkpoint) {
+            self.write(&Entry::State(checkpoint.clone()))?;
+            self.checkpoint = Some(checkpoint);
+        }
+        Ok(())
+    }
+
+    /// Records a message before it is broadcast. If a message has already been signed for
+    /// the same step, that message is returned instead, so that the node never equivocates.
+    pub(super) fn sign(&mut self, broadcast: Broadcast<B>) -> io::Result<Broadcast<B>> {
+        if let Some(existing) = self.signed.get(&slot(&broadcast)) {
+            return Ok(existing.clone());
+        }
+        self.write(&Entry::Signed(broadcast.clone()))?;
+        self.remember(broadcast.clone());
+        Ok(broadcast)
+    }
+
+    /// Discards everything from previous heights, replacing the log with a single checkpoint.
+    /// Must only be called once the app has committed those heights.
+    pub(super) fn start_height(&mut self, checkpoint: Checkpoint<B>) -> io::Result<()> {
+        // write to a new file and rename it, so that a crash leaves one log or the other
+        let temp = self.path.with_extension("tmp");
+        let mut file = File::create(&temp)?;
+        file.write_all(serialize(&Entry::State(checkpoint.clone()))?.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp, &self.path)?;
+        // the rename only survives a crash once the directory is synced too
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.checkpoint = Some(checkpoint);
+        self.signed.clear();
+        self.order.clear();
+        self.proposed.clear();
+        self.crash_point()
+    }
+
+    fn remember(&mut self, broadcast: Broadcast<B>) {
+        let slot = slot(&broadcast);
+        if self.signed.insert(slot, broadcast).is_none() {
+            self.order.push(slot);
+        }
+    }
+
+    fn write(&mut self, entry: &Entry<B>) -> io::Result<()> {
+        self.file.write_all(serialize(entry)?.as_bytes())?;
+        self.file.sync_data()?;
+        self.crash_point()
+    }
+
+    #[cfg(test)]
+    fn crash_point(&mut self) -> io::Result<()> {
+        match self.crash_after {
+            Some(0) => Err(io::Error::other("simulated crash")),
+            Some(n) => {
+                self.crash_after = Some(n - 1);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    #[cfg(not(test))]
+    fn crash_point(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn serialize<B: Hashable + Serialize>(entry: &Entry<B>) -> io::Result<String> {
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    Ok(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        consensus::{
+            simulation::{SimBlock, Simulation, CHAIN_ID},
+            ConsensusConfig, Error, Prevote, Step,
+        },
+        crypto::contracts::PrivateKey,
+        testing::lone_validator,
+    };
+
+    fn checkpoint(height: u64, round: u64) -> Checkpoint<u64> {
+        Checkpoint {
+            height,
+            current: RoundState {
+                round,
+                step: Step::prevote(),
+                precommit_timeout_scheduled: false,
+                valid_updated: true,
+            },
+            locked: Some(Record { value: 7, round }),
+            valid: None,
+        }
+    }
+
+    #[test]
+    fn replays_after_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("consensus.wal");
+        let key = PrivateKey::generate();
+
+        let mut wal = Wal::open(&path).unwrap();
+        wal.start_height(checkpoint(3, 0)).unwrap();
+        wal.sign(Broadcast::Prevote(
+            key.sign(CHAIN_ID, Prevote::new(3, 0, None)),
+        ))
+        .unwrap();
+        wal.save(checkpoint(3, 1)).unwrap();
+        drop(wal);
+
+        let wal = Wal::<u64>::open(&path).unwrap();
+        assert!(wal.checkpoint() == Some(&checkpoint(3, 1)));
+        assert_eq!(wal.signed().count(), 1);
+    }
+
+    #[test]
+    fn never_signs_twice_for_a_step() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("consensus.wal");
+        let key = PrivateKey::generate();
+        let id = Some(7u64.hash());
+
+        let mut wal = Wal::open(&path).unwrap();
+        let first = Broadcast::Prevote(key.sign(CHAIN_ID, Prevote::new(0, 0, id)));
+        wal.sign(first.clone()).unwrap();
+        drop(wal);
+
+        let mut wal = Wal::open(&path).unwrap();
+        let second = Broadcast::Prevote(key.sign(CHAIN_ID, Prevote::new(0, 0, None)));
+        assert!(wal.sign(second).unwrap() == first);
+
+        let next_round = Broadcast::Prevote(key.sign(CHAIN_ID, Prevote::new(0, 1, None)));
+        assert!(wal.sign(next_round.clone()).unwrap() == next_round);
+    }
+
+    #[test]
+    fn remembers_proposed_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("consensus.wal");
+
+        let mut wal = Wal::open(&path).unwrap();
+        wal.start_height(checkpoint(1, 0)).unwrap();
+        wal.propose(0, 7u64, None).unwrap();
+        wal.propose(2, 8u64, Some(1)).unwrap();
+        // a round's block is never replaced
+        wal.propose(2, 9u64, None).unwrap();
+        drop(wal);
+
+        let mut wal = Wal::<u64>::open(&path).unwrap();
+        assert_eq!(wal.proposed(0), Some((&7, None)));
+        assert_eq!(wal.proposed(1), None);
+        assert_eq!(wal.proposed(2), Some((&8, Some(1))));
+
+        wal.start_height(checkpoint(2, 0)).unwrap();
+        assert_eq!(wal.proposed(0), None);
+    }
+
+    #[test]
+    fn new_height_discards_old_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("consensus.wal");
+        let key = PrivateKey::generate();
+
+        let mut wal = Wal::open(&path).unwrap();
+        wal.sign(Broadcast::Prevote(
+            key.sign(CHAIN_ID, Prevote::new(0, 0, None)),
+        ))
+        .unwrap();
+        wal.start_height(checkpoint(1, 0)).unwrap();
+        drop(wal);
+
+        let wal = Wal::<u64>::open(&path).unwrap();
+        assert!(wal.checkpoint() == Some(&checkpoint(1, 0)));
+        assert_eq!(wal.signed().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn refuses_to_start_behind_the_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("consensus.wal");
+        let ahead = Checkpoint::<SimBlock> {
+            height: 3,
+            current: RoundState::new(1),
+            locked: None,
+            valid: None,
+        };
+        let mut wal = Wal::open(&path).unwrap();
+        wal.start_height(ahead.clone()).unwrap();
+        drop(wal);
+
+        // an app that lost its committed blocks, and starts over from genesis
+        let app = Simulation::new(1, 0).apps().remove(0);
+        let (_handle, node) = lone_validator(app, ConsensusConfig::default(), &path);
+        let result = node.await.unwrap();
+        assert!(matches!(result, Err(Error::WalAhead { wal: 3, app: 0 })));
+        let wal = Wal::<SimBlock>::open(&path).unwrap();
+        assert!(wal.checkpoint() == Some(&ahead));
+    }
+
+    #[test]
+    fn refuses_to_open_a_corrupt_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("consensus.wal");
+        let key = PrivateKey::generate();
+
+        let mut wal = Wal::open(&path).unwrap();
+        wal.save(checkpoint(2, 0)).unwrap();
+        wal.file.write_all(b"{\"State\":{\"hei\n").unwrap();
+        wal.sign(Broadcast::Prevote(
+            key.sign(CHAIN_ID, Prevote::new(2, 0, None)),
+        ))
+        .unwrap();
+        drop(wal);
+        let contents = fs::read(&path).unwrap();
+
+        let error = Wal::<u64>::open(&path).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        // the vote signed after the corrupt entry isn't forgotten
+        assert_eq!(fs::read(&path).unwrap(), contents);
+    }
+
+    #[test]
+    fn ignores_torn_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("consensus.wal");
+
+        let mut wal = Wal::open(&path).unwrap();
+        wal.save(checkpoint(2, 0)).unwrap();
+        wal.file.write_all(b"{\"State\":{\"hei").unwrap();
+        drop(wal);
+
+        let mut wal = Wal::<u64>::open(&path).unwrap();
+        assert!(wal.checkpoint() == Some(&checkpoint(2, 0)));
+
+        // entries written after recovering are kept
+        wal.save(checkpoint(2, 1)).unwrap();
+        drop(wal);
+        let wal = Wal::<u64>::open(&path).unwrap();
+        assert!(wal.checkpoint() == Some(&checkpoint(2, 1)));
+    }
+}
@@ -1,9 +1,9 @@
 use crate::crypto::hashing::{Hash, Hashable};
 use ed25519_dalek::{Signature, Signer, Verifier};
 use serde::{Deserialize, Serialize};
-use std::time::SystemTime;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub struct PublicKey {
     key: ed25519_dalek::PublicKey,
 }
@@ -34,9 +34,14 @@ pub struct PrivateKey {
 
 impl PrivateKey {
     pub fn generate() -> Self {
-        let mut csprng = rand::rngs::OsRng;
+        Self::from_rng(&mut rand::rngs::OsRng)
+    }
+
+    /// Generates a key from the given source of randomness, allowing
+    /// reproducible keys when a seeded generator is used.
+    pub fn from_rng<R: rand::CryptoRng + rand::RngCore>(csprng: &mut R) -> Self {
         PrivateKey {
-            keypair: ed25519_dalek::Keypair::generate(&mut csprng),
+            keypair: ed25519_dalek::Keypair::generate(csprng),
         }
     }
 
@@ -46,10 +51,25 @@ impl PrivateKey {
         }
     }
 
-    pub fn sign<T: Hashable>(&self, content: T) -> Contract<T> {
-        let timestamp = SystemTime::now().elapsed().unwrap().as_millis();
-        let mut bytes_to_sign = content.hash().get_bytes().to_vec();
-        bytes_to_sign.extend(timestamp.to_be_bytes().iter());
+    /// Signs the content for the given chain, timestamped with the milliseconds since
+    /// the Unix epoch.
+    pub fn sign<T: Signable>(&self, chain_id: &str, content: T) -> Contract<T> {
+        self.sign_at(chain_id, content, now())
+    }
+
+    /// Signs the content with the current time, or one millisecond after `earliest`
+    /// if the clock is behind it.
+    pub fn sign_after<T: Signable>(
+        &self,
+        chain_id: &str,
+        content: T,
+        earliest: u128,
+    ) -> Contract<T> {
+        self.sign_at(chain_id, content, now().max(earliest + 1))
+    }
+
+    pub fn sign_at<T: Signable>(&self, chain_id: &str, content: T, timestamp: u128) -> Contract<T> {
+        let bytes_to_sign = signed_bytes(chain_id, &content, timestamp);
 
         Contract {
             signee: self.get_public(),
@@ -64,7 +84,32 @@ impl PrivateKey {
     }
 }
 
-#[derive(PartialEq, Eq, Serialize, Deserialize)]
+/// Returns the milliseconds since the Unix epoch, or 0 if the clock is set before it.
+fn now() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis())
+}
+
+/// Content that can be signed. Signatures cover the type's domain and the chain id
+/// as well as the content, so that a signature for one kind of message can't be passed
+/// off as another, or replayed on another chain.
+pub trait Signable: Hashable {
+    /// Names the kind of content, such as "blockkey/consensus/prevote".
+    const DOMAIN: &'static str;
+}
+
+/// Returns the bytes signed for a contract.
+fn signed_bytes<T: Signable>(chain_id: &str, content: &T, timestamp: u128) -> Vec<u8> {
+    // hashed, so that the variable length strings can't run into each other
+    let mut bytes = T::DOMAIN.as_bytes().hash().get_bytes().to_vec();
+    bytes.extend_from_slice(chain_id.as_bytes().hash().get_bytes());
+    bytes.extend_from_slice(content.hash().get_bytes());
+    bytes.extend(timestamp.to_be_bytes().iter());
+    bytes
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub struct Contract<T: Hashable> {
     pub signee: PublicKey,
     signature: Signature,
@@ -72,10 +117,10 @@ pub struct Contract<T: Hashable> {
     pub content: T,
 }
 
-impl<T: Hashable> Contract<T> {
-    pub fn verify(&self) -> bool {
-        let mut bytes_to_sign = self.content.hash().get_bytes().to_vec();
-        bytes_to_sign.extend(self.timestamp.to_be_bytes().iter());
+impl<T: Signable> Contract<T> {
+    /// Checks that the contract was signed for the given chain.
+    pub fn verify(&self, chain_id: &str) -> bool {
+        let bytes_to_sign = signed_bytes(chain_id, &self.content, self.timestamp);
 
         self.signee.verify_bytes(&bytes_to_sign, &self.signature)
     }
@@ -91,36 +136,96 @@ impl<T: Hashable> Hashable for Contract<T> {
 mod tests {
     use super::*;
 
+    const CHAIN: &str = "test";
+
+    impl Signable for i32 {
+        const DOMAIN: &'static str = "blockkey/test/i32";
+    }
+
+    /// Hashes the same as the i32 inside it, but is signed in another domain.
+    #[derive(Debug)]
+    struct Other(i32);
+
+    impl Hashable for Other {
+        fn hash(&self) -> Hash<Self> {
+            self.0.hash().cast()
+        }
+    }
+
+    impl Signable for Other {
+        const DOMAIN: &'static str = "blockkey/test/other";
+    }
+
     #[test]
     fn test_correct_contract() {
         let private = PrivateKey::generate();
         let message = 123;
 
-        let contract = private.sign(message);
+        let contract = private.sign(CHAIN, message);
 
-        assert!(contract.verify());
+        assert!(contract.verify(CHAIN));
     }
 
     #[test]
     fn test_tampered_content() {
         let private = PrivateKey::generate();
         let message = 123;
-        let mut contract = private.sign(message);
+        let mut contract = private.sign(CHAIN, message);
 
         contract.content = 321;
 
-        assert!(!contract.verify());
+        assert!(!contract.verify(CHAIN));
     }
 
     #[test]
     fn test_tampered_signee() {
         let private = PrivateKey::generate();
         let message = 123;
-        let mut contract = private.sign(message);
+        let mut contract = private.sign(CHAIN, message);
 
         contract.signee = PrivateKey::generate().get_public();
 
-        assert!(!contract.verify());
+        assert!(!contract.verify(CHAIN));
+    }
+
+    #[test]
+    fn test_other_chain() {
+        let private = PrivateKey::generate();
+        let contract = private.sign(CHAIN, 123);
+
+        assert!(!contract.verify("other"));
+        assert!(!contract.verify(""));
+    }
+
+    #[test]
+    fn test_other_domain() {
+        let private = PrivateKey::generate();
+        let contract = private.sign(CHAIN, 123);
+        let other = Contract {
+            signee: contract.signee.clone(),
+            signature: contract.signature,
+            timestamp: contract.timestamp,
+            content: Other(123),
+        };
+
+        assert_eq!(other.content.hash(), contract.content.hash().cast());
+        assert!(!other.verify(CHAIN));
+    }
+
+    #[test]
+    fn test_timestamp() {
+        let private = PrivateKey::generate();
+        // 2020-01-01
+        assert!(private.sign(CHAIN, 123).timestamp > 1_577_836_800_000);
+
+        let future = now() + 60_000;
+        let contract = private.sign_after(CHAIN, 123, future);
+        assert_eq!(contract.timestamp, future + 1);
+        assert!(contract.verify(CHAIN));
+
+        let mut contract = private.sign_at(CHAIN, 123, 5);
+        contract.timestamp = 6;
+        assert!(!contract.verify(CHAIN));
     }
 
     #[test]
@@ -136,7 +241,7 @@ mod tests {
     fn test_serde_contract_i32() {
         let private = PrivateKey::generate();
         let message: i32 = 123;
-        let original = private.sign(message);
+        let original = private.sign(CHAIN, message);
 
         let serialized = serde_json::to_string(&original).unwrap();
         let deserialized: Contract<i32> = serde_json::from_str(&serialized).unwrap();
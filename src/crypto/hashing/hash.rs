@@ -1,7 +1,6 @@
 use data_encoding::HEXUPPER;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::convert::TryInto;
 use std::fmt;
 use std::marker::PhantomData;
 
@@ -10,7 +9,7 @@ pub struct Hash<T: ?Sized = ()>([u8; 32], PhantomData<T>);
 
 impl<T: ?Sized> Clone for Hash<T> {
     fn clone(&self) -> Self {
-        Hash(self.0, PhantomData)
+        *self
     }
 }
 
@@ -34,8 +33,8 @@ impl Hash {
     pub fn from_bytes(bytes: &[u8]) -> Hash<Vec<u8>> {
         let mut hasher = Sha256::new();
         hasher.update(bytes);
-        let result = hasher.finalize();
-        Hash(result.as_slice().try_into().unwrap(), PhantomData)
+        let result: [u8; 32] = hasher.finalize().into();
+        Hash(result, PhantomData)
     }
 }
 
@@ -51,6 +50,13 @@ impl<T: ?Sized> Hash<T> {
     pub fn cast<H: ?Sized>(&self) -> Hash<H> {
         Hash(self.0, PhantomData)
     }
+
+    /// Parses a hash in the hexadecimal form that `Display` writes, in either case.
+    pub fn parse(hex: &str) -> Option<Hash<T>> {
+        let bytes = HEXUPPER.decode(hex.to_ascii_uppercase().as_bytes()).ok()?;
+        let bytes: [u8; 32] = std::convert::TryFrom::try_from(bytes.as_slice()).ok()?;
+        Some(Hash(bytes, PhantomData))
+    }
 }
 
 impl<T: ?Sized> fmt::Display for Hash<T> {
@@ -175,4 +181,13 @@ mod test {
         let y: Hash = hash![2, 3];
         assert_ne!(x, hash![1, y]);
     }
+
+    #[test]
+    fn parse_display() {
+        let x: Hash = hash![1, 2, 3];
+        assert_eq!(Hash::parse(&x.to_string()), Some(x));
+        assert_eq!(Hash::parse(&x.to_string().to_lowercase()), Some(x));
+        assert_eq!(Hash::<()>::parse(&x.to_string()[2..]), None);
+        assert_eq!(Hash::<()>::parse("not hex"), None);
+    }
 }
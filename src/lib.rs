@@ -1,5 +1,9 @@
 #[macro_use]
 pub mod crypto;
+pub mod block;
 pub mod consensus;
 pub mod network;
 pub mod transactions;
+
+#[cfg(test)]
+mod testing;
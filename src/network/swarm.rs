@@ -1,3 +1,4 @@
+use crate::consensus::MAX_MESSAGE_SIZE;
 use libp2p::{
     core::{muxing, transport, upgrade},
     dns::TokioDnsConfig,
@@ -18,6 +19,7 @@ use std::error::Error;
 use std::hash::{Hash, Hasher};
 use std::time::Duration;
 
+#[allow(clippy::large_enum_variant)]
 pub enum InternalEvent {
     Received {
         peer: PeerId,
@@ -118,6 +120,7 @@ pub async fn construct() -> Result<Swarm<CustomBehaviour>, Box<dyn Error>> {
             .validation_mode(ValidationMode::Strict) // This sets the kind of message validation. The default is Strict (enforce message signing)
             .message_id_fn(message_id_fn) // content-address messages. No two messages of the
             // same content will be propagated.
+            .max_transmit_size(MAX_MESSAGE_SIZE)
             .build()
             .expect("Valid config");
 
@@ -0,0 +1,83 @@
+//! Helpers shared by tests across modules.
+
+use std::path::Path;
+
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::{sync::mpsc, task::JoinHandle};
+
+use crate::{
+    consensus::{App, Channels, ConsensusConfig, Error, Events, Handle, Tendermint, Wal},
+    crypto::{contracts::PrivateKey, hashing::Hashable},
+    transactions::{
+        state::{State, UserState},
+        Transaction, UnsignedCurrencyTransfer,
+    },
+};
+
+/// The chain that test transactions are signed for.
+pub const CHAIN: &str = "test";
+
+/// A transfer that is valid at every height.
+pub fn transfer(from: &PrivateKey, to: &PrivateKey, amount: u64, nonce: u64) -> Transaction {
+    Transaction::CurrencyTransfer(from.sign(
+        CHAIN,
+        UnsignedCurrencyTransfer {
+            amount,
+            recipient: to.get_public().hash(),
+            nonce,
+            valid_until: u64::MAX,
+        },
+    ))
+}
+
+/// A state where each of the given keys has a balance of 100.
+pub fn funded(keys: &[&PrivateKey]) -> State {
+    let user = UserState {
+        balance: 100,
+        ..UserState::default()
+    };
+    State {
+        users: keys
+            .iter()
+            .map(|key| (key.get_public().hash(), user.clone()))
+            .collect(),
+    }
+}
+
+/// Starts a node for an app whose only validator is itself, keeping its WAL at the
+/// given path. Returns the node's handle, and the task running it.
+pub fn lone_validator<A, B>(
+    app: A,
+    config: ConsensusConfig,
+    wal: &Path,
+) -> (Handle<B>, JoinHandle<Result<(), Error>>)
+where
+    A: App<B> + Send + Sync + 'static,
+    B: Hashable + Clone + Eq + Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    let wal = Wal::open(wal).unwrap();
+    let (forward, incoming) = mpsc::channel(64);
+    let (outgoing, mut broadcasts) = mpsc::channel(64);
+    let (rejected, _) = mpsc::channel(1);
+    let (_, sync_incoming) = mpsc::channel(1);
+    let (sync_outgoing, mut sync) = mpsc::channel(1);
+    let (handle, controls) = Handle::new();
+    let channels = Channels {
+        incoming,
+        outgoing,
+        rejected,
+        sync_incoming,
+        sync_outgoing,
+        events: Events::new(1),
+        controls,
+    };
+    let node = tokio::spawn(Tendermint::start(app, config, wal, channels));
+    // a lone validator has no peers to send to or sync from
+    tokio::spawn(async move {
+        // incoming closes once every sender is dropped, which would stop the node
+        let _forward = forward;
+        while broadcasts.recv().await.is_some() {}
+    });
+    tokio::spawn(async move { while sync.recv().await.is_some() {} });
+    (handle, node)
+}
@@ -0,0 +1,546 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::crypto::{
+    contracts::UserId,
+    hashing::{Hash, Hashable},
+};
+use crate::transactions::{state::State, Transaction};
+
+/// Limits on the transactions held by a `Mempool`.
+#[derive(Clone, Copy, Debug)]
+pub struct MempoolConfig {
+    /// The most transactions held at once.
+    pub max_transactions: usize,
+    /// The most encoded bytes held at once.
+    pub max_bytes: usize,
+    /// The largest encoded transaction accepted.
+    pub max_transaction_bytes: usize,
+}
+
+impl Default for MempoolConfig {
+    fn default() -> Self {
+        MempoolConfig {
+            max_transactions: 10_000,
+            max_bytes: 16 * 1024 * 1024,
+            max_transaction_bytes: 64 * 1024,
+        }
+    }
+}
+
+/// The reason a transaction was refused by the mempool.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Rejection {
+    /// The signature does not match the signee and content, or is for another chain.
+    InvalidSignature,
+    /// The transaction is already in the pool.
+    Duplicate,
+    /// The sender has already committed a transaction with the same nonce.
+    Stale,
+    /// The transaction's `valid_until` height has passed.
+    Expired,
+    /// The transaction can't be applied to the state, after the transactions ahead of it.
+    Invalid,
+    /// The transaction is larger than `max_transaction_bytes`.
+    TooLarge,
+}
+
+/// Counts of what has happened to transactions submitted to a mempool.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MempoolMetrics {
+    /// The number of transactions in the pool.
+    pub size: usize,
+    /// The total encoded size of the transactions in the pool.
+    pub bytes: usize,
+    pub accepted: u64,
+    pub rejected: HashMap<Rejection, u64>,
+    /// Transactions removed to make room for newer ones.
+    pub evicted: u64,
+    /// Transactions removed because they no longer applied, after a commit or after
+    /// evicting a transaction they depended on.
+    pub invalidated: u64,
+}
+
+struct Entry {
+    hash: Hash<Transaction>,
+    transaction: Transaction,
+    bytes: usize,
+}
+
+/// The entries to remove to make room for a new transaction.
+struct Room {
+    /// The number of entries to evict from the front of the pool.
+    evicted: usize,
+    /// The indices of later entries that depend on the evicted ones, in order.
+    dependents: Vec<usize>,
+    /// The state after applying the remaining entries.
+    pending: State,
+}
+
+/// Holds transactions between submission and block creation, in the order they arrived.
+/// Every transaction in the pool applies to the latest committed state after the ones
+/// ahead of it, so any prefix of the pool can be proposed as a block.
+pub struct Mempool {
+    config: MempoolConfig,
+    /// Transactions must be signed for this chain.
+    chain_id: String,
+    /// The latest committed state.
+    state: State,
+    /// The height of the next block, which transactions must still be valid at.
+    height: u64,
+    /// The state after applying every transaction in the pool.
+    pending: State,
+    entries: VecDeque<Entry>,
+    hashes: HashSet<Hash<Transaction>>,
+    bytes: usize,
+    metrics: MempoolMetrics,
+}
+
+impl Mempool {
+    /// Creates an empty pool for the state before the first block.
+    pub fn new(config: MempoolConfig, chain_id: String, state: State) -> Mempool {
+        Mempool {
+            config,
+            chain_id,
+            pending: state.clone(),
+            state,
+            height: 0,
+            entries: VecDeque::new(),
+            hashes: HashSet::new(),
+            bytes: 0,
+            metrics: MempoolMetrics::default(),
+        }
+    }
+
+    /// Adds a transaction to the back of the pool. If the pool is full, the oldest
+    /// transactions are evicted to make room, along with any that depended on them.
+    pub fn add(&mut self, transaction: Transaction) -> Result<(), Rejection> {
+        let result = self.check(transaction);
+        match result {
+            Ok(()) => self.metrics.accepted += 1,
+            Err(reason) => *self.metrics.rejected.entry(reason).or_insert(0) += 1,
+        }
+        result
+    }
+
+    fn check(&mut self, transaction: Transaction) -> Result<(), Rejection> {
+        let hash = transaction.hash();
+        if self.hashes.contains(&hash) {
+            return Err(Rejection::Duplicate);
+        }
+        if transaction.nonce() < self.state.nonce(transaction.signee()) {
+            return Err(Rejection::Stale);
+        }
+        if transaction.valid_until() < self.height {
+            return Err(Rejection::Expired);
+        }
+        let bytes = bincode::serialized_size(&transaction).map_or(usize::MAX, |b| b as usize);
+        if bytes > self.config.max_transaction_bytes {
+            return Err(Rejection::TooLarge);
+        }
+        if !transaction.verify(&self.chain_id) {
+            return Err(Rejection::InvalidSignature);
+        }
+        // checked before evicting, so that transactions that can't be added don't
+        // cost the pool any entries
+        let room = self.make_room(bytes);
+        let pending = room
+            .pending
+            .apply_verified(self.height, &transaction)
+            .ok_or(Rejection::Invalid)?;
+
+        self.evict(room.evicted, &room.dependents);
+        self.pending = pending;
+        self.hashes.insert(hash);
+        self.bytes += bytes;
+        self.entries.push_back(Entry {
+            hash,
+            transaction,
+            bytes,
+        });
+        Ok(())
+    }
+
+    /// Replaces the state after a block is committed, with the height of the next
+    /// block. Removes the transactions whose nonces the block used up (whether they
+    /// were included or not) and any others that no longer apply, such as expired ones.
+    pub fn update(&mut self, state: State, height: u64) {
+        self.state = state;
+        self.height = height;
+        let committed = &self.state;
+        let (included, remaining): (Vec<_>, Vec<_>) = std::mem::take(&mut self.entries)
+            .into_iter()
+            .partition(|entry| {
+                let transaction = &entry.transaction;
+                transaction.nonce() < committed.nonce(transaction.signee())
+            });
+        for entry in included.iter() {
+            self.forget(entry);
+        }
+        self.entries = remaining.into();
+        self.recheck();
+    }
+
+    /// Returns transactions from the front of the pool for a block, in order,
+    /// stopping before the given number of transactions or bytes is exceeded.
+    pub fn batch(&self, max_transactions: usize, max_bytes: usize) -> Vec<Transaction> {
+        let mut bytes = 0;
+        self.entries
+            .iter()
+            .take(max_transactions)
+            .take_while(|entry| {
+                bytes += entry.bytes;
+                bytes <= max_bytes
+            })
+            .map(|entry| entry.transaction.clone())
+            .collect()
+    }
+
+    /// Returns the latest committed state.
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn metrics(&self) -> MempoolMetrics {
+        MempoolMetrics {
+            size: self.entries.len(),
+            bytes: self.bytes,
+            ..self.metrics.clone()
+        }
+    }
+
+    /// Works out which entries must go to make room for a transaction of the given size:
+    /// the oldest ones, and any later ones that depend on them.
+    fn make_room(&self, bytes: usize) -> Room {
+        let mut evicted = 0;
+        let mut freed = 0;
+        while evicted < self.entries.len()
+            && (self.entries.len() - evicted >= self.config.max_transactions
+                || self.bytes - freed + bytes > self.config.max_bytes)
+        {
+            freed += self.entries[evicted].bytes;
+            evicted += 1;
+        }
+
+        // the users whose state the removed entries touched, which no remaining entry
+        // touches. A dependent can bring in a user that an earlier entry touched, so the
+        // remaining entries are scanned until no more users are added.
+        let mut users: HashSet<UserId> = self
+            .entries
+            .iter()
+            .take(evicted)
+            .flat_map(|entry| entry.transaction.users())
+            .collect();
+        let mut kept: Vec<usize> = (evicted..self.entries.len()).collect();
+        let mut dependents = Vec::new();
+        loop {
+            let found = dependents.len();
+            kept.retain(|&index| {
+                let touched = self.entries[index].transaction.users();
+                if touched.iter().any(|user| users.contains(user)) {
+                    users.extend(touched);
+                    dependents.push(index);
+                    false
+                } else {
+                    true
+                }
+            });
+            if dependents.len() == found {
+                break;
+            }
+        }
+        dependents.sort_unstable();
+
+        // so only those users differ from the committed state
+        let mut pending = self.pending.clone();
+        for user in users {
+            match self.state.users.get(&user) {
+                Some(committed) => pending.users.insert(user, committed.clone()),
+                None => pending.users.remove(&user),
+            };
+        }
+        Room {
+            evicted,
+            dependents,
+            pending,
+        }
+    }
+
+    /// Removes the given number of entries from the front of the pool, and the
+    /// dependents at the given indices.
+    fn evict(&mut self, evicted: usize, dependents: &[usize]) {
+        let mut dependents = dependents.iter().peekable();
+        for (index, entry) in std::mem::take(&mut self.entries).into_iter().enumerate() {
+            if index < evicted {
+                self.forget(&entry);
+                self.metrics.evicted += 1;
+            } else if dependents.next_if_eq(&&index).is_some() {
+                self.forget(&entry);
+                self.metrics.invalidated += 1;
+            } else {
+                self.entries.push_back(entry);
+            }
+        }
+    }
+
+    /// Applies the pool to the committed state again, dropping transactions that fail.
+    fn recheck(&mut self) {
+        let mut pending = self.state.clone();
+        let mut invalid = Vec::new();
+        // signatures were checked when the transactions were added
+        for entry in std::mem::take(&mut self.entries) {
+            match pending.apply_verified(self.height, &entry.transaction) {
+                Some(next) => {
+                    pending = next;
+                    self.entries.push_back(entry);
+                }
+                None => invalid.push(entry),
+            }
+        }
+        for entry in invalid.iter() {
+            self.forget(entry);
+            self.metrics.invalidated += 1;
+        }
+        self.pending = pending;
+    }
+
+    fn forget(&mut self, entry: &Entry) {
+        self.hashes.remove(&entry.hash);
+        self.bytes -= entry.bytes;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        crypto::contracts::PrivateKey,
+        testing::{funded, transfer, CHAIN},
+        transactions::UnsignedCurrencyTransfer,
+    };
+
+    #[test]
+    fn checks_transactions_in_order() {
+        let (alice, bob) = (PrivateKey::generate(), PrivateKey::generate());
+        let mut pool = Mempool::new(
+            MempoolConfig::default(),
+            CHAIN.to_string(),
+            funded(&[&alice]),
+        );
+
+        let first = transfer(&alice, &bob, 60, 0);
+        assert_eq!(pool.add(first.clone()), Ok(()));
+        assert_eq!(pool.add(first), Err(Rejection::Duplicate));
+        // only 40 is left after the first transfer
+        assert_eq!(
+            pool.add(transfer(&alice, &bob, 50, 1)),
+            Err(Rejection::Invalid)
+        );
+        // nonces can't be skipped
+        assert_eq!(
+            pool.add(transfer(&alice, &bob, 10, 2)),
+            Err(Rejection::Invalid)
+        );
+        // bob can spend the incoming transfer
+        assert_eq!(pool.add(transfer(&bob, &alice, 50, 0)), Ok(()));
+
+        let mut forged = match transfer(&alice, &bob, 10, 1) {
+            Transaction::CurrencyTransfer(c) => c,
+            _ => unreachable!(),
+        };
+        forged.content.amount = 20;
+        let forged = Transaction::CurrencyTransfer(forged);
+        assert_eq!(pool.add(forged), Err(Rejection::InvalidSignature));
+
+        let other_chain = Transaction::CurrencyTransfer(alice.sign(
+            "other",
+            UnsignedCurrencyTransfer {
+                amount: 10,
+                recipient: bob.get_public().hash(),
+                nonce: 1,
+                valid_until: u64::MAX,
+            },
+        ));
+        assert_eq!(pool.add(other_chain), Err(Rejection::InvalidSignature));
+
+        let metrics = pool.metrics();
+        assert_eq!(metrics.size, 2);
+        assert_eq!(metrics.accepted, 2);
+        assert_eq!(metrics.rejected[&Rejection::Invalid], 2);
+        assert_eq!(metrics.rejected[&Rejection::InvalidSignature], 2);
+        assert_eq!(metrics.rejected.values().sum::<u64>(), 5);
+    }
+
+    #[test]
+    fn serves_ordered_batches() {
+        let (alice, bob) = (PrivateKey::generate(), PrivateKey::generate());
+        let mut pool = Mempool::new(
+            MempoolConfig::default(),
+            CHAIN.to_string(),
+            funded(&[&alice]),
+        );
+        let transactions: Vec<Transaction> =
+            (0..5).map(|i| transfer(&alice, &bob, 10, i)).collect();
+        for transaction in transactions.iter() {
+            pool.add(transaction.clone()).unwrap();
+        }
+
+        assert_eq!(pool.batch(3, usize::MAX), transactions[..3]);
+        let bytes = pool.metrics().bytes / 5;
+        assert_eq!(pool.batch(10, 2 * bytes), transactions[..2]);
+        assert!(pool.batch(10, bytes - 1).is_empty());
+    }
+
+    #[test]
+    fn evicts_oldest_when_full() {
+        let (alice, bob, carol) = (
+            PrivateKey::generate(),
+            PrivateKey::generate(),
+            PrivateKey::generate(),
+        );
+        let config = MempoolConfig {
+            max_transactions: 2,
+            ..MempoolConfig::default()
+        };
+        let mut pool = Mempool::new(config, CHAIN.to_string(), funded(&[&alice, &carol]));
+
+        // bob's transfer depends on the first one, so is evicted along with it
+        pool.add(transfer(&alice, &bob, 10, 0)).unwrap();
+        pool.add(transfer(&bob, &alice, 10, 0)).unwrap();
+        let last = transfer(&carol, &bob, 20, 0);
+        pool.add(last.clone()).unwrap();
+
+        assert_eq!(pool.batch(10, usize::MAX), vec![last]);
+        let metrics = pool.metrics();
+        assert_eq!((metrics.evicted, metrics.invalidated), (1, 1));
+    }
+
+    #[test]
+    fn evicts_dependents_of_dependents() {
+        let (alice, bob, carol, dave, erin) = (
+            PrivateKey::generate(),
+            PrivateKey::generate(),
+            PrivateKey::generate(),
+            PrivateKey::generate(),
+            PrivateKey::generate(),
+        );
+        let config = MempoolConfig {
+            max_transactions: 3,
+            ..MempoolConfig::default()
+        };
+        let state = funded(&[&alice, &carol, &erin]);
+        let mut pool = Mempool::new(config, CHAIN.to_string(), state.clone());
+
+        // carol's transfer comes before bob's, which only depends on it through carol
+        pool.add(transfer(&alice, &bob, 60, 0)).unwrap();
+        pool.add(transfer(&carol, &dave, 10, 0)).unwrap();
+        pool.add(transfer(&bob, &carol, 50, 0)).unwrap();
+        let last = transfer(&erin, &bob, 5, 0);
+        pool.add(last.clone()).unwrap();
+
+        assert_eq!(pool.batch(10, usize::MAX), vec![last]);
+        let metrics = pool.metrics();
+        assert_eq!((metrics.evicted, metrics.invalidated), (1, 2));
+
+        // carol's nonce is free again, and the pool still applies as a whole
+        pool.add(transfer(&carol, &dave, 20, 0)).unwrap();
+        assert!(state
+            .apply_all(CHAIN, 0, &pool.batch(10, usize::MAX))
+            .is_some());
+    }
+
+    #[test]
+    fn evicts_only_for_transactions_that_fit() {
+        let (alice, bob, carol, dave, erin) = (
+            PrivateKey::generate(),
+            PrivateKey::generate(),
+            PrivateKey::generate(),
+            PrivateKey::generate(),
+            PrivateKey::generate(),
+        );
+        let config = MempoolConfig {
+            max_transactions: 2,
+            ..MempoolConfig::default()
+        };
+        let mut pool = Mempool::new(config, CHAIN.to_string(), funded(&[&alice, &carol, &erin]));
+        let unrelated = transfer(&carol, &dave, 10, 0);
+        pool.add(transfer(&alice, &bob, 60, 0)).unwrap();
+        pool.add(unrelated.clone()).unwrap();
+
+        // bob can only pay with alice's transfer, which would be evicted
+        assert_eq!(
+            pool.add(transfer(&bob, &alice, 50, 0)),
+            Err(Rejection::Invalid)
+        );
+        assert_eq!(pool.len(), 2);
+        assert_eq!(pool.metrics().evicted, 0);
+
+        // evicting alice's transfer leaves carol's, which doesn't depend on it
+        let last = transfer(&erin, &bob, 5, 0);
+        pool.add(last.clone()).unwrap();
+        assert_eq!(pool.batch(10, usize::MAX), vec![unrelated, last]);
+        let metrics = pool.metrics();
+        assert_eq!((metrics.evicted, metrics.invalidated), (1, 0));
+    }
+
+    #[test]
+    fn rechecks_after_commit() {
+        let (alice, bob) = (PrivateKey::generate(), PrivateKey::generate());
+        let state = funded(&[&alice]);
+        let mut pool = Mempool::new(MempoolConfig::default(), CHAIN.to_string(), state.clone());
+
+        let included = transfer(&alice, &bob, 30, 0);
+        let replaced = transfer(&alice, &bob, 30, 1);
+        let unaffordable = transfer(&alice, &bob, 20, 2);
+        pool.add(included.clone()).unwrap();
+        pool.add(replaced.clone()).unwrap();
+        pool.add(unaffordable).unwrap();
+
+        // a block with the first transfer, and another with the same nonce as the second
+        let replacement = transfer(&alice, &bob, 60, 1);
+        let state = state
+            .apply_all(CHAIN, 0, &[included.clone(), replacement])
+            .unwrap();
+        pool.update(state, 1);
+
+        assert!(pool.is_empty());
+        assert_eq!(pool.metrics().invalidated, 1);
+        assert_eq!(pool.add(included), Err(Rejection::Stale));
+        assert_eq!(pool.add(replaced), Err(Rejection::Stale));
+        assert_eq!(pool.add(transfer(&alice, &bob, 10, 2)), Ok(()));
+    }
+
+    #[test]
+    fn drops_expired_transactions() {
+        let (alice, bob) = (PrivateKey::generate(), PrivateKey::generate());
+        let state = funded(&[&alice]);
+        let mut pool = Mempool::new(MempoolConfig::default(), CHAIN.to_string(), state.clone());
+        let expiring = |nonce| {
+            Transaction::CurrencyTransfer(alice.sign(
+                CHAIN,
+                UnsignedCurrencyTransfer {
+                    amount: 10,
+                    recipient: bob.get_public().hash(),
+                    nonce,
+                    valid_until: 1,
+                },
+            ))
+        };
+        pool.add(expiring(0)).unwrap();
+
+        // after an empty block, the transfer can still be in the next one, but no later
+        pool.update(state.clone(), 1);
+        assert_eq!(pool.len(), 1);
+        pool.update(state, 2);
+        assert!(pool.is_empty());
+        assert_eq!(pool.metrics().invalidated, 1);
+        assert_eq!(pool.add(expiring(0)), Err(Rejection::Expired));
+    }
+}
@@ -1,10 +1,11 @@
-use crate::crypto::contracts::{Contract, UserId};
+use crate::crypto::contracts::{Contract, Signable, UserId};
 use crate::crypto::hashing::*;
 use serde::{Deserialize, Serialize};
 
+pub mod mempool;
 pub mod state;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum Transaction {
     CurrencyTransfer(CurrencyTransfer),
     SelfListing(SelfListing),
@@ -14,40 +15,64 @@ pub enum Transaction {
     LicenseTransfer(LicenseTransfer),
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub struct UnsignedCurrencyTransfer {
     pub amount: u64,
     pub recipient: UserId,
+    /// The signee's next nonce (see `UserState::nonce`).
+    pub nonce: u64,
+    /// The last height of a block that can include the transaction.
+    pub valid_until: u64,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub struct UnsignedSelfListing {
     pub price: u64,
+    /// The signee's next nonce (see `UserState::nonce`).
+    pub nonce: u64,
+    /// The last height of a block that can include the transaction.
+    pub valid_until: u64,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub struct UnsignedLicenseOrder {
     pub seller: UserId,
     pub price: u64,
+    /// The signee's next nonce (see `UserState::nonce`).
+    pub nonce: u64,
+    /// The last height of a block that can include the transaction.
+    pub valid_until: u64,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub struct UnsignedLicenseListing {
     pub license: LicenseId,
     pub price: u64,
+    /// The signee's next nonce (see `UserState::nonce`).
+    pub nonce: u64,
+    /// The last height of a block that can include the transaction.
+    pub valid_until: u64,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub struct UnsignedLicensePurchase {
     pub seller: UserId,
     pub license: LicenseId,
     pub price: u64,
+    /// The signee's next nonce (see `UserState::nonce`).
+    pub nonce: u64,
+    /// The last height of a block that can include the transaction.
+    pub valid_until: u64,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub struct UnsignedLicenseTransfer {
     pub license: LicenseId,
     pub recipient: UserId,
+    /// The signee's next nonce (see `UserState::nonce`).
+    pub nonce: u64,
+    /// The last height of a block that can include the transaction.
+    pub valid_until: u64,
 }
 
 pub type CurrencyTransfer = Contract<UnsignedCurrencyTransfer>;
@@ -59,38 +84,144 @@ pub type LicenseTransfer = Contract<UnsignedLicenseTransfer>;
 
 pub type LicenseId = Hash<LicenseOrder>;
 
+impl Hashable for Transaction {
+    /// The hash of the signed contract.
+    fn hash(&self) -> Hash<Self> {
+        match self {
+            Transaction::CurrencyTransfer(c) => c.hash().cast(),
+            Transaction::SelfListing(c) => c.hash().cast(),
+            Transaction::LicenseOrder(c) => c.hash().cast(),
+            Transaction::LicenseListing(c) => c.hash().cast(),
+            Transaction::LicensePurchase(c) => c.hash().cast(),
+            Transaction::LicenseTransfer(c) => c.hash().cast(),
+        }
+    }
+}
+
+impl Transaction {
+    /// Returns the user that signed the transaction.
+    pub fn signee(&self) -> UserId {
+        match self {
+            Transaction::CurrencyTransfer(c) => c.signee.hash(),
+            Transaction::SelfListing(c) => c.signee.hash(),
+            Transaction::LicenseOrder(c) => c.signee.hash(),
+            Transaction::LicenseListing(c) => c.signee.hash(),
+            Transaction::LicensePurchase(c) => c.signee.hash(),
+            Transaction::LicenseTransfer(c) => c.signee.hash(),
+        }
+    }
+
+    pub fn nonce(&self) -> u64 {
+        match self {
+            Transaction::CurrencyTransfer(c) => c.content.nonce,
+            Transaction::SelfListing(c) => c.content.nonce,
+            Transaction::LicenseOrder(c) => c.content.nonce,
+            Transaction::LicenseListing(c) => c.content.nonce,
+            Transaction::LicensePurchase(c) => c.content.nonce,
+            Transaction::LicenseTransfer(c) => c.content.nonce,
+        }
+    }
+
+    /// Returns the last height of a block that can include the transaction.
+    pub fn valid_until(&self) -> u64 {
+        match self {
+            Transaction::CurrencyTransfer(c) => c.content.valid_until,
+            Transaction::SelfListing(c) => c.content.valid_until,
+            Transaction::LicenseOrder(c) => c.content.valid_until,
+            Transaction::LicenseListing(c) => c.content.valid_until,
+            Transaction::LicensePurchase(c) => c.content.valid_until,
+            Transaction::LicenseTransfer(c) => c.content.valid_until,
+        }
+    }
+
+    /// Returns the users whose state the transaction depends on or changes.
+    pub fn users(&self) -> Vec<UserId> {
+        let signee = self.signee();
+        match self {
+            Transaction::CurrencyTransfer(c) => vec![signee, c.content.recipient],
+            Transaction::SelfListing(_) => vec![signee],
+            Transaction::LicenseOrder(c) => vec![signee, c.content.seller],
+            Transaction::LicenseListing(_) => vec![signee],
+            Transaction::LicensePurchase(c) => vec![signee, c.content.seller],
+            Transaction::LicenseTransfer(c) => vec![signee, c.content.recipient],
+        }
+    }
+
+    /// Checks the signature on the transaction, which must be signed for the chain.
+    pub fn verify(&self, chain_id: &str) -> bool {
+        match self {
+            Transaction::CurrencyTransfer(c) => c.verify(chain_id),
+            Transaction::SelfListing(c) => c.verify(chain_id),
+            Transaction::LicenseOrder(c) => c.verify(chain_id),
+            Transaction::LicenseListing(c) => c.verify(chain_id),
+            Transaction::LicensePurchase(c) => c.verify(chain_id),
+            Transaction::LicenseTransfer(c) => c.verify(chain_id),
+        }
+    }
+}
+
 impl Hashable for UnsignedCurrencyTransfer {
     fn hash(&self) -> Hash<Self> {
-        hash![self.amount, self.recipient]
+        hash![self.amount, self.recipient, self.nonce, self.valid_until]
     }
 }
 
 impl Hashable for UnsignedSelfListing {
     fn hash(&self) -> Hash<Self> {
-        hash![self.price]
+        hash![self.price, self.nonce, self.valid_until]
     }
 }
 
 impl Hashable for UnsignedLicenseOrder {
     fn hash(&self) -> Hash<Self> {
-        hash![self.seller, self.price]
+        hash![self.seller, self.price, self.nonce, self.valid_until]
     }
 }
 
 impl Hashable for UnsignedLicenseListing {
     fn hash(&self) -> Hash<Self> {
-        hash![self.license, self.price]
+        hash![self.license, self.price, self.nonce, self.valid_until]
     }
 }
 
 impl Hashable for UnsignedLicensePurchase {
     fn hash(&self) -> Hash<Self> {
-        hash![self.seller, self.license, self.price]
+        hash![
+            self.seller,
+            self.license,
+            self.price,
+            self.nonce,
+            self.valid_until
+        ]
     }
 }
 
 impl Hashable for UnsignedLicenseTransfer {
     fn hash(&self) -> Hash<Self> {
-        hash![self.license, self.recipient]
+        hash![self.license, self.recipient, self.nonce, self.valid_until]
     }
 }
+
+impl Signable for UnsignedCurrencyTransfer {
+    const DOMAIN: &'static str = "blockkey/tx/currency-transfer";
+}
+
+impl Signable for UnsignedSelfListing {
+    const DOMAIN: &'static str = "blockkey/tx/self-listing";
+}
+
+impl Signable for UnsignedLicenseOrder {
+    const DOMAIN: &'static str = "blockkey/tx/license-order";
+}
+
+impl Signable for UnsignedLicenseListing {
+    const DOMAIN: &'static str = "blockkey/tx/license-listing";
+}
+
+impl Signable for UnsignedLicensePurchase {
+    const DOMAIN: &'static str = "blockkey/tx/license-purchase";
+}
+
+impl Signable for UnsignedLicenseTransfer {
+    const DOMAIN: &'static str = "blockkey/tx/license-transfer";
+}
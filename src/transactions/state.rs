@@ -5,10 +5,16 @@ use crate::transactions::{
     CurrencyTransfer, LicenseId, LicenseListing, LicenseOrder, LicensePurchase, LicenseTransfer,
     SelfListing,
 };
-use im_rc::HashMap;
-use im_rc::HashSet;
+use im::HashMap;
+use im::HashSet;
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroUsize;
+use std::thread;
 
-#[derive(Clone)]
+/// The fewest transactions that are worth checking on a thread of their own.
+const MIN_CHUNK: usize = 64;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct UserState {
     /// The current balance of the user (default=0)
     pub balance: u64,
@@ -19,6 +25,9 @@ pub struct UserState {
     pub licenses: HashSet<LicenseId>,
     /// Licenses up for resale.
     pub listings: HashMap<LicenseId, u64>,
+    /// The number of transactions the user has sent, which is the nonce their
+    /// next transaction must have (default=0).
+    pub nonce: u64,
 }
 
 impl Default for UserState {
@@ -28,11 +37,24 @@ impl Default for UserState {
             price: 0,
             licenses: HashSet::<LicenseId>::new(),
             listings: HashMap::<LicenseId, u64>::new(),
+            nonce: 0,
         }
     }
 }
 
 impl UserState {
+    /// Uses up the given nonce, if it is the user's next one.
+    fn use_nonce(&self, nonce: u64) -> Option<UserState> {
+        if nonce != self.nonce {
+            None
+        } else {
+            Some(UserState {
+                nonce: self.nonce.checked_add(1)?,
+                ..self.clone()
+            })
+        }
+    }
+
     /// Adds the given amount to the user's balance, checking for overflow.
     fn deposit(&self, amount: u64) -> Option<UserState> {
         Some(UserState {
@@ -105,12 +127,46 @@ impl UserState {
     }
 }
 
-#[derive(Clone, Default)]
+/// Hashes a set in an order that doesn't depend on how it was built, as the
+/// iteration order of `im` collections varies between processes.
+fn hash_set(hashes: impl Iterator<Item = Hash>) -> Hash {
+    let mut hashes: Vec<Hash> = hashes.collect();
+    hashes.sort_unstable_by(|a, b| a.get_bytes().cmp(b.get_bytes()));
+    let mut bytes = Vec::new();
+    for hash in hashes {
+        bytes.extend_from_slice(hash.get_bytes());
+    }
+    bytes.hash().cast()
+}
+
+impl Hashable for UserState {
+    fn hash(&self) -> Hash<Self> {
+        let licenses = hash_set(self.licenses.iter().map(Hash::cast));
+        let listings = hash_set(
+            self.listings
+                .iter()
+                .map(|(license, price)| hash![license, price]),
+        );
+        hash![self.balance, self.price, licenses, listings, self.nonce]
+    }
+}
+
+/// The state of every account. Transactions can't be replayed, as each one uses up
+/// its sender's next nonce, and one that is never included can't be applied long
+/// after it was signed, as it expires. So there is no need to remember past
+/// transactions.
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
 pub struct State {
-    pub transactions: HashSet<Hash<Transaction>>,
     pub users: HashMap<UserId, UserState>,
 }
 
+impl Hashable for State {
+    /// The state root, which commits to every user.
+    fn hash(&self) -> Hash<Self> {
+        hash_set(self.users.iter().map(|(id, user)| hash![id, user])).cast()
+    }
+}
+
 impl State {
     /// Gets the state of a user by ID, or creates a default user if one doesn't exist.
     fn get_user(&self, user_id: UserId) -> UserState {
@@ -125,20 +181,17 @@ impl State {
         let user = self.get_user(user_id);
         Some(State {
             users: self.users.update(user_id, transform(user)?),
-            ..self.clone()
         })
     }
 
-    /// Records a transaction, asserting that it hasn't already been processed.
-    fn record_transaction(&self, transaction: Hash<Transaction>) -> Option<State> {
-        if self.transactions.contains(&transaction) {
-            None
-        } else {
-            Some(State {
-                transactions: self.transactions.update(transaction),
-                ..self.clone()
-            })
-        }
+    /// Returns the nonce that the user's next transaction must have.
+    pub fn nonce(&self, user_id: UserId) -> u64 {
+        self.users.get(&user_id).map_or(0, |user| user.nonce)
+    }
+
+    /// Uses up the sender's nonce, asserting that the transaction is their next one.
+    fn use_nonce(&self, sender: UserId, nonce: u64) -> Option<State> {
+        self.update_user(sender, |user| user.use_nonce(nonce))
     }
 
     /// Transfers an amount from one account to another (if funds are available).
@@ -153,7 +206,7 @@ impl State {
 
     /// Applies a CurrencyTransfer transaction.
     fn transfer_currency(&self, transfer: &CurrencyTransfer) -> Option<State> {
-        self.record_transaction(transfer.hash().cast())?
+        self.use_nonce(transfer.signee.hash(), transfer.content.nonce)?
             ._transfer_currency(
                 transfer.signee.hash(),
                 transfer.content.recipient,
@@ -165,7 +218,7 @@ impl State {
     fn list_self(&self, valuation: &SelfListing) -> Option<State> {
         let self_id = valuation.signee.hash();
         let price = valuation.content.price;
-        self.record_transaction(valuation.hash().cast())?
+        self.use_nonce(self_id, valuation.content.nonce)?
             .update_user(self_id, |user| user.set_price(price))
     }
 
@@ -177,7 +230,7 @@ impl State {
         let license = valuation.hash();
 
         if seller_id == buyer_id || (price != 0 && price == self.get_user(seller_id).price) {
-            self.record_transaction(license.cast())?
+            self.use_nonce(buyer_id, valuation.content.nonce)?
                 ._transfer_currency(buyer_id, seller_id, price)?
                 .update_user(buyer_id, |buyer| buyer.add_license(license))
         } else {
@@ -193,7 +246,7 @@ impl State {
         if price == 0 {
             None
         } else {
-            self.record_transaction(listing.hash().cast())?
+            self.use_nonce(seller_id, listing.content.nonce)?
                 .update_user(seller_id, |user| {
                     user.remove_license(license)?.add_listing(license, price)
                 })
@@ -209,8 +262,8 @@ impl State {
 
         if seller_id == buyer_id || self.get_user(seller_id).listings.get(&license) == Some(&price)
         {
-            self.record_transaction(purchase.hash().cast())?
-                ._transfer_currency(seller_id, buyer_id, price)?
+            self.use_nonce(buyer_id, purchase.content.nonce)?
+                ._transfer_currency(buyer_id, seller_id, price)?
                 .update_user(seller_id, |seller| seller.remove_listing(license))?
                 .update_user(buyer_id, |buyer| buyer.add_license(license))
         } else {
@@ -223,13 +276,51 @@ impl State {
         let license = transfer.content.license;
         let sender_id = transfer.signee.hash();
         let recipient_id = transfer.content.recipient;
-        self.record_transaction(transfer.hash().cast())?
+        self.use_nonce(sender_id, transfer.content.nonce)?
             .update_user(sender_id, |sender| sender.remove_license(license))?
             .update_user(recipient_id, |recipient| recipient.add_license(license))
     }
 
-    /// Applies a transaction.
-    pub fn apply(&self, transaction: &Transaction) -> Option<State> {
+    /// Applies a transaction that was signed for the given chain, in a block at the
+    /// given height. Returns None if the signature is invalid, or the transaction
+    /// can't be applied.
+    pub fn apply(&self, chain_id: &str, height: u64, transaction: &Transaction) -> Option<State> {
+        if !transaction.verify(chain_id) {
+            return None;
+        }
+        self.apply_verified(height, transaction)
+    }
+
+    /// Applies the transactions in order, checking every signature before applying
+    /// any. Returns None if any signature is invalid, or any transaction can't be applied.
+    ///
+    /// Large blocks have their signatures checked on several threads. Each signature is
+    /// still checked on its own rather than in a batch, as batch verification is
+    /// randomised and can disagree with `Contract::verify` on some signatures, which
+    /// would let nodes disagree on whether a block is valid.
+    pub fn apply_all(
+        &self,
+        chain_id: &str,
+        height: u64,
+        transactions: &[Transaction],
+    ) -> Option<State> {
+        let threads = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        if !verify_all(chain_id, transactions, threads) {
+            return None;
+        }
+        transactions
+            .iter()
+            .try_fold(self.clone(), |state, transaction| {
+                state.apply_verified(height, transaction)
+            })
+    }
+
+    /// Applies a transaction whose signature has already been checked, such as one
+    /// that was accepted into the mempool, in a block at the given height.
+    pub(crate) fn apply_verified(&self, height: u64, transaction: &Transaction) -> Option<State> {
+        if height > transaction.valid_until() {
+            return None;
+        }
         match transaction {
             CurrencyTransfer(transfer) => self.transfer_currency(transfer),
             SelfListing(listing) => self.list_self(listing),
@@ -241,5 +332,236 @@ impl State {
     }
 }
 
+/// Checks the signature on every transaction, splitting the work between up to the
+/// given number of threads. Every signature is checked the same way wherever it is
+/// checked, so the result doesn't depend on the number of threads.
+fn verify_all(chain_id: &str, transactions: &[Transaction], threads: usize) -> bool {
+    let chunk = transactions.len().div_ceil(threads).max(MIN_CHUNK);
+    if transactions.len() <= chunk {
+        return transactions.iter().all(|t| t.verify(chain_id));
+    }
+    thread::scope(|scope| {
+        let checks: Vec<_> = transactions
+            .chunks(chunk)
+            .map(|chunk| scope.spawn(move || chunk.iter().all(|t| t.verify(chain_id))))
+            .collect();
+        checks
+            .into_iter()
+            .all(|check| check.join().unwrap_or(false))
+    })
+}
+
 #[cfg(test)]
-mod test {}
+mod test {
+    use super::*;
+    use crate::{
+        crypto::contracts::PrivateKey,
+        testing::{funded, transfer, CHAIN},
+        transactions::{
+            UnsignedCurrencyTransfer, UnsignedLicenseListing, UnsignedLicenseOrder,
+            UnsignedLicensePurchase, UnsignedSelfListing,
+        },
+    };
+
+    #[test]
+    fn hash_ignores_insertion_order() {
+        let ids: Vec<UserId> = (0..32u64).map(|i| i.hash().cast()).collect();
+        let user = |i: usize| UserState {
+            balance: i as u64,
+            ..UserState::default()
+        };
+        let forwards = ids
+            .iter()
+            .enumerate()
+            .fold(State::default(), |state, (i, id)| State {
+                users: state.users.update(*id, user(i)),
+            });
+        let backwards = ids
+            .iter()
+            .enumerate()
+            .rev()
+            .fold(State::default(), |state, (i, id)| State {
+                users: state.users.update(*id, user(i)),
+            });
+        assert_eq!(forwards.hash(), backwards.hash());
+
+        let changed = State {
+            users: forwards.users.update(ids[0], user(1)),
+        };
+        assert_ne!(forwards.hash(), changed.hash());
+    }
+
+    #[test]
+    fn nonces_prevent_replays() {
+        let (alice, bob) = (PrivateKey::generate(), PrivateKey::generate());
+        let alice_id = alice.get_public().hash();
+        let state = funded(&[&alice]);
+
+        assert!(state
+            .apply(CHAIN, 0, &transfer(&alice, &bob, 10, 1))
+            .is_none());
+        let first = transfer(&alice, &bob, 10, 0);
+        let state = state.apply(CHAIN, 0, &first).unwrap();
+        assert_eq!(state.nonce(alice_id), 1);
+        assert!(state.apply(CHAIN, 0, &first).is_none());
+
+        // every kind of transaction uses up a nonce
+        let listing = SelfListing(alice.sign(
+            CHAIN,
+            UnsignedSelfListing {
+                price: 5,
+                nonce: 1,
+                valid_until: u64::MAX,
+            },
+        ));
+        let state = state.apply(CHAIN, 0, &listing).unwrap();
+        assert!(state.apply(CHAIN, 0, &listing).is_none());
+        assert!(state
+            .apply(CHAIN, 0, &transfer(&alice, &bob, 10, 2))
+            .is_some());
+
+        // the recipient's nonce is unaffected
+        assert_eq!(state.nonce(bob.get_public().hash()), 0);
+    }
+
+    #[test]
+    fn rejects_expired_transactions() {
+        let (alice, bob) = (PrivateKey::generate(), PrivateKey::generate());
+        let state = funded(&[&alice]);
+        let expiring = |nonce| {
+            CurrencyTransfer(alice.sign(
+                CHAIN,
+                UnsignedCurrencyTransfer {
+                    amount: 10,
+                    recipient: bob.get_public().hash(),
+                    nonce,
+                    valid_until: 5,
+                },
+            ))
+        };
+
+        assert!(state.apply(CHAIN, 5, &expiring(0)).is_some());
+        assert!(state.apply(CHAIN, 6, &expiring(0)).is_none());
+
+        // signed early with a later nonce, and replayed once that nonce is reached
+        let replayed = expiring(1);
+        let state = state.apply(CHAIN, 3, &expiring(0)).unwrap();
+        assert!(state.apply(CHAIN, 4, &replayed).is_some());
+        assert!(state.apply(CHAIN, 20, &replayed).is_none());
+        assert!(state.apply_all(CHAIN, 20, &[replayed]).is_none());
+    }
+
+    #[test]
+    fn rejects_forged_transfers() {
+        let (alice, mallory) = (PrivateKey::generate(), PrivateKey::generate());
+        let state = funded(&[&alice]);
+
+        // signed by mallory, but claiming to be from alice
+        let mut forged = mallory.sign(
+            CHAIN,
+            UnsignedCurrencyTransfer {
+                amount: 100,
+                recipient: mallory.get_public().hash(),
+                nonce: 0,
+                valid_until: u64::MAX,
+            },
+        );
+        forged.signee = alice.get_public();
+        let forged = CurrencyTransfer(forged);
+        assert!(state.apply(CHAIN, 0, &forged).is_none());
+
+        // signed by alice, but for another chain
+        let other_chain = CurrencyTransfer(alice.sign(
+            "other",
+            UnsignedCurrencyTransfer {
+                amount: 100,
+                recipient: mallory.get_public().hash(),
+                nonce: 0,
+                valid_until: u64::MAX,
+            },
+        ));
+        assert!(state.apply(CHAIN, 0, &other_chain).is_none());
+
+        // one forgery spoils the whole block
+        let genuine = transfer(&alice, &mallory, 10, 0);
+        let state_after = state
+            .apply_all(CHAIN, 0, std::slice::from_ref(&genuine))
+            .unwrap();
+        assert_eq!(state_after.users[&mallory.get_public().hash()].balance, 10);
+        assert!(state.apply_all(CHAIN, 0, &[genuine, forged]).is_none());
+        assert!(state.apply_all(CHAIN, 0, &[]).is_some());
+    }
+
+    #[test]
+    fn checks_every_signature_in_large_blocks() {
+        let (alice, bob) = (PrivateKey::generate(), PrivateKey::generate());
+        let state = funded(&[&alice]);
+        let mut transactions: Vec<Transaction> = (0..4 * MIN_CHUNK as u64)
+            .map(|nonce| transfer(&alice, &bob, 0, nonce))
+            .collect();
+        assert!(state.apply_all(CHAIN, 0, &transactions).is_some());
+        assert!(verify_all(CHAIN, &transactions, 4));
+
+        // a forgery late in the block, which another thread checks
+        if let CurrencyTransfer(contract) = &mut transactions[3 * MIN_CHUNK] {
+            contract.content.amount = 10;
+        }
+        assert!(state.apply_all(CHAIN, 0, &transactions).is_none());
+        assert!(!verify_all(CHAIN, &transactions, 4));
+        assert!(!verify_all(CHAIN, &transactions, 1));
+    }
+
+    #[test]
+    fn purchase_pays_the_seller() {
+        let (alice, bob) = (PrivateKey::generate(), PrivateKey::generate());
+        let (alice_id, bob_id) = (alice.get_public().hash(), bob.get_public().hash());
+
+        // alice licenses her own work, and lists the license for 30
+        let order = alice.sign(
+            CHAIN,
+            UnsignedLicenseOrder {
+                seller: alice_id,
+                price: 0,
+                nonce: 0,
+                valid_until: u64::MAX,
+            },
+        );
+        let license = order.hash();
+        let listing = alice.sign(
+            CHAIN,
+            UnsignedLicenseListing {
+                license,
+                price: 30,
+                nonce: 1,
+                valid_until: u64::MAX,
+            },
+        );
+        let purchase = bob.sign(
+            CHAIN,
+            UnsignedLicensePurchase {
+                seller: alice_id,
+                license,
+                price: 30,
+                nonce: 0,
+                valid_until: u64::MAX,
+            },
+        );
+        let state = funded(&[&alice])
+            .apply_all(
+                CHAIN,
+                0,
+                &[
+                    LicenseOrder(order),
+                    LicenseListing(listing),
+                    transfer(&alice, &bob, 50, 2),
+                    LicensePurchase(purchase),
+                ],
+            )
+            .unwrap();
+
+        assert_eq!(state.users[&alice_id].balance, 80);
+        assert_eq!(state.users[&bob_id].balance, 20);
+        assert!(state.users[&alice_id].listings.is_empty());
+        assert!(state.users[&bob_id].licenses.contains(&license));
+    }
+}